@@ -0,0 +1,83 @@
+#![allow(non_snake_case)]
+
+// Per cycle cost of the emulator, run with cargo bench
+// Throughput is in machine cycles or evaluations, so the reports read as cycles per second
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+
+use pdp11_microcode_emulator::data_path::{evaluate_bmux, evaluate_dmux};
+use pdp11_microcode_emulator::replay::Input;
+use pdp11_microcode_emulator::{Machine, MICROROM};
+
+// Power up with SWITCH set and the single clock debounce off, so the console loop counts D up
+// until it reaches the end of the transcribed microcode
+fn console_machine(predecode: bool) -> Machine<'static> {
+    let mut machine = Machine::new(&MICROROM, 0, 0).expect("microword 000 is implemented");
+    machine.state.SINCLK = false;
+    let _ = machine.input(Input::Switch(true));
+    if predecode {
+        machine.predecode();
+    }
+    machine
+}
+
+fn console_loop(c: &mut Criterion) {
+    let mut cycles = console_machine(false);
+    while cycles.run_headless(u64::MAX).is_ok() {}
+
+    let mut group = c.benchmark_group("console_loop");
+    group.throughput(Throughput::Elements(cycles.state().CYCLE));
+    group.sample_size(20);
+    for (name, predecode) in [("direct", false), ("predecoded", true)] {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let mut machine = console_machine(predecode);
+                while machine.run_headless(u64::MAX).is_ok() {}
+                machine
+            })
+        });
+    }
+    // The debugger and stepping front ends go through the history, a pulse at a time
+    group.bench_function("pulse_stepped_with_history", |b| {
+        b.iter(|| {
+            let mut machine = console_machine(false);
+            machine.enable_history(1024);
+            while machine.step_pulse().is_ok() {}
+            machine
+        })
+    });
+    group.finish();
+}
+
+fn data_path(c: &mut Criterion) {
+    let mut group = c.benchmark_group("data_path");
+
+    group.throughput(Throughput::Elements(16));
+    group.bench_function("bmux", |b| {
+        b.iter(|| {
+            let mut result = 0;
+            for SBM in 0..16 {
+                result ^= evaluate_bmux(black_box(SBM), black_box(0o123456), black_box(0o177570));
+            }
+            result
+        })
+    });
+
+    group.throughput(Throughput::Elements(4));
+    group.bench_function("dmux", |b| {
+        b.iter(|| {
+            let mut result = 0;
+            for SDM in 0..4 {
+                result ^= evaluate_dmux(black_box(SDM), black_box(0o1), black_box(0o2), black_box(0o3), black_box(1));
+            }
+            result
+        })
+    });
+    group.finish();
+}
+
+// TODO: A tight PDP-11 instruction loop (a SOB loop in memory), once the microcode fetches
+// instructions, and a boot to the bootstrap loader with --memory and the DL11 attached
+
+criterion_group!(benches, console_loop, data_path);
+criterion_main!(benches);
@@ -0,0 +1,52 @@
+#![allow(non_snake_case)]
+
+// Times the console bounce loop with the microROM decoded every cycle and pre-decoded,
+// and checks both end in the same state
+//  cargo run --release --example predecode [runs]
+
+use std::time::{Duration, Instant};
+
+use pdp11_microcode_emulator::replay::Input;
+use pdp11_microcode_emulator::{Machine, MICROROM};
+
+// Run the console loop from power up until it reaches the end of the transcribed microcode
+fn console_loop(predecode: bool) -> (Machine<'static>, Duration) {
+    let mut machine = Machine::new(&MICROROM, 0, 0).expect("microword 000 is implemented");
+    machine.state.SINCLK = false;
+    let _ = machine.input(Input::Switch(true));
+    if predecode {
+        machine.predecode();
+    }
+    let start = Instant::now();
+    while machine.run_headless(u64::MAX).is_ok() {}
+    (machine, start.elapsed())
+}
+
+fn main() {
+    let runs: u32 = std::env::args().nth(1).and_then(|runs| runs.parse().ok()).unwrap_or(20);
+
+    let mut totals = [Duration::ZERO; 2];
+    let mut cycles = 0;
+    for _ in 0..runs {
+        let (direct, direct_time) = console_loop(false);
+        let (decoded, decoded_time) = console_loop(true);
+
+        let (a, b) = (direct.state(), decoded.state());
+        let same = (a.CYCLE, a.TIME_NS, a.UPP, a.PUPP, a.BUBC, a.B, a.D, a.REGISTERS, a.HALTED)
+            == (b.CYCLE, b.TIME_NS, b.UPP, b.PUPP, b.BUBC, b.B, b.D, b.REGISTERS, b.HALTED);
+        if !same {
+            eprintln!("Pre-decoded run ended in a different state, at cycle {} not {}", b.CYCLE, a.CYCLE);
+            std::process::exit(1);
+        }
+
+        totals[0] += direct_time;
+        totals[1] += decoded_time;
+        cycles += a.CYCLE;
+    }
+
+    for (name, total) in ["direct", "pre-decoded"].iter().zip(totals.iter()) {
+        println!("{:<12} {:>8.2} million cycles per second", name, cycles as f64 / total.as_secs_f64() / 1e6);
+    }
+    println!("speed-up      {:>7.2}x, {} runs of {} cycles with identical results",
+        totals[0].as_secs_f64() / totals[1].as_secs_f64(), runs, cycles / runs as u64);
+}
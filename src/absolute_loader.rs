@@ -0,0 +1,127 @@
+#![allow(non_snake_case)]
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::unibus::{self, BusTimeout, Unibus};
+
+// Paper tapes in the DEC absolute loader format, as the MAINDEC diagnostics were shipped
+// A tape is blocks of
+//  001 000     start of block, anything before it (leader) is skipped
+//  u16         byte count, these six header bytes included
+//  u16         load address
+//  data        byte count - 6 bytes
+//  u8          checksum, the block's bytes sum to 0 with it
+// all little endian. The last block has no data, its address is where to start the program,
+// an odd address to load without starting.
+
+#[derive(Debug)]
+pub struct Block {
+    pub address: u16,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct Tape {
+    pub blocks: Vec<Block>,
+
+    // From the last block, None if it was odd
+    pub start: Option<u16>,
+}
+
+pub fn from_bytes(bytes: &[u8]) -> Result<Tape, String> {
+    let mut blocks = Vec::new();
+    let mut position = 0;
+    loop {
+        // Leader and trailer, and anything else between blocks
+        while position < bytes.len() && bytes[position] != 1 {
+            position += 1;
+        }
+        let header = bytes.get(position..position + 6).ok_or("no end block")?;
+        if header[1] != 0 {
+            position += 1;
+            continue;
+        }
+        let count = u16::from_le_bytes([header[2], header[3]]) as usize;
+        let address = u16::from_le_bytes([header[4], header[5]]);
+        if count < 6 {
+            return Err(format!("block at byte {} has a count of {}", position, count));
+        }
+        let block = bytes.get(position..position + count + 1)
+            .ok_or_else(|| format!("block at byte {} runs off the end of the tape", position))?;
+        let sum = block.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        if sum != 0 {
+            return Err(format!("block at byte {} has a bad checksum", position));
+        }
+        position += count + 1;
+        if count == 6 {
+            let start = if address & 1 == 0 { Some(address) } else { None };
+            return Ok(Tape { blocks, start });
+        }
+        blocks.push(Block { address, data: block[6..count].to_vec() });
+    }
+}
+
+pub fn read(path: &Path) -> io::Result<Tape> {
+    let bytes = fs::read(path)?;
+    from_bytes(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))
+}
+
+// Put the tape's blocks into memory a byte at a time, as the loader would
+pub fn load(tape: &Tape, unibus: &mut Unibus) -> Result<(), BusTimeout> {
+    for block in tape.blocks.iter() {
+        for (offset, byte) in block.data.iter().enumerate() {
+            let address = block.address.wrapping_add(offset as u16);
+            // A byte write takes the byte from the half of the bus its address selects
+            unibus.write(unibus::bus_address(address), *byte as u16 * 0o401, true)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A block as the tape punch writes it
+    fn block(address: u16, data: &[u8]) -> Vec<u8> {
+        let count = (data.len() + 6) as u16;
+        let mut bytes = vec![1, 0];
+        bytes.extend_from_slice(&count.to_le_bytes());
+        bytes.extend_from_slice(&address.to_le_bytes());
+        bytes.extend_from_slice(data);
+        let sum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        bytes.push(sum.wrapping_neg());
+        bytes
+    }
+
+    #[test]
+    fn loads_blocks_and_start() {
+        let mut bytes = vec![0; 20];
+        bytes.extend(block(0o1000, &[0o001, 0o002, 0o003]));
+        bytes.extend([0; 4]);
+        bytes.extend(block(0o1003, &[0o004]));
+        bytes.extend(block(0o200, &[]));
+        bytes.extend([0; 20]);
+        let tape = from_bytes(&bytes).unwrap();
+        assert_eq!(tape.blocks.len(), 2);
+        assert_eq!(tape.start, Some(0o200));
+
+        let mut unibus = Unibus::new(0o1000);
+        load(&tape, &mut unibus).unwrap();
+        assert_eq!(unibus.peek(0o1000), Some(0o001001));
+        assert_eq!(unibus.peek(0o1002), Some(0o002003));
+    }
+
+    #[test]
+    fn odd_start_and_bad_tapes() {
+        assert_eq!(from_bytes(&block(0o1, &[])).unwrap().start, None);
+
+        let mut bad = block(0o1000, &[0o123]);
+        bad[6] ^= 1;
+        assert!(from_bytes(&bad).unwrap_err().contains("checksum"));
+        assert!(from_bytes(&block(0o1000, &[0o123])).unwrap_err().contains("end block"));
+        assert!(from_bytes(&block(0o1000, &[0o123])[..5]).is_err());
+    }
+}
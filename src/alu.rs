@@ -0,0 +1,134 @@
+#![allow(non_snake_case)]
+
+// The ALU is four 74181s with a 74182 look ahead carry
+// The ALU field is SALUM followed by SALU3-SALU0, in 74181 terms M and S3-S0
+//  1 xxxx => logic functions
+//  0 xxxx => arithmetic functions, CIN adds one
+// Data is active high, so these are the functions from the active high 74181 table
+
+pub fn evaluate_alu(ALU: u8, A: u16, B: u16, CIN: bool) -> u16 {
+    evaluate_alu_carry(ALU, A, B, CIN).0
+}
+
+// The ALU output and the carry out of the top 74181 (through the 74182), no carry in logic mode
+// Each arithmetic function is a sum of two terms, minus one being plus 177777
+pub fn evaluate_alu_carry(ALU: u8, A: u16, B: u16, CIN: bool) -> (u16, bool) {
+    let M = (ALU >> 4) & 1;
+    let S = ALU & 0b1111;
+
+    if (ALU >> 5) > 0 {
+        panic!("Invalid ALU: {:o}", ALU);
+    }
+
+    if M == 1 {
+        let F = match S {
+            0b0000 => !A,
+            0b0001 => !(A | B),
+            0b0010 => !A & B,
+            0b0011 => 0,
+            0b0100 => !(A & B),
+            0b0101 => !B,
+            0b0110 => A ^ B,
+            0b0111 => A & !B,
+            0b1000 => !A | B,
+            0b1001 => !(A ^ B),
+            0b1010 => B,
+            0b1011 => A & B,
+            0b1100 => 0xFFFF,
+            0b1101 => A | !B,
+            0b1110 => A | B,
+            0b1111 => A,
+            _ => panic!("Invalid ALU: {:o}", ALU)
+        };
+        return (F, false);
+    }
+
+    let (P, Q) = match S {
+        0b0000 => (A, 0),
+        0b0001 => (A | B, 0),
+        0b0010 => (A | !B, 0),
+        0b0011 => (0, 0xFFFF),                          // Minus 1
+        0b0100 => (A, A & !B),
+        0b0101 => (A | B, A & !B),
+        0b0110 => (A, !B),                              // A minus B minus 1
+        0b0111 => (A & !B, 0xFFFF),
+        0b1000 => (A, A & B),
+        0b1001 => (A, B),
+        0b1010 => (A | !B, A & B),
+        0b1011 => (A & B, 0xFFFF),
+        0b1100 => (A, A),
+        0b1101 => (A | B, A),
+        0b1110 => (A | !B, A),
+        0b1111 => (A, 0xFFFF),
+        _ => panic!("Invalid ALU: {:o}", ALU)
+    };
+    let sum = P as u32 + Q as u32 + CIN as u32;
+    (sum as u16, sum > 0xFFFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: u16 = 0o123456;
+    const B: u16 = 0o070707;
+
+    #[test]
+    fn logic_functions() {
+        // S, F with M set, the carry in makes no difference
+        let table: [(u8, u16); 16] = [
+            (0b0000, 0o054321), (0b0001, 0o004020), (0b0010, 0o050301), (0b0011, 0o000000),
+            (0b0100, 0o157371), (0b0101, 0o107070), (0b0110, 0o153351), (0b0111, 0o103050),
+            (0b1000, 0o074727), (0b1001, 0o024426), (0b1010, 0o070707), (0b1011, 0o020406),
+            (0b1100, 0o177777), (0b1101, 0o127476), (0b1110, 0o173757), (0b1111, 0o123456),
+        ];
+        for (S, expected) in table {
+            for CIN in [false, true] {
+                assert_eq!(evaluate_alu_carry(0o20 | S, A, B, CIN), (expected, false), "S {:04b} CIN {}", S, CIN);
+            }
+        }
+    }
+
+    #[test]
+    fn arithmetic_functions() {
+        // S, F without the carry in
+        let table: [(u8, u16); 16] = [
+            (0b0000, A), (0b0001, A | B), (0b0010, A | !B), (0b0011, 0o177777),
+            (0b0100, A.wrapping_add(A & !B)), (0b0101, (A | B).wrapping_add(A & !B)),
+            (0b0110, A.wrapping_sub(B).wrapping_sub(1)), (0b0111, (A & !B).wrapping_sub(1)),
+            (0b1000, A.wrapping_add(A & B)), (0b1001, A.wrapping_add(B)),
+            (0b1010, (A | !B).wrapping_add(A & B)), (0b1011, (A & B).wrapping_sub(1)),
+            (0b1100, A.wrapping_add(A)), (0b1101, (A | B).wrapping_add(A)),
+            (0b1110, (A | !B).wrapping_add(A)), (0b1111, A.wrapping_sub(1)),
+        ];
+        for (S, expected) in table {
+            assert_eq!(evaluate_alu(S, A, B, false), expected, "S {:04b}", S);
+            assert_eq!(evaluate_alu(S, A, B, true), expected.wrapping_add(1), "S {:04b} with CIN", S);
+        }
+        assert_eq!(evaluate_alu(0b1001, 0o000005, 0o000003, false), 0o000010);
+        assert_eq!(evaluate_alu(0b0110, 0o000005, 0o000003, true), 0o000002);
+    }
+
+    #[test]
+    fn carry_out() {
+        // A plus B
+        assert_eq!(evaluate_alu_carry(0b1001, 0o177777, 0o000001, false), (0, true));
+        assert_eq!(evaluate_alu_carry(0b1001, 0o177776, 0o000001, false), (0o177777, false));
+        assert_eq!(evaluate_alu_carry(0b1001, 0o177776, 0o000001, true), (0, true));
+        // A minus B with the carry in, carry out is no borrow
+        assert_eq!(evaluate_alu_carry(0b0110, 5, 3, true), (2, true));
+        assert_eq!(evaluate_alu_carry(0b0110, 3, 5, true), (0o177776, false));
+        // Minus one carries out unless it is zero take one
+        assert_eq!(evaluate_alu_carry(0b1111, 1, 0, false), (0, true));
+        assert_eq!(evaluate_alu_carry(0b1111, 0, 0, false), (0o177777, false));
+        assert_eq!(evaluate_alu_carry(0b0011, A, B, true), (0, true));
+        // A plus A shifts the top bit out
+        assert_eq!(evaluate_alu_carry(0b1100, 0o100000, 0, false), (0, true));
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid ALU")]
+    fn rejects_wide_alu() {
+        evaluate_alu(0o40, 0, 0, false);
+    }
+}
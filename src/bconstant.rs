@@ -1,3 +1,4 @@
+use crate::error::EmulatorError;
 use crate::machinestate::MachineState;
 
 // TODO: Complete Constant 00 to enable traps
@@ -18,29 +19,83 @@ use crate::machinestate::MachineState;
 // In the case of the emulation we just do all 16 bits individually
 // we just need to make sure we generate the right constant
 
-pub fn evaluate_bconstant(state: &MachineState, sbc: u8) -> u16 {
+pub fn evaluate_bconstant(state: &MachineState, sbc: u8) -> Result<u16, EmulatorError> {
+    bconstant(sbc, state.SINCLK, state.UPP)
+}
+
+// The constant for the SBC of the microword at UPP, SINCLK is the only machine state it depends on
+pub fn bconstant(sbc: u8, SINCLK: bool, UPP: u8) -> Result<u16, EmulatorError> {
+    let unimplemented = EmulatorError::UnimplementedConstant { SBC: sbc, UPP };
+    let invalid = EmulatorError::InvalidConstant { SBC: sbc, UPP };
     match sbc {
-        0o00 => 0,
-        0o01 => 1,          // CONST1
-        0o02 => 2,          // CONST2
-        0o03 => todo!("Constant 03"),
-        0o04 => panic!("Constant 04 NOT USED"),
-        0o05 => panic!("Constant 04 NOT USED"),
-        0o06 => panic!("Constant 04 NOT USED"),
-        0o07 => todo!("Constant 07"),
-        0o10 => 0b1111111101111000, // SR Address
-        0o11 => todo!("Constant 11"),
-        0o12 => 0b0000000000001111, // CC MASK
-        0o13 => 0b0000000000111111, // SOB MASK
+        0o00 => Ok(0),
+        0o01 => Ok(1),          // CONST1
+        0o02 => Ok(2),          // CONST2
+        0o03 => Err(unimplemented),
+        0o04 => Err(invalid),   // NOT USED
+        0o05 => Err(invalid),   // NOT USED
+        0o06 => Err(invalid),   // NOT USED
+        0o07 => Err(unimplemented),
+        0o10 => Ok(0b1111111101111000), // SR Address
+        0o11 => Err(unimplemented),
+        0o12 => Ok(0b0000000000001111), // CC MASK
+        0o13 => Ok(0b0000000000111111), // SOB MASK
         0o14 => {                   // SINCLK
-            match state.SINCLK {
-                true => 0,
-                false => 0b0000000000010000,
+            match SINCLK {
+                true => Ok(0),
+                false => Ok(0b0000000000010000),
             }
         },
-        0o15 => 0b0000000010101000, // MM VECTOR
-        0o16 => todo!("Constant 16"),
-        0o17 => 0b0000000000000100, // STACK04
-        _ => panic!("Invalid 'Select B Constant'")
+        0o15 => Ok(0b0000000010101000), // MM VECTOR
+        0o16 => Err(unimplemented),
+        0o17 => Ok(0b0000000000000100), // STACK04
+        _ => Err(invalid)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::microrom::MICROROM;
+
+    #[test]
+    fn every_constant() {
+        let mut state = MachineState::new(&MICROROM, 0o26).unwrap();
+        let unimplemented = |SBC| Err(EmulatorError::UnimplementedConstant { SBC, UPP: 0o26 });
+        let invalid = |SBC| Err(EmulatorError::InvalidConstant { SBC, UPP: 0o26 });
+        let table: [(u8, Result<u16, EmulatorError>); 17] = [
+            (0o00, Ok(0)),
+            (0o01, Ok(1)),
+            (0o02, Ok(2)),
+            (0o03, unimplemented(0o03)),
+            (0o04, invalid(0o04)),
+            (0o05, invalid(0o05)),
+            (0o06, invalid(0o06)),
+            (0o07, unimplemented(0o07)),
+            (0o10, Ok(0o177570)),
+            (0o11, unimplemented(0o11)),
+            (0o12, Ok(0o17)),
+            (0o13, Ok(0o77)),
+            (0o14, Ok(0)),
+            (0o15, Ok(0o250)),
+            (0o16, unimplemented(0o16)),
+            (0o17, Ok(0o4)),
+            (0o20, invalid(0o20)),
+        ];
+        state.SINCLK = true;
+        for (SBC, expected) in table {
+            assert_eq!(evaluate_bconstant(&state, SBC), expected, "SBC {:02o}", SBC);
+        }
+    }
+
+    #[test]
+    fn sinclk_constant() {
+        // Single clock mode skips the SWITCH debounce count
+        let mut state = MachineState::new(&MICROROM, 0o27).unwrap();
+        state.SINCLK = true;
+        assert_eq!(evaluate_bconstant(&state, 0o14), Ok(0));
+        state.SINCLK = false;
+        assert_eq!(evaluate_bconstant(&state, 0o14), Ok(0o20));
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,298 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use pdp11_microcode_emulator::history::DEFAULT_HISTORY_CYCLES;
+use pdp11_microcode_emulator::machinestate::{SINGLE_CLOCK_MODE, STARTING_UPP};
+use pdp11_microcode_emulator::trace::{self, TraceFormat};
+
+pub const USAGE: &str = "\
+Usage: pdp11-microcode-emulator [options]
+
+Run modes:
+  --step                    Single step, press Enter for each machine cycle, q to quit (default)
+  --inst-step               Single step, press Enter for each PDP-11 instruction, q to quit
+  --run                     Free run, sleeping between machine cycles
+  --max-speed               Free run without sleeping or displaying each cycle
+  --headless                Run as fast as possible, console output only and a summary at the end
+  --cycles <n>              With --headless, stop after n machine cycles
+  --debug                   Start the interactive microcode debugger
+  --history <cycles>        Machine cycles the debugger can step back over (default 65536)
+  --sleep <ms>              With --run, sleep between machine cycles (default 100)
+  --pace <scale>            With --run or --max-speed, run at scale times the speed of a real
+                            11/40 by emulated time, 1 for real time, 0.001 to watch the lamps
+
+Machine:
+  --start-upp <octal>       Microword to start from (default 000)
+  --sinclk <on|off>         Single clock mode, as set by a KM11 (default on)
+  --microrom <dir>          Load the microROM from PROM images written by --export-rom
+  --memory <words>          Memory size in words, K suffix for 1024 (default 28K)
+  --device <name>           Attach a Unibus device: dl11, kw11l (may be repeated)
+  --restore <file>          Start from a snapshot instead of power up
+  --save <file>             Save a snapshot of the machine on exit
+  --record <file>           Log switch and console inputs with the emulated time they happened at
+  --replay <file>           Feed a logged run's inputs back in at the same times
+  --watch <spec>            Stop when a watchpoint triggers (may be repeated)
+                            reg <n>, d <value>, bus <low> [<high>], priority, expr <expression>
+
+Output:
+  --trace <file>            Log every machine cycle
+  --trace-format <format>   text or binary (default text)
+  --trace-fields <list>     Comma separated fields to trace, or all (default all)
+                            upp, pupp, ubf, bubc, clk, bmux, dmux, alu, d, b, wr
+  --vcd <file>              Dump the processor signals by emulated time as a Value Change Dump
+  --coverage <file>         Accumulate microcode coverage into a file
+  --export-rom <dir>        Write the microROM as PROM images, in the emulator's own layout, and exit
+  --dot <file>              Write the microflow as a Graphviz graph and exit
+  --help                    Show this message";
+
+pub const DEFAULT_MEMORY_WORDS: usize = 28 * 1024;
+pub const DEFAULT_CYCLE_SLEEP: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RunMode {
+    SingleStep,
+    InstructionStep,
+    FreeRun,
+    MaxSpeed,
+    Headless,
+    Debug,
+}
+
+pub struct Options {
+    pub mode: RunMode,
+    pub cycle_sleep: Duration,
+    pub cycles: Option<u64>,
+    pub pace: Option<f64>,
+    pub history: usize,
+    pub start_upp: u8,
+    pub sinclk: bool,
+    pub microrom: Option<PathBuf>,
+    pub memory_words: usize,
+    pub devices: Vec<String>,
+    pub watches: Vec<String>,
+    pub restore: Option<PathBuf>,
+    pub save: Option<PathBuf>,
+    pub record: Option<PathBuf>,
+    pub replay: Option<PathBuf>,
+    pub trace: Option<PathBuf>,
+    pub trace_format: TraceFormat,
+    pub trace_fields: u32,
+    pub vcd: Option<PathBuf>,
+    pub coverage: Option<PathBuf>,
+    pub export_rom: Option<PathBuf>,
+    pub dot: Option<PathBuf>,
+    pub help: bool,
+}
+
+impl Options {
+    pub fn new() -> Options {
+        Options {
+            mode: RunMode::SingleStep,
+            cycle_sleep: DEFAULT_CYCLE_SLEEP,
+            cycles: None,
+            pace: None,
+            history: DEFAULT_HISTORY_CYCLES,
+            start_upp: STARTING_UPP,
+            sinclk: SINGLE_CLOCK_MODE,
+            microrom: None,
+            memory_words: DEFAULT_MEMORY_WORDS,
+            devices: Vec::new(),
+            watches: Vec::new(),
+            restore: None,
+            save: None,
+            record: None,
+            replay: None,
+            trace: None,
+            trace_format: TraceFormat::Text,
+            trace_fields: trace::TRACE_ALL,
+            vcd: None,
+            coverage: None,
+            export_rom: None,
+            dot: None,
+            help: false,
+        }
+    }
+}
+
+// Parse the command line, not including the program name
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options::new();
+    let mut args = args.into_iter();
+    let mut sleep = false;
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--step" => options.mode = RunMode::SingleStep,
+            "--inst-step" => options.mode = RunMode::InstructionStep,
+            "--run" => options.mode = RunMode::FreeRun,
+            "--max-speed" => options.mode = RunMode::MaxSpeed,
+            "--headless" => options.mode = RunMode::Headless,
+            "--debug" => options.mode = RunMode::Debug,
+            "--cycles" => {
+                let cycles = value()?;
+                options.cycles = Some(cycles.parse::<u64>().map_err(|_| format!("invalid cycles '{}'", cycles))?);
+            },
+            "--pace" => {
+                let scale = value()?;
+                match scale.parse::<f64>() {
+                    Ok(scale) if scale > 0.0 && scale.is_finite() => options.pace = Some(scale),
+                    _ => return Err(format!("invalid pace '{}'", scale)),
+                }
+            },
+            "--history" => {
+                let cycles = value()?;
+                options.history = cycles.parse::<usize>().map_err(|_| format!("invalid history '{}'", cycles))?;
+            },
+            "--sleep" => {
+                let ms = value()?;
+                let ms = ms.parse::<u64>().map_err(|_| format!("invalid sleep '{}'", ms))?;
+                options.cycle_sleep = Duration::from_millis(ms);
+                sleep = true;
+            },
+            "--start-upp" => {
+                let upp = value()?;
+                options.start_upp = u8::from_str_radix(&upp, 8).map_err(|_| format!("invalid UPP '{}'", upp))?;
+            },
+            "--sinclk" => {
+                options.sinclk = match value()?.as_str() {
+                    "on" => true,
+                    "off" => false,
+                    other => return Err(format!("invalid sinclk '{}'", other)),
+                };
+            },
+            "--microrom" => options.microrom = Some(value()?.into()),
+            "--memory" => options.memory_words = parse_size(&value()?)?,
+            "--device" => options.devices.push(value()?.to_lowercase()),
+            "--restore" => options.restore = Some(value()?.into()),
+            "--save" => options.save = Some(value()?.into()),
+            "--record" => options.record = Some(value()?.into()),
+            "--replay" => options.replay = Some(value()?.into()),
+            "--watch" => options.watches.push(value()?),
+            "--trace" => options.trace = Some(value()?.into()),
+            "--trace-format" => {
+                options.trace_format = match value()?.as_str() {
+                    "text" => TraceFormat::Text,
+                    "binary" => TraceFormat::Binary,
+                    other => return Err(format!("invalid trace format '{}'", other)),
+                };
+            },
+            "--trace-fields" => options.trace_fields = trace::parse_fields(&value()?)?,
+            "--vcd" => options.vcd = Some(value()?.into()),
+            "--coverage" => options.coverage = Some(value()?.into()),
+            "--export-rom" => options.export_rom = Some(value()?.into()),
+            "--dot" => options.dot = Some(value()?.into()),
+            "--help" | "-h" => options.help = true,
+            _ => return Err(format!("unknown option '{}'", arg)),
+        }
+    }
+
+    // Options the run mode wouldn't look at, the mode may come after them
+    let mode = options.mode;
+    if options.cycles.is_some() && mode != RunMode::Headless {
+        return Err(String::from("--cycles only applies to --headless"));
+    }
+    if options.pace.is_some() && mode != RunMode::FreeRun && mode != RunMode::MaxSpeed {
+        return Err(String::from("--pace only applies to --run and --max-speed"));
+    }
+    if sleep && mode != RunMode::FreeRun {
+        return Err(String::from("--sleep only applies to --run"));
+    }
+    if sleep && options.pace.is_some() {
+        return Err(String::from("--sleep doesn't apply with --pace, the pace sets the speed"));
+    }
+    Ok(options)
+}
+
+// A count of words, "28K" is 28 * 1024
+fn parse_size(size: &str) -> Result<usize, String> {
+    let invalid = || format!("invalid memory size '{}'", size);
+    match size.strip_suffix(['k', 'K']) {
+        Some(kilo) => kilo.parse::<usize>().map(|k| k * 1024).map_err(|_| invalid()),
+        None => size.parse::<usize>().map_err(|_| invalid()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_line(line: &str) -> Result<Options, String> {
+        parse(line.split_whitespace().map(String::from))
+    }
+
+    fn error(line: &str) -> String {
+        match parse_line(line) {
+            Ok(_) => panic!("'{}' parsed", line),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn defaults() {
+        let options = parse_line("").unwrap();
+        assert_eq!(options.mode, RunMode::SingleStep);
+        assert_eq!(options.cycle_sleep, DEFAULT_CYCLE_SLEEP);
+        assert_eq!((options.cycles, options.pace), (None, None));
+        assert_eq!(options.history, DEFAULT_HISTORY_CYCLES);
+        assert_eq!((options.start_upp, options.sinclk), (STARTING_UPP, SINGLE_CLOCK_MODE));
+        assert_eq!(options.memory_words, DEFAULT_MEMORY_WORDS);
+        assert_eq!(options.trace_format, TraceFormat::Text);
+        assert!(!options.help);
+    }
+
+    #[test]
+    fn values() {
+        let options = parse_line("--headless --cycles 1000 --start-upp 026 --sinclk off --memory 4K --device DL11 --device kw11l").unwrap();
+        assert_eq!(options.mode, RunMode::Headless);
+        assert_eq!(options.cycles, Some(1000));
+        assert_eq!((options.start_upp, options.sinclk), (0o26, false));
+        assert_eq!(options.memory_words, 4096);
+        assert_eq!(options.devices, ["dl11", "kw11l"]);
+
+        let options = parse_line("--run --sleep 5 --watch reg --watch d --trace-format binary --memory 100").unwrap();
+        assert_eq!(options.cycle_sleep, Duration::from_millis(5));
+        assert_eq!(options.watches, ["reg", "d"]);
+        assert_eq!(options.trace_format, TraceFormat::Binary);
+        assert_eq!(options.memory_words, 100);
+
+        assert_eq!(parse_line("--max-speed --pace 0.001").unwrap().pace, Some(0.001));
+        assert_eq!(parse_line("--debug --history 10").unwrap().history, 10);
+        assert_eq!(parse_line("--dot flow.dot").unwrap().dot, Some(PathBuf::from("flow.dot")));
+
+        // The last mode given wins
+        assert_eq!(parse_line("--run --inst-step").unwrap().mode, RunMode::InstructionStep);
+    }
+
+    #[test]
+    fn bad_values() {
+        assert_eq!(error("--frobnicate"), "unknown option '--frobnicate'");
+        assert_eq!(error("--headless --cycles"), "--cycles needs a value");
+        assert_eq!(error("--headless --cycles -1"), "invalid cycles '-1'");
+        assert_eq!(error("--run --pace 0"), "invalid pace '0'");
+        assert_eq!(error("--run --pace -2"), "invalid pace '-2'");
+        assert_eq!(error("--run --pace inf"), "invalid pace 'inf'");
+        assert_eq!(error("--run --sleep 1.5"), "invalid sleep '1.5'");
+        assert_eq!(error("--history many"), "invalid history 'many'");
+        assert_eq!(error("--start-upp 8"), "invalid UPP '8'");
+        assert_eq!(error("--start-upp 400"), "invalid UPP '400'");
+        assert_eq!(error("--sinclk maybe"), "invalid sinclk 'maybe'");
+        assert_eq!(error("--memory 28M"), "invalid memory size '28M'");
+        assert_eq!(error("--trace-format csv"), "invalid trace format 'csv'");
+    }
+
+    #[test]
+    fn options_the_mode_does_not_use() {
+        assert_eq!(error("--cycles 10"), "--cycles only applies to --headless");
+        assert_eq!(error("--cycles 10 --run"), "--cycles only applies to --headless");
+        assert_eq!(error("--headless --pace 1"), "--pace only applies to --run and --max-speed");
+        assert_eq!(error("--pace 1 --debug"), "--pace only applies to --run and --max-speed");
+        assert_eq!(error("--max-speed --sleep 10"), "--sleep only applies to --run");
+        assert_eq!(error("--sleep 10"), "--sleep only applies to --run");
+        assert_eq!(error("--run --sleep 10 --pace 1"), "--sleep doesn't apply with --pace, the pace sets the speed");
+
+        // The mode may come after the options for it
+        assert_eq!(parse_line("--cycles 10 --headless").unwrap().cycles, Some(10));
+        assert_eq!(parse_line("--pace 2 --run").unwrap().pace, Some(2.0));
+    }
+}
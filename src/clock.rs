@@ -1,6 +1,7 @@
 #![allow(non_snake_case)]
 #![allow(clippy::upper_case_acronyms)]
 
+use crate::error::EmulatorError;
 
 #[derive(Debug, PartialEq)]
 pub enum ClockPulse {
@@ -10,7 +11,6 @@ pub enum ClockPulse {
     P3,
 }
 
-#[allow(dead_code)]
 #[derive(PartialEq, Debug)]
 pub enum ClockMode {
     OFF,
@@ -19,26 +19,86 @@ pub enum ClockMode {
     CL3,
 }
 
+// When a clocked part of the processor is clocked
+#[derive(Debug, PartialEq)]
+pub enum Edge {
+    // Every clock pulse
+    Every,
+
+    // The pulse that ends the machine cycle, P1 in CL1, P2 in CL2 and P3 in CL3
+    CycleEnd,
+
+    // One pulse, in whichever clock mode has it
+    Pulse(ClockPulse),
+}
+
 pub struct ClockState {
     pub mode: ClockMode,
     pub pulse_count: u32,
-    pub pulses: &'static [ClockPulse; 2]
+    pub pulses: &'static [ClockPulse; 2],
+
+    // Nanoseconds from the start of the machine cycle, or the pulse before, to each pulse
+    // The machine cycle is 140, 200 or 300 ns long in CL1, CL2 and CL3
+    pub pulse_ns: [u64; 2],
+}
+
+// The clock is stopped, no microwords run until the console restarts it
+// Time still passes, counted in steps of one pulse_ns
+pub const OFF: ClockState = ClockState {
+    mode: ClockMode::OFF,
+    pulse_count: 1,
+    pulses: &[ClockPulse::NONE, ClockPulse::NONE],
+    pulse_ns: [300, 0],
+};
+
+impl ClockState {
+    // True if edge comes on pulse (0 or 1) of this clock mode's machine cycle
+    pub fn fires(&self, edge: &Edge, pulse: u32) -> bool {
+        match edge {
+            Edge::Every => true,
+            Edge::CycleEnd => pulse + 1 == self.pulse_count,
+            Edge::Pulse(active) => self.pulses[pulse as usize] == *active,
+        }
+    }
+}
+
+// RE-CLOCK, the clock mode a microword's CLK field selects for the machine cycle after it
+//  1 and 2 are CL1, 3 and 4 CL2, 5 and 6 CL3
+//  0 and 7 turn the clock off (CLKOFF), it waits for SSYN on a bus cycle, for HALT and for the console
+//  A bus cycle started by the machine cycle just run has already had SSYN, so that restarts at once
+// The print set isn't transcribed here, 0 and 7 being CLKOFF comes from them being the two codes
+// left over. No transcribed microword has either, so the shipped microROM doesn't depend on it.
+pub fn reclock(CLK: u8, bus_access: bool, UPP: u8) -> Result<&'static ClockState, EmulatorError> {
+    match CLK {
+        0 | 7 if bus_access => Ok(&CL3),
+        0 | 7 => Ok(&OFF),
+        6 => Ok(&CL3),
+        5 => Ok(&CL3),
+        4 => Ok(&CL2),
+        3 => Ok(&CL2),
+        2 => Ok(&CL1),
+        1 => Ok(&CL1),
+        CLK => Err(EmulatorError::InvalidClock { CLK, UPP }),
+    }
 }
 
 pub const CL1: ClockState = ClockState {
     mode: ClockMode::CL1,
     pulse_count: 1,
-    pulses: &[ClockPulse::P1, ClockPulse::NONE]
+    pulses: &[ClockPulse::P1, ClockPulse::NONE],
+    pulse_ns: [140, 0],
 };
 
 pub const CL2: ClockState = ClockState {
     mode: ClockMode::CL2,
     pulse_count: 1,
-    pulses: &[ClockPulse::P2, ClockPulse::NONE]
+    pulses: &[ClockPulse::P2, ClockPulse::NONE],
+    pulse_ns: [200, 0],
 };
 
 pub const CL3: ClockState = ClockState {
     mode: ClockMode::CL3,
     pulse_count: 2,
-    pulses: &[ClockPulse::P2, ClockPulse::P3]
+    pulses: &[ClockPulse::P2, ClockPulse::P3],
+    pulse_ns: [200, 100],
 };
\ No newline at end of file
@@ -0,0 +1,278 @@
+#![allow(non_snake_case)]
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::microrom::MicroRom;
+
+// Microcode coverage, accumulated across runs
+//
+// Counts are kept in a plain text file, one entry per line:
+//  word <UPP> <hits>
+//  branch <UPP> <BUBC> <hits>
+// with UPP and BUBC in octal. A run loads the file, adds its own counts and writes it back,
+// along with a text report (<file>.txt) and a JSON report (<file>.json).
+
+pub struct Coverage {
+    // Number of machine cycles each microword was in the U WORD register
+    pub words: [u64; 256],
+
+    // Number of times each BUT outcome was taken, keyed by (UPP of the BUT, BUBC)
+    pub branches: BTreeMap<(u8, u8), u64>,
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage {
+            words: [0; 256],
+            branches: BTreeMap::new(),
+        }
+    }
+
+    // Load counts from a previous run, a missing file is an empty coverage set
+    pub fn load(path: &Path) -> io::Result<Coverage> {
+        let mut coverage = Coverage::new();
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(coverage),
+            Err(e) => return Err(e),
+        };
+
+        for (number, line) in text.lines().enumerate() {
+            let invalid = || io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}:{}: invalid coverage entry '{}'", path.display(), number + 1, line),
+            );
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [] => {},
+                ["word", upp, hits] => {
+                    let upp = u8::from_str_radix(upp, 8).map_err(|_| invalid())?;
+                    coverage.words[upp as usize] += hits.parse::<u64>().map_err(|_| invalid())?;
+                },
+                ["branch", upp, bubc, hits] => {
+                    let upp = u8::from_str_radix(upp, 8).map_err(|_| invalid())?;
+                    let bubc = u8::from_str_radix(bubc, 8).map_err(|_| invalid())?;
+                    *coverage.branches.entry((upp, bubc)).or_insert(0) += hits.parse::<u64>().map_err(|_| invalid())?;
+                },
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(coverage)
+    }
+
+    pub fn record_word(&mut self, UPP: u8) {
+        self.words[UPP as usize] += 1;
+    }
+
+    pub fn record_branch(&mut self, UPP: u8, BUBC: u8) {
+        *self.branches.entry((UPP, BUBC)).or_insert(0) += 1;
+    }
+
+    // Implemented microwords that have never been executed
+    pub fn unexecuted(&self, rom: &MicroRom) -> Vec<u8> {
+        (0..=255u8)
+            .filter(|&upp| rom.words()[upp as usize].implemented && self.words[upp as usize] == 0)
+            .collect()
+    }
+
+    // Unimplemented microwords that were reached, these are the next to transcribe
+    pub fn unimplemented_reached(&self, rom: &MicroRom) -> Vec<u8> {
+        (0..=255u8)
+            .filter(|&upp| !rom.words()[upp as usize].implemented && self.words[upp as usize] > 0)
+            .collect()
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut text = String::new();
+        for (upp, hits) in self.words.iter().enumerate() {
+            if *hits > 0 {
+                text += &format!("word {:03o} {}\n", upp, hits);
+            }
+        }
+        for ((upp, bubc), hits) in self.branches.iter() {
+            text += &format!("branch {:03o} {:02o} {}\n", upp, bubc, hits);
+        }
+        fs::write(path, text)
+    }
+
+    pub fn text_report(&self, rom: &MicroRom) -> String {
+        let implemented = rom.words().iter().filter(|w| w.implemented).count();
+        let executed = (0..256).filter(|&upp| rom.words()[upp].implemented && self.words[upp] > 0).count();
+
+        let mut report = String::new();
+        report += "Microcode coverage\n";
+        report += &format!("Implemented microwords executed: {}/{}\n\n", executed, implemented);
+
+        report += "UPP    HITS        IMPLEMENTED\n";
+        for upp in 0..256 {
+            let implemented = rom.words()[upp].implemented;
+            if implemented || self.words[upp] > 0 {
+                report += &format!("{:03o}    {:<10}  {}\n", upp, self.words[upp], implemented);
+            }
+        }
+
+        report += "\nBUT outcomes (UPP BUBC HITS)\n";
+        for ((upp, bubc), hits) in self.branches.iter() {
+            report += &format!("{:03o}    {:02o}    {}\n", upp, bubc, hits);
+        }
+
+        report += "\nImplemented but never executed:\n";
+        for upp in self.unexecuted(rom) {
+            report += &format!("  {:03o}\n", upp);
+        }
+
+        report += "\nReached but not implemented:\n";
+        for upp in self.unimplemented_reached(rom) {
+            report += &format!("  {:03o}\n", upp);
+        }
+        report
+    }
+
+    pub fn json_report(&self, rom: &MicroRom) -> String {
+        let octal_list = |list: Vec<u8>| list.iter()
+            .map(|upp| format!("\"{:03o}\"", upp))
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        let words = (0..256)
+            .filter(|&upp| rom.words()[upp].implemented || self.words[upp] > 0)
+            .map(|upp| format!(
+                "    {{ \"upp\": \"{:03o}\", \"hits\": {}, \"implemented\": {} }}",
+                upp, self.words[upp], rom.words()[upp].implemented
+            ))
+            .collect::<Vec<String>>()
+            .join(",\n");
+
+        let branches = self.branches.iter()
+            .map(|((upp, bubc), hits)| format!(
+                "    {{ \"upp\": \"{:03o}\", \"bubc\": \"{:02o}\", \"hits\": {} }}",
+                upp, bubc, hits
+            ))
+            .collect::<Vec<String>>()
+            .join(",\n");
+
+        format!(
+            "{{\n  \"words\": [\n{}\n  ],\n  \"branches\": [\n{}\n  ],\n  \"unexecuted\": [{}],\n  \"unimplemented_reached\": [{}]\n}}\n",
+            words,
+            branches,
+            octal_list(self.unexecuted(rom)),
+            octal_list(self.unimplemented_reached(rom)),
+        )
+    }
+
+    // Write the merged counts and both reports next to them
+    pub fn write(&self, path: &Path, rom: &MicroRom) -> io::Result<()> {
+        self.save(path)?;
+        fs::write(report_path(path, "txt"), self.text_report(rom))?;
+        fs::write(report_path(path, "json"), self.json_report(rom))
+    }
+}
+
+impl Default for Coverage {
+    fn default() -> Coverage {
+        Coverage::new()
+    }
+}
+
+fn report_path(path: &Path, extension: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(extension);
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::EmulatorError;
+    use crate::machine::Machine;
+    use crate::microrom::MICROROM;
+    use crate::replay::Input;
+
+    fn file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("coverage_{}_{}", name, std::process::id()))
+    }
+
+    // The console loop from 000 with the coverage in path added to, as a --coverage run does
+    fn run(path: &Path, cycles: usize) -> Coverage {
+        let mut machine = Machine::new(&MICROROM, 0, 0).unwrap();
+        machine.state.SINCLK = false;
+        machine.session.coverage = Some(Coverage::load(path).unwrap());
+        for _ in 0..cycles {
+            machine.step_cycle().unwrap();
+        }
+        let coverage = machine.session.coverage.take().unwrap();
+        coverage.save(path).unwrap();
+        coverage
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let path = file("round_trip");
+        let mut coverage = Coverage::new();
+        coverage.words[0o000] = 1;
+        coverage.words[0o047] = 12345678901;
+        coverage.words[0o377] = 2;
+        coverage.record_branch(0o026, 0o001);
+        coverage.record_branch(0o026, 0o001);
+        coverage.record_branch(0o046, 0o000);
+        coverage.save(&path).unwrap();
+        let loaded = Coverage::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.words, coverage.words);
+        assert_eq!(loaded.branches, coverage.branches);
+        assert_eq!(loaded.branches[&(0o026, 0o001)], 2);
+    }
+
+    #[test]
+    fn merged_runs_sum() {
+        let path = file("merged");
+        let first = run(&path, 500);
+        let merged = run(&path, 500);
+        let loaded = Coverage::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert!(first.words.iter().sum::<u64>() > 0 && !first.branches.is_empty());
+        for UPP in 0..256 {
+            assert_eq!(merged.words[UPP], first.words[UPP] * 2, "word {:03o}", UPP);
+        }
+        for (key, hits) in first.branches.iter() {
+            assert_eq!(merged.branches[key], hits * 2, "branch {:03o} {:02o}", key.0, key.1);
+        }
+        assert_eq!(merged.branches.len(), first.branches.len());
+        assert_eq!((loaded.words, loaded.branches), (merged.words, merged.branches));
+    }
+
+    // The console loop, with SWITCH set and the single clock on, runs out of microcode at 045
+    #[test]
+    fn unimplemented_reached_in_a_run() {
+        let mut machine = Machine::new(&MICROROM, 0, 0).unwrap();
+        machine.input(Input::Switch(true)).unwrap();
+        machine.session.coverage = Some(Coverage::new());
+        let error = machine.run_until(100, |_| false).unwrap_err();
+        assert!(matches!(error, EmulatorError::UnimplementedMicroword { UPP: 0o045, .. }));
+
+        let coverage = machine.session.coverage.as_ref().unwrap();
+        assert_eq!(coverage.unimplemented_reached(&MICROROM), [0o045]);
+        assert_eq!(coverage.words[0o045], 1);
+        assert!(coverage.words[0o047] > 0);
+    }
+
+    #[test]
+    fn missing_and_bad_files() {
+        let path = file("bad");
+        assert_eq!(Coverage::load(&path).unwrap().words, [0; 256]);
+
+        for text in ["word 047", "word 400 1", "word 8 1", "branch 026 01", "word 047 -1", "line 047 1"] {
+            fs::write(&path, format!("word 000 1\n{}\n", text)).unwrap();
+            let error = Coverage::load(&path).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{}", text);
+            assert!(error.to_string().contains(":2: invalid coverage entry"), "{}", error);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
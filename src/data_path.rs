@@ -61,4 +61,62 @@ pub fn evaluate_bmux(SBM: u8, B: u16, B_CONST: u16) -> u16 {
     };
 
     ((bmuxH as u16) << 8) | (bmuxL as u16)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // B with bit 7 set, so SBM high byte 01 sign extends to all ones
+    const B: u16 = 0x12F4;
+    const B_CONST: u16 = 0x5A3C;
+
+    #[test]
+    fn bmux_every_sbm() {
+        // SBM, BMUX: low byte from B, B, swapped B or the constant,
+        // high byte from B, the sign of B, swapped B or the constant
+        let table: [(u8, u16); 16] = [
+            (0b0000, 0x12F4), (0b0001, 0x12F4), (0b0010, 0x1212), (0b0011, 0x123C),
+            (0b0100, 0xFFF4), (0b0101, 0xFFF4), (0b0110, 0xFF12), (0b0111, 0xFF3C),
+            (0b1000, 0xF4F4), (0b1001, 0xF4F4), (0b1010, 0xF412), (0b1011, 0xF43C),
+            (0b1100, 0x5AF4), (0b1101, 0x5AF4), (0b1110, 0x5A12), (0b1111, 0x5A3C),
+        ];
+        for (SBM, expected) in table {
+            assert_eq!(evaluate_bmux(SBM, B, B_CONST), expected, "SBM {:04b}", SBM);
+        }
+    }
+
+    #[test]
+    fn bmux_sign_extension() {
+        assert_eq!(evaluate_bmux(0b0100, 0xAB7F, 0), 0x007F);
+        assert_eq!(evaluate_bmux(0b0100, 0xAB80, 0), 0xFF80);
+    }
+
+    #[test]
+    fn bmux_byte_swap() {
+        assert_eq!(evaluate_bmux(0b1010, 0x0102, 0), 0x0201);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid SBM")]
+    fn bmux_rejects_wide_sbm() {
+        evaluate_bmux(0o20, 0, 0);
+    }
+
+    #[test]
+    fn dmux_every_sdm() {
+        // SDM, BUS RD, BUS D, D, carry, DMUX
+        let table: [(u8, u16, u16, u16, u8, u16); 6] = [
+            (0b00, 0o1, 0o2, 0o3, 0, 0o1),
+            (0b01, 0o1, 0o2, 0o3, 0, 0o2),
+            (0b10, 0o1, 0o2, 0o3, 0, 0o3),
+            // Shift D right, the carry goes into bit 15
+            (0b11, 0, 0, 0x8001, 0, 0x4000),
+            (0b11, 0, 0, 0x8001, 1, 0xC000),
+            (0b11, 0, 0, 0xFFFF, 1, 0xFFFF),
+        ];
+        for (SDM, BUS_RD, BUS_D, D, D_C, expected) in table {
+            assert_eq!(evaluate_dmux(SDM, BUS_RD, BUS_D, D, D_C), expected, "SDM {:02b} D {:04x} C {}", SDM, D, D_C);
+        }
+    }
+}
@@ -0,0 +1,438 @@
+#![allow(non_snake_case)]
+
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+use crate::disassembler;
+use crate::machine::{Machine, StepEnd};
+use crate::machinestate::{MachineState, FIELD_NAMES};
+use crate::processor::Session;
+use crate::replay::Input;
+use crate::watch::{Watchpoint, Watchpoints};
+
+// Interactive microcode debugger
+// Every number typed or printed is octal, as on the front panel
+
+const HELP: &str = "\
+step [n]              s    Run n machine cycles (default 1)
+pulse [n]             p    Run n clock pulses (default 1)
+istep [n]             si   Run n PDP-11 instructions, up to the fetch of the next one (default 1)
+continue [n]          c    Run until a breakpoint or halt, at most n machine cycles
+until-branch <upp> [<target>]
+                      ub   Run until a BUT is taken out of the microword at <upp> [to <target>]
+back [n]              sb   Step back n machine cycles (default 1)
+run-back [field]      rb   Run backwards to a breakpoint, or to the cycle that last changed field
+break <upp>           b    Stop before the microword at <upp> runs
+pbreak <pupp>         pb   Stop when PUPP is <pupp>
+delete <upp>|all      del  Remove a breakpoint
+breaks                bl   List breakpoints
+watch <spec>          w    Stop when a watchpoint triggers
+                           reg <n>, d <value>, bus <low> [<high>], priority, expr <expression>
+watches               wl   List watchpoints
+unwatch <n>|all       uw   Remove a watchpoint
+print [field]         x    Print the machine state, or one field
+set <field> <value>        Change a field, registers are R0 to R17
+examine <addr> [n]    e    Print n words of Unibus memory
+examine/i <addr> [n]  e/i  Disassemble n PDP-11 instructions from memory
+deposit <addr> <value>..   dep  Write words to Unibus memory
+switch 0|1                 Set or clear the SWITCH flip-flop, as the front panel switches do
+type <text>                Type text at the console terminal, \\r for return
+save <file>                Save a snapshot of the machine
+restore <file>             Restore the machine from a snapshot
+help                  h    Show this message
+quit                  q    Leave the debugger
+An empty line repeats the last command";
+
+// Why a run stopped
+enum Stop {
+    Done,
+    Breakpoint(u8),
+    PuppBreakpoint(u8),
+    Branch(u8, u8),
+    Watch,
+    Halted,
+    ClockStopped,
+}
+
+pub struct Debugger {
+    // Stop before the microword at these addresses runs
+    pub breakpoints: BTreeSet<u8>,
+
+    // Stop when the PUPP holds these addresses
+    pub pupp_breakpoints: BTreeSet<u8>,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: BTreeSet::new(),
+            pupp_breakpoints: BTreeSet::new(),
+        }
+    }
+
+    // Read commands from stdin until quit or end of input
+    pub fn run(&mut self, machine: &mut Machine) {
+        println!("Microcode debugger, type help for commands");
+        print_status(&machine.state);
+
+        let stdin = io::stdin();
+        let mut last = String::new();
+        loop {
+            print!("> ");
+            let _ = io::stdout().flush();
+
+            let mut line = String::new();
+            match stdin.lock().read_line(&mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {},
+            }
+            let line = match line.trim() {
+                "" => last.clone(),
+                line => line.to_string(),
+            };
+            last = line.clone();
+
+            match self.command(&line, machine) {
+                Ok(true) => {},
+                Ok(false) => break,
+                Err(e) => println!("{}", e),
+            }
+        }
+    }
+
+    // Run one command, returns false when the debugger should exit
+    pub fn command(&mut self, line: &str, machine: &mut Machine) -> Result<bool, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((command, arguments)) = words.split_first() else {
+            return Ok(true);
+        };
+
+        match *command {
+            "step" | "s" => {
+                let count = optional_octal(arguments.first(), 1)?;
+                self.report(self.run_cycles(machine, count, |_| None), &machine.state, &machine.session);
+            },
+            "pulse" | "p" => {
+                let count = optional_octal(arguments.first(), 1)?;
+                for _ in 0..count {
+                    if !run_pulse(machine) {
+                        break;
+                    }
+                }
+                print_status(&machine.state);
+            },
+            "istep" | "si" => {
+                let count = optional_octal(arguments.first(), 1)?;
+                for _ in 0..count {
+                    let step = match machine.step_instruction() {
+                        Ok(step) => step,
+                        Err(_) => break,
+                    };
+                    println!("{}", step);
+                    let output = machine.take_output();
+                    if !output.is_empty() {
+                        let _ = io::stdout().write_all(&output);
+                    }
+                    if step.end != StepEnd::Fetched {
+                        break;
+                    }
+                }
+                print_status(&machine.state);
+            },
+            "continue" | "c" => {
+                let count = optional_octal(arguments.first(), u64::MAX)?;
+                self.report(self.run_cycles(machine, count, |_| None), &machine.state, &machine.session);
+            },
+            "back" | "sb" => {
+                let count = optional_octal(arguments.first(), 1)?;
+                for _ in 0..count {
+                    if !machine.step_back() {
+                        println!("No more history");
+                        break;
+                    }
+                }
+                print_status(&machine.state);
+            },
+            "run-back" | "rb" => {
+                let field = arguments.first().map(|name| name.to_uppercase());
+                let mut last = match &field {
+                    Some(name) => Some(machine.state.field(name).ok_or(format!("unknown field '{}'", name))?),
+                    None => None,
+                };
+                loop {
+                    if !machine.step_back() {
+                        println!("No more history");
+                        break;
+                    }
+                    let state = &machine.state;
+                    if let (Some(name), Some(value)) = (&field, last) {
+                        let before = state.field(name).unwrap_or(0);
+                        if before != value {
+                            println!("{} changed from {:o} to {:o} by the microword at UPP {:03o}", name, before, value, state.UPP);
+                            break;
+                        }
+                        last = Some(before);
+                    }
+                    if self.breakpoints.contains(&state.UPP) {
+                        println!("Breakpoint at UPP {:03o}", state.UPP);
+                        break;
+                    }
+                    if self.pupp_breakpoints.contains(&state.PUPP) {
+                        println!("Breakpoint at PUPP {:03o}", state.PUPP);
+                        break;
+                    }
+                }
+                print_status(&machine.state);
+            },
+            "until-branch" | "ub" => {
+                let from = octal(arguments.first().ok_or("until-branch needs a UPP")?)? as u8;
+                let target = match arguments.get(1) {
+                    Some(target) => Some(octal(target)? as u8),
+                    None => None,
+                };
+                let words = machine.state.ROM.words();
+                let mut before = machine.state.PUPP;
+                let stop = self.run_cycles(machine, u64::MAX, |state| {
+                    // BUBC from a BUT reaches the UPP a machine cycle late, ORed into the UPF of the
+                    // microword after it. A branch was taken if that changed the UPP from the plain UPF
+                    let taken = before == from && state.UPP != words[state.PUPP as usize].UPF;
+                    before = state.PUPP;
+                    match (taken, target) {
+                        (true, None) => Some(Stop::Branch(from, state.UPP)),
+                        (true, Some(target)) if target == state.UPP => Some(Stop::Branch(from, state.UPP)),
+                        _ => None,
+                    }
+                });
+                self.report(stop, &machine.state, &machine.session);
+            },
+            "break" | "b" => {
+                self.breakpoints.insert(octal(arguments.first().ok_or("break needs a UPP")?)? as u8);
+            },
+            "pbreak" | "pb" => {
+                self.pupp_breakpoints.insert(octal(arguments.first().ok_or("pbreak needs a PUPP")?)? as u8);
+            },
+            "delete" | "del" => match arguments.first() {
+                Some(&"all") => {
+                    self.breakpoints.clear();
+                    self.pupp_breakpoints.clear();
+                },
+                Some(upp) => {
+                    let upp = octal(upp)? as u8;
+                    self.breakpoints.remove(&upp);
+                    self.pupp_breakpoints.remove(&upp);
+                },
+                None => return Err(String::from("delete needs a UPP or all")),
+            },
+            "breaks" | "bl" => {
+                for upp in self.breakpoints.iter() {
+                    println!("UPP  {:03o}", upp);
+                }
+                for pupp in self.pupp_breakpoints.iter() {
+                    println!("PUPP {:03o}", pupp);
+                }
+            },
+            "watch" | "w" => {
+                let spec = line.split_once(char::is_whitespace).map(|(_, spec)| spec).unwrap_or("");
+                machine.session.watchpoints.add(Watchpoint::parse(spec)?);
+            },
+            "watches" | "wl" => {
+                for (index, watchpoint) in machine.session.watchpoints.list.iter().enumerate() {
+                    println!("{:<3o}{}", index, watchpoint.describe());
+                }
+            },
+            "unwatch" | "uw" => match arguments.first() {
+                Some(&"all") => machine.session.watchpoints = Watchpoints::new(),
+                Some(index) => {
+                    let index = octal(index)? as usize;
+                    machine.session.watchpoints.remove(index).ok_or(format!("no watchpoint {:o}", index))?;
+                },
+                None => return Err(String::from("unwatch needs a number or all")),
+            },
+            "print" | "x" => match arguments.first() {
+                Some(name) => {
+                    let value = machine.state.field(name).ok_or(format!("unknown field '{}'", name))?;
+                    println!("{} = {:o}", name.to_uppercase(), value);
+                },
+                None => print_state(&machine.state),
+            },
+            "set" => {
+                let (Some(name), Some(value)) = (arguments.first(), arguments.get(1)) else {
+                    return Err(String::from("set needs a field and a value"));
+                };
+                machine.state.set_field(name, octal(value)?)?;
+            },
+            "examine" | "e" => {
+                let address = octal(arguments.first().ok_or("examine needs an address")?)? as u32 & !1;
+                let count = optional_octal(arguments.get(1), 1)? as u32;
+                for word in 0..count {
+                    let address = address + word * 2;
+                    // Reading a device register can change the device, so only memory is shown
+                    match machine.state.UNIBUS.peek(address) {
+                        Some(value) => println!("{:06o}: {:06o}", address, value),
+                        None => {
+                            println!("{:06o}: not memory", address);
+                            break;
+                        },
+                    }
+                }
+            },
+            "examine/i" | "e/i" => {
+                let mut address = octal(arguments.first().ok_or("examine/i needs an address")?)? as u16 & !1;
+                let count = optional_octal(arguments.get(1), 1)?;
+                for _ in 0..count {
+                    let (text, length) = disassembler::disassemble_memory(&machine.state.UNIBUS, address);
+                    let words: Vec<String> = (0..length)
+                        .map(|word| {
+                            let word = machine.state.UNIBUS.peek(address.wrapping_add(word * 2) as u32);
+                            format!("{:06o}", word.unwrap_or(0))
+                        })
+                        .collect();
+                    println!("{:06o}: {:<22}{}", address, words.join(" "), text);
+                    address = address.wrapping_add(length * 2);
+                }
+            },
+            "deposit" | "dep" => {
+                let address = octal(arguments.first().ok_or("deposit needs an address")?)? as u32 & !1;
+                if arguments.len() < 2 {
+                    return Err(String::from("deposit needs a value"));
+                }
+                for (word, value) in arguments[1..].iter().enumerate() {
+                    let address = address + word as u32 * 2;
+                    machine.state.UNIBUS.write(address, octal(value)? as u16, false)
+                        .map_err(|_| format!("{:06o}: bus timeout", address))?;
+                }
+            },
+            "switch" => {
+                let value = octal(arguments.first().ok_or("switch needs 0 or 1")?)? != 0;
+                machine.input(Input::Switch(value))?;
+            },
+            "type" => {
+                let text = line.split_once(char::is_whitespace).map(|(_, text)| text).unwrap_or("");
+                for character in text.replace("\\r", "\r").bytes() {
+                    machine.input(Input::Console(character))?;
+                }
+            },
+            "save" => {
+                let path = arguments.first().ok_or("save needs a file")?;
+                machine.save_snapshot(Path::new(path)).map_err(|e| format!("Failed to save snapshot: {}", e))?;
+                println!("Saved snapshot at cycle {} to {}", machine.state.CYCLE, path);
+            },
+            "restore" => {
+                let path = arguments.first().ok_or("restore needs a file")?;
+                machine.restore_snapshot(Path::new(path)).map_err(|e| format!("Failed to restore snapshot: {}", e))?;
+                print_status(&machine.state);
+            },
+            "help" | "h" => println!("{}", HELP),
+            "quit" | "q" => return Ok(false),
+            _ => return Err(format!("unknown command '{}', type help for commands", command)),
+        }
+        Ok(true)
+    }
+
+    // Run up to count machine cycles, stopping early at a breakpoint, a halt or when stop says so
+    fn run_cycles(&self, machine: &mut Machine, count: u64, mut stop: impl FnMut(&MachineState) -> Option<Stop>) -> Stop {
+        for _ in 0..count {
+            // Finish a machine cycle left part way through by pulse stepping
+            if !run_pulse(machine) {
+                return Stop::Halted;
+            }
+            while machine.state.PULSE != 0 {
+                if !run_pulse(machine) {
+                    return Stop::Halted;
+                }
+            }
+
+            let state = &machine.state;
+            if machine.clock_stopped() {
+                return Stop::ClockStopped;
+            }
+            if let Some(reason) = stop(state) {
+                return reason;
+            }
+            if !machine.session.watch_hits.is_empty() {
+                return Stop::Watch;
+            }
+            if self.breakpoints.contains(&state.UPP) {
+                return Stop::Breakpoint(state.UPP);
+            }
+            if self.pupp_breakpoints.contains(&state.PUPP) {
+                return Stop::PuppBreakpoint(state.PUPP);
+            }
+        }
+        Stop::Done
+    }
+
+    fn report(&self, stop: Stop, state: &MachineState, session: &Session) {
+        match stop {
+            Stop::Done | Stop::Halted => {},
+            Stop::Breakpoint(upp) => println!("Breakpoint at UPP {:03o}", upp),
+            Stop::PuppBreakpoint(pupp) => println!("Breakpoint at PUPP {:03o}", pupp),
+            Stop::Branch(from, to) => println!("Branch taken from {:03o} to {:03o}", from, to),
+            Stop::ClockStopped => println!("Clock stopped at UPP {:03o}, waiting for SWITCH", state.UPP),
+            Stop::Watch => {
+                for hit in session.watch_hits.iter() {
+                    println!("{}", hit);
+                }
+            },
+        }
+        print_status(state);
+    }
+}
+
+impl Default for Debugger {
+    fn default() -> Debugger {
+        Debugger::new()
+    }
+}
+
+// Run one clock pulse, false if the machine is halted
+fn run_pulse(machine: &mut Machine) -> bool {
+    if machine.step_pulse().is_err() {
+        return false;
+    }
+
+    let output = machine.take_output();
+    if !output.is_empty() {
+        let _ = io::stdout().write_all(&output);
+    }
+    true
+}
+
+fn print_status(state: &MachineState) {
+    println!(
+        "cycle {}  pulse {}  time {}ns  UPP {:03o}  PUPP {:03o}  BUBC {:02o}  CLK {:?}",
+        state.CYCLE, state.PULSE, state.TIME_NS, state.UPP, state.PUPP, state.BUBC, state.CLK_MODE.mode
+    );
+    if let Some(error) = &state.HALTED {
+        println!("Machine halted: {}", error);
+    }
+}
+
+fn print_state(state: &MachineState) {
+    for name in FIELD_NAMES.iter() {
+        if let Some(value) = state.field(name) {
+            println!("{:<13}{:o}", name, value);
+        }
+    }
+    for row in 0..4 {
+        let registers: Vec<String> = (0..4)
+            .map(|column| {
+                let register = row * 4 + column;
+                format!("R{:<3o}{:06o}", register, state.REGISTERS[register])
+            })
+            .collect();
+        println!("{}", registers.join("  "));
+    }
+}
+
+fn octal(text: &str) -> Result<u64, String> {
+    u64::from_str_radix(text, 8).map_err(|_| format!("'{}' is not an octal number", text))
+}
+
+fn optional_octal(text: Option<&&str>, default: u64) -> Result<u64, String> {
+    match text {
+        Some(text) => octal(text),
+        None => Ok(default),
+    }
+}
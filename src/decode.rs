@@ -0,0 +1,187 @@
+#![allow(non_snake_case)]
+
+use crate::bconstant;
+use crate::clock::{self, ClockState};
+use crate::error::EmulatorError;
+use crate::microbranch_control;
+use crate::microrom::MicroRom;
+
+// Pre-decoded microROM, for long runs
+// Everything about a microword that doesn't depend on the registers is worked out once: the
+// B constant for either SINCLK, the BMUX when it passes the constant straight through, the
+// BUT as masks on SWITCH and D = 0, the clock for the cycle after it and which of the
+// registers, bus and IR it clocks. processor::run_decoded_cycle runs a whole machine cycle
+// from that. Whether a microword can be latched next is known up front too, so a cycle that
+// gets that far can't stop part way through and needs no copy of the processor to take back.
+
+#[derive(Clone, Copy)]
+pub struct DecodedWord {
+    // SBC, the B constant indexed by SINCLK
+    pub bconstant: [Result<u16, EmulatorError>; 2],
+
+    // SBM, the BMUX indexed by SINCLK when both bytes are the B constant, None when it uses B
+    pub bmux: [Option<u16>; 2],
+
+    pub SBM: u8,
+    pub ALU: u8,
+    pub SDM: u8,
+    pub RIF: u8,
+
+    // WR, CD, CB or CBA set, the cycle clocks something in the data path
+    pub clocks_registers: bool,
+
+    // BGBUS, the cycle starts a bus cycle
+    pub bus: bool,
+
+    // CLKIR
+    pub CIR: bool,
+
+    // What latching the microword as the next one needs, None if that would stop the machine
+    pub next: Option<NextWord>,
+}
+
+#[derive(Clone, Copy)]
+pub struct NextWord {
+    // UBF, the BUBC lines set by SWITCH and by D = 0
+    pub switch_mask: u8,
+    pub d_zero_mask: u8,
+
+    // CLK, the clock mode for the cycle after it without and with a bus cycle in the machine cycle
+    pub clock: [&'static ClockState; 2],
+}
+
+pub struct DecodedRom([DecodedWord; 256]);
+
+impl DecodedRom {
+    pub fn new(ROM: &MicroRom) -> DecodedRom {
+        let mut UPP = 0;
+        DecodedRom(ROM.words().each_ref().map(|word| {
+            let bconstant = [false, true].map(|SINCLK| bconstant::bconstant(word.SBC, SINCLK, UPP));
+            let bmux = bconstant.map(|constant| match word.SBM {
+                0o17 => constant.ok(),
+                _ => None,
+            });
+            let next = match (word.implemented, microbranch_control::but_masks(word.UBF), clock::reclock(word.CLK, false, UPP)) {
+                (true, Some((switch_mask, d_zero_mask)), Ok(clock)) => Some(NextWord {
+                    switch_mask,
+                    d_zero_mask,
+                    clock: [clock, clock::reclock(word.CLK, true, UPP).unwrap_or(clock)],
+                }),
+                _ => None,
+            };
+            let decoded = DecodedWord {
+                bconstant,
+                bmux,
+                SBM: word.SBM,
+                ALU: word.ALU,
+                SDM: word.SDM,
+                RIF: word.RIF,
+                clocks_registers: word.WR != 0 || word.CD == 1 || word.CB == 1 || word.CBA == 1,
+                bus: word.BUS & 0b001 != 0,
+                CIR: word.CIR == 1,
+                next,
+            };
+            UPP = UPP.wrapping_add(1);
+            decoded
+        }))
+    }
+
+    // The microword at UPP, whether or not it is implemented
+    pub fn word(&self, UPP: u8) -> &DecodedWord {
+        &self.0[UPP as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coverage::Coverage;
+    use crate::error::EmulatorError;
+    use crate::machine::Machine;
+    use crate::microrom::{MicroWord, MICROROM};
+    use crate::replay::Input;
+    use crate::rom_image::bits_to_microword;
+    use crate::snapshot;
+
+    // Run to a halt a cycle at a time, direct and pre-decoded, and check every cycle ends the same
+    fn compare(mut direct: Machine, mut decoded: Machine) -> EmulatorError {
+        decoded.predecode();
+        loop {
+            let (a, b) = (direct.step_cycle(), decoded.run_headless(1));
+            assert_eq!(a.map(|_| 1), b, "cycle {}", direct.state.CYCLE);
+            assert_eq!(snapshot::to_bytes(&direct.state), snapshot::to_bytes(&decoded.state), "cycle {}", direct.state.CYCLE);
+            assert_eq!(direct.take_output(), decoded.take_output());
+            if let Err(error) = a {
+                return error;
+            }
+        }
+    }
+
+    fn console_loop() -> Machine<'static> {
+        let mut machine = Machine::new(&MICROROM, 0, 0).unwrap();
+        machine.state.SINCLK = false;
+        machine.session.coverage = Some(Coverage::new());
+        machine.input(Input::Switch(true)).unwrap();
+        machine
+    }
+
+    #[test]
+    fn console_loop_matches_direct() {
+        let (direct, decoded) = (console_loop(), console_loop());
+        let error = compare(direct, decoded);
+        assert_eq!(error, EmulatorError::UnimplementedMicroword { UPP: 0o45, PUPP: 0o47 });
+    }
+
+    #[test]
+    fn coverage_matches_direct() {
+        let mut direct = console_loop();
+        let mut decoded = console_loop();
+        decoded.predecode();
+        let _ = direct.run_until(u64::MAX, |_| false);
+        let _ = decoded.run_headless(u64::MAX);
+        let (a, b) = (direct.session.coverage.unwrap(), decoded.session.coverage.unwrap());
+        assert_eq!((a.words, a.branches), (b.words, b.branches));
+    }
+
+    // D and BA count down from R0 with a DATO of D in CL3 on each count, until BA goes past
+    // memory into the I/O page and the bus cycle times out
+    #[test]
+    fn bus_timeout_matches_direct() {
+        let mut words: [MicroWord; 256] = std::array::from_fn(|_| bits_to_microword(0, false));
+        for (UPP, word) in words.iter_mut().enumerate().take(2) {
+            word.implemented = true;
+            word.UPF = (UPP as u8 + 1) % 2;
+        }
+        // D and BA <- R0 - 1, in CL1
+        words[0].ALU = 0b01111;
+        words[0].CD = 1;
+        words[0].CBA = 1;
+        words[0].CLK = 2;
+        // R0 <- D and DATO, in CL3
+        words[1].SDM = 0b10;
+        words[1].WR = 0b11;
+        words[1].BUS = 0b101;
+        words[1].CLK = 5;
+        let ROM = MicroRom::new(words);
+        let machine = || {
+            let mut machine = Machine::new(&ROM, 0, 0o100).unwrap();
+            machine.state.REGISTERS[0] = 0o12;
+            machine
+        };
+        let (direct, decoded) = (machine(), machine());
+        assert!(matches!(compare(direct, decoded), EmulatorError::BusTimeout { UPP: 1, .. }));
+    }
+
+    #[test]
+    fn next_word() {
+        let decoded = DecodedRom::new(&MICROROM);
+        // 026 BUT(SWITCH), 044 BUT(D=0)
+        let masks = |UPP| decoded.word(UPP).next.map(|next| (next.switch_mask, next.d_zero_mask));
+        assert_eq!(masks(0o026), Some((1, 0)));
+        assert_eq!(masks(0o044), Some((0, 1)));
+        assert_eq!(masks(0o046), Some((0, 0)));
+        assert!(masks(0o045).is_none());
+        // SBC 014, the SINCLK constant
+        assert_eq!(decoded.word(0o027).bconstant[0], Ok(0o20));
+    }
+}
@@ -0,0 +1,290 @@
+#![allow(non_snake_case)]
+
+use std::fmt;
+
+use crate::disassembler;
+use crate::machine::{Machine, INSTRUCTION_CYCLE_LIMIT};
+use crate::microrom::MicroRom;
+use crate::reference::{self, Outcome, Reference};
+use crate::trace::{self, TraceRecord, TRACE_ALL};
+
+// Differential testing, the microcode against the instruction level reference model
+// Both start from the same registers and memory and run a random instruction sequence one
+// instruction at a time, comparing R0 to R6, the PC, the PSW and memory after each. The first
+// difference is reported with the machine cycles the microcode ran for that instruction.
+//
+// The microcode machine is started as the console START would (Machine::start), and runs to
+// its first instruction fetch before anything is compared. After that each instruction step
+// ends with the next instruction fetched, so its R7 has already moved past it and the PC
+// compared is the address the IR was fetched from.
+
+// Memory layout of a case, addresses in bytes
+pub const MEMORY_WORDS: usize = 0o4000 / 2;
+pub const ORIGIN: u16 = 0o1000;
+pub const DATA: u16 = 0o2000;
+pub const DATA_END: u16 = 0o3000;
+// Every trap vector points at a HALT here, so a trap ends the case in both
+const TRAP_HALT: u16 = 0o500;
+
+// Registers the random instructions use, SP and PC are left alone
+const OPERAND_REGISTERS: u16 = 6;
+
+// A starting state and the program to run from it
+#[derive(Clone)]
+pub struct Case {
+    pub seed: u64,
+    pub REGISTERS: [u16; 8],
+    pub PSW: u16,
+    pub memory: Vec<u16>,
+
+    // Instructions in the program, it ends with a HALT after them
+    pub instructions: usize,
+}
+
+// Where the two first disagree
+pub struct Divergence {
+    pub seed: u64,
+
+    // Instructions that matched before this one
+    pub instruction: usize,
+    pub PC: u16,
+    pub text: String,
+
+    // What differed, or why the microcode stopped
+    pub differences: Vec<String>,
+
+    // The text trace of every machine cycle the microcode ran for the instruction
+    pub trace: Vec<String>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "seed {:#x}: instruction {} at {:06o} {}", self.seed, self.instruction, self.PC, self.text)?;
+        for difference in self.differences.iter() {
+            write!(f, "\n  {}", difference)?;
+        }
+        write!(f, "\nmicrocycles:")?;
+        for line in self.trace.iter() {
+            write!(f, "\n{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+// xorshift64, so the same seed gives the same case everywhere
+pub struct Random(u64);
+
+impl Random {
+    pub fn new(seed: u64) -> Random {
+        Random(seed.max(1))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    pub fn below(&mut self, limit: u16) -> u16 {
+        (self.next_u64() % limit as u64) as u16
+    }
+
+    pub fn word(&mut self) -> u16 {
+        self.next_u64() as u16
+    }
+
+    // An even address in the data area, away from the ends so a few increments and
+    // decrements stay inside it
+    fn pointer(&mut self) -> u16 {
+        DATA + 0o100 + self.below((DATA_END - DATA - 0o200) / 2) * 2
+    }
+}
+
+// A random program of instructions that stay in line: the basic set without jumps, branches
+// and SOB by zero, EIS, with operands through R0 to R5 pointing into the data area
+pub fn random_case(seed: u64, instructions: usize) -> Case {
+    let mut random = Random::new(seed);
+    let mut memory = vec![0; MEMORY_WORDS];
+    for vector in [reference::BUS_ERROR, reference::RESERVED_INSTRUCTION, reference::BPT_VECTOR,
+                   reference::IOT_VECTOR, reference::EMT_VECTOR, reference::TRAP_VECTOR] {
+        memory[vector as usize / 2] = TRAP_HALT;
+    }
+    // Half pointers for the deferred modes, half anything
+    for address in (DATA..DATA_END).step_by(2) {
+        memory[address as usize / 2] = if random.below(2) == 0 { random.pointer() } else { random.word() };
+    }
+
+    let mut program = Vec::new();
+    for _ in 0..instructions {
+        program.extend(random_instruction(&mut random));
+    }
+    program.push(0);
+    for (index, word) in program.iter().enumerate() {
+        memory[ORIGIN as usize / 2 + index] = *word;
+    }
+
+    let mut REGISTERS = [0; 8];
+    for register in REGISTERS.iter_mut().take(OPERAND_REGISTERS as usize) {
+        *register = random.pointer();
+    }
+    REGISTERS[6] = ORIGIN;
+    REGISTERS[7] = ORIGIN;
+    Case { seed, REGISTERS, PSW: random.below(0o20), memory, instructions }
+}
+
+// One instruction and the words after it
+fn random_instruction(random: &mut Random) -> Vec<u16> {
+    let mut extra = Vec::new();
+    let IR = match random.below(8) {
+        // MOV to SUB, byte or not
+        0..=2 => {
+            let opcode = (1 + random.below(6)) << 12 | random.below(2) << 15;
+            let source = operand(random, &mut extra, true);
+            let destination = operand(random, &mut extra, false);
+            opcode | source << 6 | destination
+        },
+        // CLR to ASL, byte or not
+        3 | 4 => {
+            let opcode = (0o050 + random.below(0o14)) << 6 | random.below(2) << 15;
+            opcode | operand(random, &mut extra, false)
+        },
+        // SWAB and SXT
+        5 => {
+            let opcode = if random.below(2) == 0 { 0o000300 } else { 0o006700 };
+            opcode | operand(random, &mut extra, false)
+        },
+        // Condition codes, or a branch by zero
+        6 => match random.below(2) {
+            0 => 0o000240 | random.below(0o40),
+            _ => [0o000400, 0o001000, 0o001400, 0o002000, 0o002400, 0o003000, 0o003400, 0o100000,
+                  0o100400, 0o101000, 0o101400, 0o102000, 0o102400, 0o103000, 0o103400][random.below(15) as usize],
+        },
+        // MUL, DIV into an even register, ASH, ASHC, XOR and SOB by zero
+        _ => {
+            let register = random.below(OPERAND_REGISTERS);
+            match random.below(6) {
+                5 => 0o077000 | register << 6,
+                4 => 0o074000 | register << 6 | operand(random, &mut extra, false),
+                opcode => {
+                    let register = if opcode == 1 { register & !1 } else { register };
+                    0o070000 | opcode << 9 | register << 6 | operand(random, &mut extra, true)
+                },
+            }
+        },
+    };
+    let mut words = vec![IR];
+    words.extend(extra);
+    words
+}
+
+// A six bit operand field through R0 to R5, or immediate for a source, any index word is added to extra
+fn operand(random: &mut Random, extra: &mut Vec<u16>, source: bool) -> u16 {
+    if source && random.below(6) == 0 {
+        extra.push(random.word());
+        return 0o27;
+    }
+    let mode = random.below(7);
+    let register = random.below(OPERAND_REGISTERS);
+    if mode == 6 {
+        // Small even displacements, the registers point into the middle of the data area
+        extra.push((random.below(0o40) * 2).wrapping_sub(0o40));
+    }
+    mode << 3 | register
+}
+
+// Run a case on both, Ok with the number of instructions compared
+pub fn run(ROM: &MicroRom, case: &Case) -> Result<usize, Divergence> {
+    let mut model = Reference::new(MEMORY_WORDS);
+    model.REGISTERS = case.REGISTERS;
+    model.PSW = case.PSW;
+    model.memory.copy_from_slice(&case.memory);
+
+    let divergence = |instruction: usize, PC: u16, text: String, differences: Vec<String>, trace: Vec<String>| {
+        Divergence { seed: case.seed, instruction, PC, text, differences, trace }
+    };
+
+    let mut machine = match Machine::new(ROM, 0, MEMORY_WORDS) {
+        Ok(machine) => machine,
+        Err(error) => return Err(divergence(0, ORIGIN, String::new(), vec![format!("microcode: {}", error)], Vec::new())),
+    };
+    machine.state.UNIBUS.memory.copy_from_slice(&case.memory);
+    machine.state.REGISTERS[..8].copy_from_slice(&case.REGISTERS);
+    machine.state.PSW = case.PSW;
+    let _ = machine.start(case.REGISTERS[7]);
+
+    // Up to the first fetch
+    let mut trace = Vec::new();
+    if let Err(error) = run_instruction(&mut machine, &mut trace) {
+        let (text, _) = disassemble(&model, ORIGIN);
+        return Err(divergence(0, ORIGIN, text, vec![format!("microcode stopped before the first fetch: {}", error)], trace));
+    }
+
+    for instruction in 0..=case.instructions {
+        let PC = model.REGISTERS[7];
+        let (text, _) = disassemble(&model, PC);
+        let outcome = model.step();
+        let result = run_instruction(&mut machine, &mut trace);
+        let mut differences = Vec::new();
+        if let Err(error) = result {
+            differences.push(format!("microcode stopped: {}", error));
+        }
+        // Nothing is fetched after a halt, so no PC to compare
+        let fetched = matches!(outcome, Outcome::Ran | Outcome::Trap(_));
+        differences.extend(compare(&model, &machine, fetched));
+        if !differences.is_empty() {
+            if let Outcome::Trap(vector) = outcome {
+                differences.insert(0, format!("reference trapped to {:03o}", vector));
+            }
+            return Err(divergence(instruction, PC, text, differences, trace));
+        }
+        if !fetched {
+            return Ok(instruction + 1);
+        }
+    }
+    Ok(case.instructions + 1)
+}
+
+// Run machine cycles up to and including the next that clocks the IR, like step_instruction,
+// leaving the cycles traced. Gives up after INSTRUCTION_CYCLE_LIMIT machine cycles without a fetch
+fn run_instruction(machine: &mut Machine, trace: &mut Vec<String>) -> Result<(), String> {
+    trace.clear();
+    for _ in 0..INSTRUCTION_CYCLE_LIMIT {
+        let fetch = machine.state.U_WORD.CIR != 0;
+        let mut record = TraceRecord::start(&machine.state);
+        let result = machine.step_cycle();
+        record.finish(&machine.state);
+        trace.push(trace::text_line(&record, TRACE_ALL));
+        result.map_err(|error| error.to_string())?;
+        if fetch || machine.clock_stopped() {
+            return Ok(());
+        }
+    }
+    Err(format!("no instruction fetch in {} machine cycles", INSTRUCTION_CYCLE_LIMIT))
+}
+
+fn disassemble(model: &Reference, address: u16) -> (String, u16) {
+    let word = |offset: u16| model.read(address.wrapping_add(offset), false).unwrap_or(0);
+    disassembler::disassemble(address, [word(0), word(2), word(4)])
+}
+
+// Every difference between the two, as "name reference microcode"
+fn compare(model: &Reference, machine: &Machine, fetched: bool) -> Vec<String> {
+    let mut differences = Vec::new();
+    let mut differ = |name: String, expected: u16, actual: u16| {
+        if expected != actual {
+            differences.push(format!("{:<8}{:06o} microcode {:06o}", name, expected, actual));
+        }
+    };
+    for register in 0..7 {
+        differ(format!("R{}", register), model.REGISTERS[register], machine.state.REGISTERS[register]);
+    }
+    if fetched {
+        differ(String::from("PC"), model.REGISTERS[7], machine.state.IR_PC);
+    }
+    differ(String::from("PSW"), model.PSW, machine.state.PSW);
+    for (index, (expected, actual)) in model.memory.iter().zip(machine.state.UNIBUS.memory.iter()).enumerate() {
+        differ(format!("{:06o}", index * 2), *expected, *actual);
+    }
+    differences
+}
@@ -0,0 +1,261 @@
+#![allow(non_snake_case)]
+
+use crate::unibus::Unibus;
+
+// PDP-11/40 instruction set disassembler, MACRO-11 syntax
+// Covers the basic instruction set, EIS (MUL, DIV, ASH, ASHC, XOR, SOB), FIS (FADD, FSUB, FMUL, FDIV)
+// and MFPI/MTPI for the KT11-D. Anything the 11/40 does not implement prints as .WORD
+// Addresses print as six octal digits, immediates and index words as plain octal
+
+const REGISTER_NAMES: [&str; 8] = ["R0", "R1", "R2", "R3", "R4", "R5", "SP", "PC"];
+
+// Indexed by opcode bits 15:12
+const DOUBLE_OPERAND: [Option<&str>; 16] = [
+    None, Some("MOV"), Some("CMP"), Some("BIT"), Some("BIC"), Some("BIS"), Some("ADD"), None,
+    None, Some("MOVB"), Some("CMPB"), Some("BITB"), Some("BICB"), Some("BISB"), Some("SUB"), None,
+];
+
+// Indexed by bit 15 and bits 10:8
+const BRANCHES: [&str; 16] = [
+    "", "BR", "BNE", "BEQ", "BGE", "BLT", "BGT", "BLE",
+    "BPL", "BMI", "BHI", "BLOS", "BVC", "BVS", "BCC", "BCS",
+];
+
+// Opcode bits 11:6 from 050 to 063, B appended for the byte forms
+const SINGLE_OPERAND: [&str; 12] = [
+    "CLR", "COM", "INC", "DEC", "NEG", "ADC", "SBC", "TST", "ROR", "ROL", "ASR", "ASL",
+];
+
+const OPERATES: [&str; 7] = ["HALT", "WAIT", "RTI", "BPT", "IOT", "RESET", "RTT"];
+
+// Opcode bits 11:9 from 0 to 3
+const EIS: [&str; 4] = ["MUL", "DIV", "ASH", "ASHC"];
+
+const FIS: [&str; 4] = ["FADD", "FSUB", "FMUL", "FDIV"];
+
+// The words after the instruction, used up as the operands need them
+struct Operands {
+    address: u16,
+    words: [u16; 3],
+    length: u16,
+}
+
+impl Operands {
+    // The next word, and the address just past it that PC relative operands are taken from
+    fn next(&mut self) -> (u16, u16) {
+        let word = self.words[self.length as usize];
+        self.length += 1;
+        (word, self.address.wrapping_add(self.length * 2))
+    }
+
+    // A six bit mode and register field
+    fn operand(&mut self, field: u16) -> String {
+        let mode = (field >> 3) & 0b111;
+        let register = (field & 0b111) as usize;
+        let name = REGISTER_NAMES[register];
+        match (mode, register) {
+            (2, 7) => format!("#{:o}", self.next().0),
+            (3, 7) => format!("@#{:06o}", self.next().0),
+            (6, 7) => {
+                let (offset, pc) = self.next();
+                format!("{:06o}", pc.wrapping_add(offset))
+            },
+            (7, 7) => {
+                let (offset, pc) = self.next();
+                format!("@{:06o}", pc.wrapping_add(offset))
+            },
+            (0, _) => name.to_string(),
+            (1, _) => format!("({})", name),
+            (2, _) => format!("({})+", name),
+            (3, _) => format!("@({})+", name),
+            (4, _) => format!("-({})", name),
+            (5, _) => format!("@-({})", name),
+            (6, _) => format!("{:o}({})", self.next().0, name),
+            _ => format!("@{:o}({})", self.next().0, name),
+        }
+    }
+}
+
+// Disassemble the instruction at address, words holds it and the two words that follow
+// Returns the text and the number of words the instruction takes
+pub fn disassemble(address: u16, words: [u16; 3]) -> (String, u16) {
+    let mut operands = Operands { address, words, length: 1 };
+    let text = decode(words[0], &mut operands);
+    (text, operands.length)
+}
+
+// Disassemble from memory, without the side effects a bus read has on device registers
+// Words outside memory read as 0
+pub fn disassemble_memory(unibus: &Unibus, address: u16) -> (String, u16) {
+    let IR = unibus.peek(address as u32).unwrap_or(0);
+    disassemble_instruction(unibus, IR, address)
+}
+
+// As disassemble_memory, for an instruction already fetched from address into the IR
+pub fn disassemble_instruction(unibus: &Unibus, IR: u16, address: u16) -> (String, u16) {
+    let peek = |offset: u16| unibus.peek(address.wrapping_add(offset) as u32).unwrap_or(0);
+    disassemble(address, [IR, peek(2), peek(4)])
+}
+
+fn decode(IR: u16, operands: &mut Operands) -> String {
+    let register = REGISTER_NAMES[((IR >> 6) & 0b111) as usize];
+    let source = (IR >> 6) & 0o77;
+    let destination = IR & 0o77;
+
+    if let Some(name) = DOUBLE_OPERAND[(IR >> 12) as usize] {
+        let source = operands.operand(source);
+        let destination = operands.operand(destination);
+        return format!("{} {},{}", name, source, destination);
+    }
+
+    match IR {
+        0o000000..=0o000006 => OPERATES[IR as usize].to_string(),
+        0o000100..=0o000177 => format!("JMP {}", operands.operand(destination)),
+        0o000200..=0o000207 => format!("RTS {}", REGISTER_NAMES[(IR & 0b111) as usize]),
+        0o000240..=0o000277 => condition_codes(IR),
+        0o000300..=0o000377 => format!("SWAB {}", operands.operand(destination)),
+        0o000400..=0o003777 | 0o100000..=0o103777 => {
+            let index = ((IR >> 8) & 0b111) as usize | ((IR >> 12) & 0b1000) as usize;
+            let offset = (IR & 0o377) as u8 as i8 as i16;
+            let target = operands.address.wrapping_add(2).wrapping_add((offset * 2) as u16);
+            format!("{} {:06o}", BRANCHES[index], target)
+        },
+        0o004000..=0o004777 => format!("JSR {},{}", register, operands.operand(destination)),
+        0o005000..=0o006377 | 0o105000..=0o106377 => {
+            let name = SINGLE_OPERAND[(((IR >> 6) & 0o77) - 0o50) as usize];
+            let suffix = if IR & 0o100000 != 0 { "B" } else { "" };
+            format!("{}{} {}", name, suffix, operands.operand(destination))
+        },
+        0o006400..=0o006477 => format!("MARK {:o}", IR & 0o77),
+        0o006500..=0o006577 => format!("MFPI {}", operands.operand(destination)),
+        0o006600..=0o006677 => format!("MTPI {}", operands.operand(destination)),
+        0o006700..=0o006777 => format!("SXT {}", operands.operand(destination)),
+        0o070000..=0o073777 => {
+            let name = EIS[((IR >> 9) & 0b11) as usize];
+            format!("{} {},{}", name, operands.operand(destination), register)
+        },
+        0o074000..=0o074777 => format!("XOR {},{}", register, operands.operand(destination)),
+        0o075000..=0o075037 => {
+            let name = FIS[((IR >> 3) & 0b11) as usize];
+            format!("{} {}", name, REGISTER_NAMES[(IR & 0b111) as usize])
+        },
+        0o077000..=0o077777 => {
+            let target = operands.address.wrapping_add(2).wrapping_sub((IR & 0o77) * 2);
+            format!("SOB {},{:06o}", register, target)
+        },
+        0o104000..=0o104377 => format!("EMT {:o}", IR & 0o377),
+        0o104400..=0o104777 => format!("TRAP {:o}", IR & 0o377),
+        _ => format!(".WORD {:06o}", IR),
+    }
+}
+
+// 000240 to 000277, bit 4 sets rather than clears the flags in bits 3:0
+fn condition_codes(IR: u16) -> String {
+    let set = IR & 0o20 != 0;
+    match (IR & 0o17, set) {
+        (0, _) => String::from("NOP"),
+        (0o17, false) => String::from("CCC"),
+        (0o17, true) => String::from("SCC"),
+        (flags, _) => {
+            let prefix = if set { "SE" } else { "CL" };
+            let names: Vec<String> = [(0o10, "N"), (0o4, "Z"), (0o2, "V"), (0o1, "C")].iter()
+                .filter(|(bit, _)| flags & bit != 0)
+                .map(|(_, flag)| format!("{}{}", prefix, flag))
+                .collect();
+            names.join("!")
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // At 001000, the instruction, the words after it, the text and its length
+    fn check(table: &[(u16, [u16; 2], &str, u16)]) {
+        for &(IR, [first, second], text, length) in table {
+            assert_eq!(disassemble(0o1000, [IR, first, second]), (text.to_string(), length), "{:06o}", IR);
+        }
+    }
+
+    #[test]
+    fn addressing_modes() {
+        check(&[
+            (0o010100, [0, 0], "MOV R1,R0", 1),
+            (0o011100, [0, 0], "MOV (R1),R0", 1),
+            (0o012100, [0, 0], "MOV (R1)+,R0", 1),
+            (0o013100, [0, 0], "MOV @(R1)+,R0", 1),
+            (0o014100, [0, 0], "MOV -(R1),R0", 1),
+            (0o015100, [0, 0], "MOV @-(R1),R0", 1),
+            (0o016100, [0o20, 0], "MOV 20(R1),R0", 2),
+            (0o017100, [0o20, 0], "MOV @20(R1),R0", 2),
+            (0o016600, [0o177776, 0], "MOV 177776(SP),R0", 2),
+            // PC modes, relative to the address after the index word
+            (0o012700, [0o1234, 0], "MOV #1234,R0", 2),
+            (0o013700, [0o177566, 0], "MOV @#177566,R0", 2),
+            (0o016700, [0o100, 0], "MOV 001104,R0", 2),
+            (0o017700, [0o177774, 0], "MOV @001000,R0", 2),
+            (0o010007, [0, 0], "MOV R0,PC", 1),
+        ]);
+    }
+
+    #[test]
+    fn two_and_three_words() {
+        check(&[
+            (0o012737, [0o110, 0o177566], "MOV #110,@#177566", 3),
+            // The destination's index word is the second, its PC the address after it
+            (0o016167, [0o6, 0o100], "MOV 6(R1),001106", 3),
+            (0o016700, [0o100, 0o777], "MOV 001104,R0", 2),
+            (0o004737, [0o2000, 0], "JSR PC,@#002000", 2),
+            (0o105737, [0o177560, 0], "TSTB @#177560", 2),
+            (0o070127, [0o5, 0], "MUL #5,R1", 2),
+            (0o000137, [0o1000, 0], "JMP @#001000", 2),
+        ]);
+    }
+
+    #[test]
+    fn branches() {
+        check(&[
+            (0o000401, [0, 0], "BR 001004", 1),
+            (0o000400, [0, 0], "BR 001002", 1),
+            (0o000777, [0, 0], "BR 001000", 1),
+            (0o000600, [0, 0], "BR 000402", 1),
+            (0o001377, [0, 0], "BNE 001000", 1),
+            (0o100377, [0, 0], "BPL 001000", 1),
+            (0o100777, [0, 0], "BMI 001000", 1),
+            (0o103776, [0, 0], "BCS 000776", 1),
+            (0o077102, [0, 0], "SOB R1,000776", 1),
+            (0o077100, [0, 0], "SOB R1,001002", 1),
+        ]);
+        // Backwards past zero wraps
+        assert_eq!(disassemble(0, [0o000776, 0, 0]), ("BR 177776".to_string(), 1));
+    }
+
+    #[test]
+    fn operates_and_condition_codes() {
+        check(&[
+            (0o000000, [0, 0], "HALT", 1),
+            (0o000006, [0, 0], "RTT", 1),
+            (0o000207, [0, 0], "RTS PC", 1),
+            (0o000240, [0, 0], "NOP", 1),
+            (0o000241, [0, 0], "CLC", 1),
+            (0o000243, [0, 0], "CLV!CLC", 1),
+            (0o000257, [0, 0], "CCC", 1),
+            (0o000270, [0, 0], "SEN", 1),
+            (0o000277, [0, 0], "SCC", 1),
+            (0o006403, [0, 0], "MARK 3", 1),
+            (0o075012, [0, 0], "FSUB R2", 1),
+            (0o104377, [0, 0], "EMT 377", 1),
+            (0o104400, [0, 0], "TRAP 0", 1),
+        ]);
+    }
+
+    #[test]
+    fn not_11_40_instructions() {
+        check(&[
+            (0o000007, [0, 0], ".WORD 000007", 1),
+            (0o007000, [0, 0], ".WORD 007000", 1),
+            (0o170000, [0o12345, 0], ".WORD 170000", 1),
+        ]);
+    }
+}
@@ -0,0 +1,53 @@
+#![allow(non_snake_case)]
+
+use std::fmt;
+
+// Conditions that stop the emulated machine
+// Each carries enough context to find the microword responsible
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EmulatorError {
+    // The UPP addressed a microword that has not been transcribed yet
+    UnimplementedMicroword { UPP: u8, PUPP: u8 },
+
+    // The microword selected a BUT input that is not emulated yet
+    UnimplementedBut { UBF: u8, UPP: u8 },
+
+    // The microword selected a BUT input that is not connected in the hardware
+    UnusedBut { UBF: u8, UPP: u8 },
+
+    // The microword selected a B constant that is not emulated yet
+    UnimplementedConstant { SBC: u8, UPP: u8 },
+
+    // The microword selected a B constant that does not exist in the hardware
+    InvalidConstant { SBC: u8, UPP: u8 },
+
+    // The microword CLK field does not select a clock the emulator knows about
+    InvalidClock { CLK: u8, UPP: u8 },
+
+    // Nothing on the Unibus answered a bus cycle
+    // TODO: Trap through 4 instead of stopping
+    BusTimeout { address: u32, UPP: u8 },
+}
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmulatorError::UnimplementedMicroword { UPP, PUPP } =>
+                write!(f, "unimplemented microword at {:03o} reached from PUPP {:03o}", UPP, PUPP),
+            EmulatorError::UnimplementedBut { UBF, UPP } =>
+                write!(f, "unimplemented BUT {:02o} in microword {:03o}", UBF, UPP),
+            EmulatorError::UnusedBut { UBF, UPP } =>
+                write!(f, "unused BUT {:02o} in microword {:03o}", UBF, UPP),
+            EmulatorError::UnimplementedConstant { SBC, UPP } =>
+                write!(f, "unimplemented B constant {:02o} in microword {:03o}", SBC, UPP),
+            EmulatorError::InvalidConstant { SBC, UPP } =>
+                write!(f, "invalid B constant {:02o} in microword {:03o}", SBC, UPP),
+            EmulatorError::InvalidClock { CLK, UPP } =>
+                write!(f, "invalid clock {:o} in microword {:03o}", CLK, UPP),
+            EmulatorError::BusTimeout { address, UPP } =>
+                write!(f, "bus timeout at {:06o} in microword {:03o}", address, UPP),
+        }
+    }
+}
+
+impl std::error::Error for EmulatorError {}
@@ -0,0 +1,114 @@
+#![allow(non_snake_case)]
+
+use std::collections::BTreeSet;
+
+use crate::microbranch_control::possible_bubc;
+use crate::microrom::{microword_label, MicroRom};
+
+// Graphviz export of the microflow
+//
+// Nodes are microwords, edges are the addresses each one can hand to the UPP.
+// A BUT only reaches the UPP one machine cycle after the microword that selected it, so the
+// BUBC variants of a microword are ORed into the UPF of the microword that follows it.
+// That is how CON04 (026) tests SWITCH but the branch is taken out of CONS06 (046).
+//
+// Edges that rely on a BUBC bit being set are dashed and labelled with the BUBC value,
+// microwords that are reached but not yet transcribed are drawn grey.
+
+pub fn to_dot(rom: &MicroRom) -> String {
+    let words = rom.words();
+
+    // BUBC values that may be ORed into each microword's UPF, starting with the plain UPF
+    let mut pending: Vec<BTreeSet<u8>> = vec![BTreeSet::new(); 256];
+    for (UPP, word) in words.iter().enumerate() {
+        if word.implemented {
+            pending[UPP].insert(0);
+        }
+    }
+
+    // Follow the edges until no microword picks up a new BUBC value
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for UPP in 0..256 {
+            let word = &words[UPP];
+            if !word.implemented {
+                continue;
+            }
+            let targets: Vec<u8> = pending[UPP].iter().map(|BUBC| word.UPF | BUBC).collect();
+            for target in targets {
+                for BUBC in possible_bubc(word.UBF) {
+                    changed |= pending[target as usize].insert(BUBC);
+                }
+            }
+        }
+    }
+
+    let mut reached: BTreeSet<u8> = BTreeSet::new();
+    let mut edges = String::new();
+    for (UPP, word) in words.iter().enumerate() {
+        if !word.implemented {
+            continue;
+        }
+        reached.insert(UPP as u8);
+        for BUBC in pending[UPP].iter() {
+            let target = word.UPF | BUBC;
+            reached.insert(target);
+            if *BUBC == 0 {
+                edges += &format!("    \"{:03o}\" -> \"{:03o}\";\n", UPP, target);
+            } else {
+                edges += &format!("    \"{:03o}\" -> \"{:03o}\" [style=dashed, label=\"BUBC {:02o}\"];\n", UPP, target, BUBC);
+            }
+        }
+    }
+
+    let mut nodes = String::new();
+    for UPP in reached {
+        let word = &words[UPP as usize];
+        let mut label = format!("{:03o}", UPP);
+        if let Some((name, comment)) = microword_label(UPP) {
+            label += &format!("\\n{}\\n{}", name, comment);
+        }
+        if word.implemented {
+            if word.UBF != 0 {
+                label += &format!("\\nUBF {:02o}", word.UBF);
+            }
+            nodes += &format!("    \"{:03o}\" [label=\"{}\"];\n", UPP, label);
+        } else {
+            nodes += &format!("    \"{:03o}\" [label=\"{}\", style=filled, fillcolor=lightgrey];\n", UPP, label);
+        }
+    }
+
+    format!(
+        "digraph microrom {{\n    node [shape=box, fontname=\"monospace\"];\n\n{}\n{}}}\n",
+        nodes, edges
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::microrom::MICROROM;
+
+    // The BUTs at 026 and 044 branch out of the microword after them, 046 and 047
+    #[test]
+    fn console_loop() {
+        let dot = to_dot(&MICROROM);
+        let edges: Vec<&str> = dot.lines().filter(|line| line.contains("->")).map(str::trim).collect();
+        assert_eq!(edges, [
+            "\"000\" -> \"026\";",
+            "\"026\" -> \"046\";",
+            "\"027\" -> \"044\";",
+            "\"044\" -> \"047\";",
+            "\"046\" -> \"026\";",
+            "\"046\" -> \"027\" [style=dashed, label=\"BUBC 01\"];",
+            "\"047\" -> \"044\";",
+            "\"047\" -> \"045\" [style=dashed, label=\"BUBC 01\"];",
+        ]);
+
+        // 045 is reached but not transcribed
+        let grey: Vec<&str> = dot.lines().filter(|line| line.contains("fillcolor=lightgrey")).map(str::trim).collect();
+        assert_eq!(grey, ["\"045\" [label=\"045\", style=filled, fillcolor=lightgrey];"]);
+        assert!(dot.contains("\"026\" [label=\"026\\nCON04\\nTEST FOR SWITCH\\nUBF 06\"];"));
+    }
+}
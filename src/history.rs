@@ -0,0 +1,98 @@
+#![allow(non_snake_case)]
+
+use std::collections::VecDeque;
+
+use crate::machinestate::{MachineState, Processor};
+use crate::unibus::BusChange;
+
+// Reverse stepping, a bounded history of machine cycles
+// Each cycle keeps the processor registers from its start and the memory words and device
+// states its bus cycles overwrote, enough to put the machine back as it was before the cycle.
+// Coverage, traces and console output already written are not taken back.
+//
+// Memory is kept as deltas, the old value of each word a bus cycle wrote, a full copy is 56K
+// bytes at 28K words. The processor registers are copied whole, they are 144 bytes with the
+// record and most of them change every cycle, so the default history is about 9M bytes plus
+// 32 bytes for each word or device written.
+
+pub const DEFAULT_HISTORY_CYCLES: usize = 65536;
+
+struct CycleRecord<'a> {
+    processor: Processor<'a>,
+    bus: Vec<BusChange>,
+}
+
+pub struct History<'a> {
+    capacity: usize,
+    cycles: VecDeque<CycleRecord<'a>>,
+
+    // The machine cycle in progress, set from its first clock pulse until it completes or halts
+    current: Option<Processor<'a>>,
+}
+
+impl<'a> History<'a> {
+    pub fn new(capacity: usize) -> History<'a> {
+        History {
+            capacity,
+            cycles: VecDeque::with_capacity(capacity.min(DEFAULT_HISTORY_CYCLES)),
+            current: None,
+        }
+    }
+
+    // Machine cycles that can be stepped back over
+    pub fn len(&self) -> usize {
+        self.cycles.len() + self.current.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.cycles.clear();
+        self.current = None;
+    }
+
+    // Call before every clock pulse
+    pub fn before_pulse(&mut self, state: &mut MachineState<'a>) {
+        if self.current.is_none() && state.PULSE == 0 {
+            self.current = Some(Processor::capture(state));
+            state.UNIBUS.journal = Some(Vec::new());
+        }
+    }
+
+    // Call after a clock pulse, with whether it finished the machine cycle or halted the machine
+    pub fn after_pulse(&mut self, state: &mut MachineState<'a>, finished: bool) {
+        if !finished {
+            return;
+        }
+        if let Some(processor) = self.current.take() {
+            let bus = state.UNIBUS.journal.take().unwrap_or_default();
+            if self.cycles.len() == self.capacity {
+                self.cycles.pop_front();
+            }
+            if self.capacity > 0 {
+                self.cycles.push_back(CycleRecord { processor, bus });
+            }
+        }
+    }
+
+    // Put the machine back to the start of the last machine cycle, or of the one pulse stepping
+    // is part way through, false if there is no history left
+    pub fn step_back(&mut self, state: &mut MachineState<'a>) -> bool {
+        if let Some(processor) = self.current.take() {
+            let bus = state.UNIBUS.journal.take().unwrap_or_default();
+            state.UNIBUS.undo(bus);
+            processor.apply(state);
+            return true;
+        }
+        match self.cycles.pop_back() {
+            Some(record) => {
+                state.UNIBUS.undo(record.bus);
+                record.processor.apply(state);
+                true
+            },
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,40 @@
+#![allow(non_snake_case)]
+
+// KD11-A (PDP-11/40) microcode emulator
+// Machine is the entry point for anything that embeds the emulator, the rest is exposed
+// for tools that need to look at the microROM or the data path directly
+
+pub mod machinestate;
+pub mod bconstant;
+pub mod clock;
+pub mod microrom;
+pub mod microbranch_control;
+pub mod debug;
+pub mod data_path;
+pub mod rom_image;
+pub mod coverage;
+pub mod error;
+pub mod flowgraph;
+pub mod alu;
+pub mod trace;
+pub mod unibus;
+pub mod processor;
+pub mod debugger;
+pub mod watch;
+pub mod machine;
+pub mod disassembler;
+pub mod snapshot;
+pub mod history;
+pub mod replay;
+pub mod pacing;
+pub mod scheduler;
+pub mod decode;
+pub mod vcd;
+pub mod reference;
+pub mod differential;
+pub mod absolute_loader;
+
+pub use error::EmulatorError;
+pub use machine::Machine;
+pub use machinestate::MachineState;
+pub use microrom::{MicroRom, MICROROM};
@@ -0,0 +1,291 @@
+#![allow(non_snake_case)]
+
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use crate::clock::ClockMode;
+use crate::decode::DecodedRom;
+use crate::disassembler;
+use crate::error::EmulatorError;
+use crate::history::History;
+use crate::machinestate::MachineState;
+use crate::microrom::MicroRom;
+use crate::processor::{self, Session};
+use crate::replay::{self, Input};
+use crate::snapshot;
+use crate::unibus::{BusTimeout, Device, Unibus};
+
+// A KD11-A on its Unibus, for front ends, tools and test harnesses to drive
+// An error halts the machine, after that every step returns the same error
+pub struct Machine<'a> {
+    pub state: MachineState<'a>,
+    pub session: Session,
+
+    // Past machine cycles for stepping backwards, None until enabled
+    pub history: Option<History<'a>>,
+}
+
+impl<'a> Machine<'a> {
+    // Power up at the given microword with memory_words of memory, no devices and nothing displayed
+    pub fn new(ROM: &'a MicroRom, UPP: u8, memory_words: usize) -> Result<Machine<'a>, EmulatorError> {
+        let mut state = MachineState::new(ROM, UPP)?;
+        state.UNIBUS = Unibus::new(memory_words);
+        Ok(Machine {
+            state,
+            session: Session::new(false),
+            history: None,
+        })
+    }
+
+    pub fn attach(&mut self, device: Box<dyn Device>) {
+        self.state.UNIBUS.attach(device);
+    }
+
+    pub fn state(&self) -> &MachineState<'a> {
+        &self.state
+    }
+
+    pub fn state_mut(&mut self) -> &mut MachineState<'a> {
+        &mut self.state
+    }
+
+    pub fn halted(&self) -> Option<EmulatorError> {
+        self.state.HALTED
+    }
+
+    // Emulated nanoseconds since power up
+    pub fn time_ns(&self) -> u64 {
+        self.state.TIME_NS
+    }
+
+    pub fn register(&self, number: usize) -> u16 {
+        self.state.REGISTERS[number]
+    }
+
+    pub fn read_memory(&mut self, address: u32) -> Result<u16, BusTimeout> {
+        self.state.UNIBUS.read(address)
+    }
+
+    pub fn write_memory(&mut self, address: u32, value: u16) -> Result<(), BusTimeout> {
+        self.state.UNIBUS.write(address, value, false)
+    }
+
+    // Apply an input from outside the machine, logged if the session is recording
+    pub fn input(&mut self, input: Input) -> Result<(), String> {
+        replay::apply(&mut self.state, input)?;
+        if let Some(recording) = &mut self.session.recording {
+            recording.record(&self.state, input);
+        }
+        Ok(())
+    }
+
+    // Start the program at address as the console START would, PC loaded and SWITCH set
+    // TODO: The real START goes through the console microcode with the address in the switch
+    // register, the console isn't emulated yet
+    pub fn start(&mut self, address: u16) -> Result<(), String> {
+        self.state.REGISTERS[7] = address;
+        self.input(Input::Switch(true))
+    }
+
+    // Anything the machine has sent to the console since the last call
+    pub fn take_output(&mut self) -> Vec<u8> {
+        self.state.UNIBUS.take_output()
+    }
+
+    // Save the whole machine, registers, microprogram pointers, memory and devices
+    pub fn save_snapshot(&self, path: &Path) -> io::Result<()> {
+        snapshot::save(&self.state, path)
+    }
+
+    // Carry on from a saved snapshot, the microROM must be the one it was saved with
+    pub fn restore_snapshot(&mut self, path: &Path) -> io::Result<()> {
+        snapshot::restore(&mut self.state, path)?;
+        if let Some(history) = &mut self.history {
+            history.clear();
+        }
+        Ok(())
+    }
+
+    // Decode the microROM once up front so machine cycles run faster, the results are the same
+    pub fn predecode(&mut self) {
+        self.session.decoded = Some(Box::new(DecodedRom::new(self.state.ROM)));
+    }
+
+    // Keep the last cycles machine cycles so they can be stepped back over
+    pub fn enable_history(&mut self, cycles: usize) {
+        self.history = Some(History::new(cycles));
+    }
+
+    // Go back to the start of the last machine cycle, false if there is no history to go back to
+    pub fn step_back(&mut self) -> bool {
+        let Some(history) = &mut self.history else {
+            return false;
+        };
+        self.session.watch_hits.clear();
+        if !history.step_back(&mut self.state) {
+            return false;
+        }
+        self.state.UNIBUS.schedule_all(self.state.TIME_NS);
+        true
+    }
+
+    // Run the next clock pulse, returns true once it completes a machine cycle
+    pub fn step_pulse(&mut self) -> Result<bool, EmulatorError> {
+        if let Some(error) = self.state.HALTED {
+            return Err(error);
+        }
+        if let Some(history) = &mut self.history {
+            history.before_pulse(&mut self.state);
+        }
+        let result = processor::step_pulse(&mut self.state, &mut self.session).inspect_err(|error| {
+            // Stop the machine as it was before the pulse, the state is left for inspection
+            self.state.HALTED = Some(*error);
+        });
+        if let Some(history) = &mut self.history {
+            history.after_pulse(&mut self.state, !matches!(result, Ok(false)));
+        }
+        result
+    }
+
+    // Run to the end of the machine cycle, a whole one unless pulse stepping left one part way through
+    pub fn step_cycle(&mut self) -> Result<(), EmulatorError> {
+        while !self.step_pulse()? {}
+        Ok(())
+    }
+
+    // Run machine cycles up to and including the next one that clocks the IR (CIR), so the
+    // instruction in the IR runs to completion and the next one is fetched
+    // Stops early if the clock stops, the instruction carries on once the console restarts it,
+    // and after INSTRUCTION_CYCLE_LIMIT machine cycles without a fetch. Nothing in the microROM
+    // clocks the IR yet, so for now every step ends at the limit or a halt
+    pub fn step_instruction(&mut self) -> Result<InstructionStep, EmulatorError> {
+        let IR = self.state.IR;
+        let PC = self.state.IR_PC;
+        let (text, _) = disassembler::disassemble_instruction(&self.state.UNIBUS, IR, PC);
+        let before = self.general_registers();
+        let start_ns = self.state.TIME_NS;
+
+        let mut cycles = 0;
+        if self.state.PULSE != 0 {
+            self.step_cycle()?;
+            cycles += 1;
+        }
+        let end = loop {
+            let fetch = self.state.U_WORD.CIR != 0;
+            self.step_cycle()?;
+            cycles += 1;
+            if fetch {
+                break StepEnd::Fetched;
+            }
+            if self.clock_stopped() {
+                break StepEnd::ClockStopped;
+            }
+            if cycles >= INSTRUCTION_CYCLE_LIMIT {
+                break StepEnd::CycleLimit;
+            }
+        };
+
+        let after = self.general_registers();
+        let changes = GENERAL_REGISTER_NAMES.iter().zip(before.iter().zip(after.iter()))
+            .filter(|(_, (before, after))| before != after)
+            .map(|(name, (before, after))| (*name, *before, *after))
+            .collect();
+        let ns = self.state.TIME_NS - start_ns;
+        Ok(InstructionStep { IR, PC, text, next_IR: self.state.IR, cycles, ns, changes, end })
+    }
+
+    // The clock is off, the machine is waiting for the console to restart it
+    pub fn clock_stopped(&self) -> bool {
+        self.state.CLK_MODE.mode == ClockMode::OFF
+    }
+
+    // R0 to R7 and the PSW, as a PDP-11 programmer sees them
+    fn general_registers(&self) -> [u16; 9] {
+        let mut registers = [0; 9];
+        registers[..8].copy_from_slice(&self.state.REGISTERS[..8]);
+        registers[8] = self.state.PSW;
+        registers
+    }
+
+    // Run up to max_cycles machine cycles as fast as the host allows, for long unattended runs
+    // Nothing is displayed and no history is kept, console output waits in the devices for
+    // take_output. Stops early when the clock stops or a watchpoint triggers, returns the number
+    // of machine cycles run
+    pub fn run_headless(&mut self, max_cycles: u64) -> Result<u64, EmulatorError> {
+        if let Some(error) = self.state.HALTED {
+            return Err(error);
+        }
+        // The history can't step back over cycles it didn't see
+        if let Some(history) = &mut self.history {
+            history.clear();
+        }
+        let display = std::mem::replace(&mut self.session.display, false);
+        let result = processor::run_cycles(&mut self.state, &mut self.session, max_cycles);
+        self.session.display = display;
+        result.inspect_err(|error| self.state.HALTED = Some(*error))
+    }
+
+    // Run up to max_cycles machine cycles, stopping early after a cycle where stop returns true
+    // or a watchpoint triggers, returns the number of machine cycles run
+    pub fn run_until(&mut self, max_cycles: u64, mut stop: impl FnMut(&MachineState) -> bool) -> Result<u64, EmulatorError> {
+        for cycle in 1..=max_cycles {
+            self.step_cycle()?;
+            if stop(&self.state) || !self.session.watch_hits.is_empty() {
+                return Ok(cycle);
+            }
+        }
+        Ok(max_cycles)
+    }
+}
+
+// Machine cycles an instruction step runs without a fetch before giving up, far more than the
+// longest instruction
+pub const INSTRUCTION_CYCLE_LIMIT: u64 = 1000;
+
+const GENERAL_REGISTER_NAMES: [&str; 9] = ["R0", "R1", "R2", "R3", "R4", "R5", "SP", "PC", "PSW"];
+
+// What one instruction step did
+pub struct InstructionStep {
+    // The instruction that ran, and the address it was fetched from
+    pub IR: u16,
+    pub PC: u16,
+    pub text: String,
+
+    // The instruction fetched at the end of the step, that runs next
+    pub next_IR: u16,
+
+    pub cycles: u64,
+
+    // Emulated time the step took, to compare with the instruction timing tables
+    pub ns: u64,
+
+    // Every general register and the PSW that changed, (name, before, after)
+    pub changes: Vec<(&'static str, u16, u16)>,
+
+    pub end: StepEnd,
+}
+
+// Why an instruction step stopped
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StepEnd {
+    // The next instruction was fetched, the step is complete
+    Fetched,
+    ClockStopped,
+    CycleLimit,
+}
+
+impl fmt::Display for InstructionStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:06o}: {:06o}  {:<24}({} cycles, {:.2} us)", self.PC, self.IR, self.text, self.cycles, self.ns as f64 / 1000.0)?;
+        for (name, before, after) in self.changes.iter() {
+            write!(f, "\n  {:<4}{:06o} -> {:06o}", name, before, after)?;
+        }
+        match self.end {
+            StepEnd::Fetched => {},
+            StepEnd::ClockStopped => write!(f, "\n  Clock stopped before the next fetch")?,
+            StepEnd::CycleLimit => write!(f, "\n  No instruction fetch in {} machine cycles", INSTRUCTION_CYCLE_LIMIT)?,
+        }
+        Ok(())
+    }
+}
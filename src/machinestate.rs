@@ -1,13 +1,16 @@
 #![allow(non_snake_case)]
 
-use crate::{clock::{ClockState, CL1}, microrom::{MicroWord, MICROROM}};
+use crate::{clock::{ClockState, CL1}, error::EmulatorError, microrom::{MicroRom, MicroWord}, unibus::{BusCycle, Unibus}};
 
-const SINGLE_CLOCK_MODE: bool = true;
-const STARTING_UPP: u8 = 0;
+pub const SINGLE_CLOCK_MODE: bool = true;
+pub const STARTING_UPP: u8 = 0;
 
 
 pub struct MachineState<'a> {
 
+    // The microROM the U WORD register is loaded from
+    pub ROM: &'a MicroRom,
+
     // The data display is directly driven off the out of the DMUX
     // For now I will explicitly set DATA_DISPLAY after evaluating the DMUX value
     pub DATA_DISPLAY: u16,
@@ -16,6 +19,9 @@ pub struct MachineState<'a> {
     // contains associated information
     pub CLK_MODE: &'a ClockState,
 
+    // The clock pulse of the current machine cycle that runs next, 0 at the start of a cycle
+    pub PULSE: u32,
+
     // Single Clock Mode
     // This is true if in Single Clock Mode (using KM11), it skips the SWITCH debounce.
     pub SINCLK: bool,
@@ -54,19 +60,62 @@ pub struct MachineState<'a> {
     // D Register
     pub D: u16,
 
+    // Scratchpad Memory - 16 x 16 bits
+    // R0-R7 followed by the processor's temporary registers, addressed by RIF
+    pub REGISTERS: [u16; 16],
+
+    // Instruction Register, and the bus address it was fetched from
+    pub IR: u16,
+    pub IR_PC: u16,
+
+    // Processor Status Word
+    // TODO: SPS is not emulated yet, nothing but the debugger changes the PSW
+    pub PSW: u16,
+
+    // Bus Address Register
+    pub BA: u16,
+
     // Unibus data
-    pub BUS_D: u16
+    pub BUS_D: u16,
+
+    // The Unibus with memory and any attached devices
+    pub UNIBUS: Unibus,
+
+    // Outputs of the data path during the last machine cycle
+    // Nothing latches these, they are kept for tracing and debugging
+    pub B_MUX: u16,
+    pub D_MUX: u16,
+    pub ALU: u16,
+
+    // Scratchpad write made during the last machine cycle, (address, value)
+    pub REGISTER_WRITE: Option<(u8, u16)>,
+
+    // Unibus cycle made during the last machine cycle, (type, address)
+    pub BUS_ACCESS: Option<(BusCycle, u32)>,
+
+    // Number of machine cycles run since power up
+    pub CYCLE: u64,
+
+    // Emulated time since power up in nanoseconds, clock periods plus time waiting on the Unibus
+    pub TIME_NS: u64,
+
+    // Set when the emulator stops the machine, the rest of the state is left as it was
+    // before the clock pulse that failed
+    pub HALTED: Option<EmulatorError>,
 }
 
 
-impl MachineState<'_> {
-    pub fn new() -> MachineState<'static> {
-        MachineState {
+impl<'a> MachineState<'a> {
+    // Power up at the given microword, with no memory until a Unibus is set
+    pub fn new(ROM: &'a MicroRom, UPP: u8) -> Result<MachineState<'a>, EmulatorError> {
+        Ok(MachineState {
+            ROM,
             DATA_DISPLAY: 0,
             CLK_MODE: &CL1,
+            PULSE: 0,
             SINCLK: SINGLE_CLOCK_MODE,
-            U_WORD: &MICROROM[STARTING_UPP as usize],
-            UPP: STARTING_UPP,
+            U_WORD: ROM.fetch(UPP, 0)?,
+            UPP,
             BUPP: 0,
             PUPP: 0,
             BUBC: 0,
@@ -74,7 +123,202 @@ impl MachineState<'_> {
             SWITCH: false,
             B: 0,
             D: 0,
+            REGISTERS: [0; 16],
+            IR: 0,
+            IR_PC: 0,
+            PSW: 0,
+            BA: 0,
             BUS_D: 0,
+            UNIBUS: Unibus::new(0),
+            B_MUX: 0,
+            D_MUX: 0,
+            ALU: 0,
+            REGISTER_WRITE: None,
+            BUS_ACCESS: None,
+            CYCLE: 0,
+            TIME_NS: 0,
+            HALTED: None,
+        })
+    }
+}
+
+// Everything in MachineState but the Unibus, to put the processor back as it was
+#[derive(Clone, Copy)]
+pub struct Processor<'a> {
+    DATA_DISPLAY: u16,
+    CLK_MODE: &'a ClockState,
+    PULSE: u32,
+    SINCLK: bool,
+    U_WORD: &'a MicroWord,
+    UPP: u8,
+    BUPP: u8,
+    PUPP: u8,
+    BUBC: u8,
+    BUBC_FLUX: u8,
+    SWITCH: bool,
+    B: u16,
+    D: u16,
+    REGISTERS: [u16; 16],
+    IR: u16,
+    IR_PC: u16,
+    PSW: u16,
+    BA: u16,
+    BUS_D: u16,
+    B_MUX: u16,
+    D_MUX: u16,
+    ALU: u16,
+    REGISTER_WRITE: Option<(u8, u16)>,
+    BUS_ACCESS: Option<(BusCycle, u32)>,
+    CYCLE: u64,
+    TIME_NS: u64,
+    HALTED: Option<EmulatorError>,
+}
+
+impl<'a> Processor<'a> {
+    pub fn capture(state: &MachineState<'a>) -> Processor<'a> {
+        Processor {
+            DATA_DISPLAY: state.DATA_DISPLAY,
+            CLK_MODE: state.CLK_MODE,
+            PULSE: state.PULSE,
+            SINCLK: state.SINCLK,
+            U_WORD: state.U_WORD,
+            UPP: state.UPP,
+            BUPP: state.BUPP,
+            PUPP: state.PUPP,
+            BUBC: state.BUBC,
+            BUBC_FLUX: state.BUBC_FLUX,
+            SWITCH: state.SWITCH,
+            B: state.B,
+            D: state.D,
+            REGISTERS: state.REGISTERS,
+            IR: state.IR,
+            IR_PC: state.IR_PC,
+            PSW: state.PSW,
+            BA: state.BA,
+            BUS_D: state.BUS_D,
+            B_MUX: state.B_MUX,
+            D_MUX: state.D_MUX,
+            ALU: state.ALU,
+            REGISTER_WRITE: state.REGISTER_WRITE,
+            BUS_ACCESS: state.BUS_ACCESS,
+            CYCLE: state.CYCLE,
+            TIME_NS: state.TIME_NS,
+            HALTED: state.HALTED,
+        }
+    }
+
+    pub fn apply(&self, state: &mut MachineState<'a>) {
+        state.DATA_DISPLAY = self.DATA_DISPLAY;
+        state.CLK_MODE = self.CLK_MODE;
+        state.PULSE = self.PULSE;
+        state.SINCLK = self.SINCLK;
+        state.U_WORD = self.U_WORD;
+        state.UPP = self.UPP;
+        state.BUPP = self.BUPP;
+        state.PUPP = self.PUPP;
+        state.BUBC = self.BUBC;
+        state.BUBC_FLUX = self.BUBC_FLUX;
+        state.SWITCH = self.SWITCH;
+        state.B = self.B;
+        state.D = self.D;
+        state.REGISTERS = self.REGISTERS;
+        state.IR = self.IR;
+        state.IR_PC = self.IR_PC;
+        state.PSW = self.PSW;
+        state.BA = self.BA;
+        state.BUS_D = self.BUS_D;
+        state.B_MUX = self.B_MUX;
+        state.D_MUX = self.D_MUX;
+        state.ALU = self.ALU;
+        state.REGISTER_WRITE = self.REGISTER_WRITE;
+        state.BUS_ACCESS = self.BUS_ACCESS;
+        state.CYCLE = self.CYCLE;
+        state.TIME_NS = self.TIME_NS;
+        state.HALTED = self.HALTED;
+    }
+}
+
+// Fields that can be read and written by name, for the debugger
+// The scratchpad registers are R0 to R17, numbered in octal
+pub const FIELD_NAMES: [&str; 21] = [
+    "UPP", "PUPP", "BUPP", "BUBC", "BUBC_FLUX", "SWITCH", "SINCLK", "B", "D", "IR", "IR_PC", "PSW", "BA", "BUS_D",
+    "B_MUX", "D_MUX", "ALU", "DATA_DISPLAY", "CYCLE", "TIME_NS", "PULSE",
+];
+
+impl MachineState<'_> {
+    pub fn is_field(name: &str) -> bool {
+        let name = name.to_uppercase();
+        register_number(&name).is_some() || FIELD_NAMES.contains(&name.as_str())
+    }
+
+    pub fn field(&self, name: &str) -> Option<u64> {
+        let name = name.to_uppercase();
+        if let Some(register) = register_number(&name) {
+            return Some(self.REGISTERS[register] as u64);
         }
+        let value = match name.as_str() {
+            "UPP" => self.UPP as u64,
+            "PUPP" => self.PUPP as u64,
+            "BUPP" => self.BUPP as u64,
+            "BUBC" => self.BUBC as u64,
+            "BUBC_FLUX" => self.BUBC_FLUX as u64,
+            "SWITCH" => self.SWITCH as u64,
+            "SINCLK" => self.SINCLK as u64,
+            "B" => self.B as u64,
+            "D" => self.D as u64,
+            "IR" => self.IR as u64,
+            "IR_PC" => self.IR_PC as u64,
+            "PSW" => self.PSW as u64,
+            "BA" => self.BA as u64,
+            "BUS_D" => self.BUS_D as u64,
+            "B_MUX" => self.B_MUX as u64,
+            "D_MUX" => self.D_MUX as u64,
+            "ALU" => self.ALU as u64,
+            "DATA_DISPLAY" => self.DATA_DISPLAY as u64,
+            "CYCLE" => self.CYCLE,
+            "TIME_NS" => self.TIME_NS,
+            "PULSE" => self.PULSE as u64,
+            _ => return None,
+        };
+        Some(value)
     }
-}
\ No newline at end of file
+
+    // Setting the UPP latches the microword it points to, as if the machine had branched there
+    pub fn set_field(&mut self, name: &str, value: u64) -> Result<(), String> {
+        let name = name.to_uppercase();
+        if let Some(register) = register_number(&name) {
+            self.REGISTERS[register] = value as u16;
+            return Ok(());
+        }
+        match name.as_str() {
+            "UPP" => {
+                self.U_WORD = self.ROM.fetch(value as u8, self.PUPP).map_err(|e| e.to_string())?;
+                self.UPP = value as u8;
+                self.BUPP = value as u8;
+            },
+            "PUPP" => self.PUPP = value as u8,
+            "BUPP" => self.BUPP = value as u8,
+            "BUBC" => self.BUBC = value as u8,
+            "BUBC_FLUX" => self.BUBC_FLUX = value as u8,
+            "SWITCH" => self.SWITCH = value != 0,
+            "SINCLK" => self.SINCLK = value != 0,
+            "B" => self.B = value as u16,
+            "D" => self.D = value as u16,
+            "IR" => self.IR = value as u16,
+            "IR_PC" => self.IR_PC = value as u16,
+            "PSW" => self.PSW = value as u16,
+            "BA" => self.BA = value as u16,
+            "BUS_D" => self.BUS_D = value as u16,
+            "DATA_DISPLAY" => self.DATA_DISPLAY = value as u16,
+            "B_MUX" | "D_MUX" | "ALU" | "CYCLE" | "TIME_NS" | "PULSE" => return Err(format!("{} is read only", name)),
+            _ => return Err(format!("unknown field '{}'", name)),
+        }
+        Ok(())
+    }
+}
+
+// "R0" to "R17" (octal) to a scratchpad index
+fn register_number(name: &str) -> Option<usize> {
+    let number = usize::from_str_radix(name.strip_prefix('R')?, 8).ok()?;
+    if number < 16 { Some(number) } else { None }
+}
@@ -1,135 +1,337 @@
 #![allow(non_snake_case)]
 
-mod machinestate;
-mod bconstant;
-mod clock;
-mod microrom;
-mod microbranch_control;
-mod debug;
-mod data_path;
+mod cli;
 
 use std::thread;
-use std::time::{self, Duration, Instant};
-use machinestate::MachineState;
-use microrom::MICROROM;
-use clock::{ClockMode, CL1, CL2, CL3};
-use clock::ClockPulse;
-use debug::{print_data_bus, print_diagnostic_tool};
+use std::time::Instant;
+use std::io::Write;
+use std::path::Path;
+use pdp11_microcode_emulator::{flowgraph, rom_image, unibus};
+use pdp11_microcode_emulator::{Machine, MicroRom, MICROROM};
+use pdp11_microcode_emulator::coverage::Coverage;
+use pdp11_microcode_emulator::trace::Tracer;
+use pdp11_microcode_emulator::vcd::VcdWriter;
+use pdp11_microcode_emulator::debugger::Debugger;
+use pdp11_microcode_emulator::watch::Watchpoint;
+use pdp11_microcode_emulator::replay::{Input, InputLog, Replay};
+use pdp11_microcode_emulator::pacing::Pacer;
+use cli::RunMode;
 
-const SINGLE_STEP_EMULATION: bool = true;
-const MACHINE_CYCLE_SLEEP: Duration = time::Duration::from_millis(100);
+fn main() {
 
+    // Command line options
+    let options = match cli::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            std::process::exit(1);
+        },
+    };
+    if options.help {
+        println!("{}", cli::USAGE);
+        return;
+    }
 
-fn main() {
+    let ROM: &'static MicroRom = match &options.microrom {
+        Some(directory) => match rom_image::import(directory) {
+            Ok(rom) => Box::leak(Box::new(rom)),
+            Err(e) => {
+                eprintln!("Failed to load microROM: {}", e);
+                std::process::exit(1);
+            },
+        },
+        None => &MICROROM,
+    };
 
-    //-------------------------------
-    //---------Machine State---------
-    //-------------------------------
-    let mut MACHINE_STATE = MachineState::new();
+    // Export the microROM as PROM images and exit
+    if let Some(directory) = &options.export_rom {
+        match rom_image::export(ROM, directory) {
+            Ok(()) => println!("Wrote {} microROM chip images to {}", rom_image::ROM_CHIP_COUNT, directory.display()),
+            Err(e) => eprintln!("Failed to export microROM: {}", e),
+        }
+        return;
+    }
 
-    // TODO: Build UI?
-    MACHINE_STATE.SWITCH = true; // Fake a key press
+    // Write the microflow as a Graphviz graph and exit
+    if let Some(path) = &options.dot {
+        match std::fs::write(path, flowgraph::to_dot(ROM)) {
+            Ok(()) => println!("Wrote microflow graph to {}", path.display()),
+            Err(e) => eprintln!("Failed to write microflow graph: {}", e),
+        }
+        return;
+    }
 
-    //---------------------------------
-    //--------Application Loop---------
-    //---------------------------------
-    loop {
-        // Set up next machine cycle
-        print!("{esc}[2J{esc}[1;1H", esc = 27 as char); // Clear the screen
-
-        println!("New Machine Cycle");
-        println!("Current UPP = {:o}", MACHINE_STATE.UPP);
-        println!("Current clk = {:?}", MACHINE_STATE.CLK_MODE.mode);
-
-        let time_zero = Instant::now(); // Time = 0, start of machine cycle
-
-        // Each machine cycle may have either one or two clock pulses
-        // Various parts of the processor subscribe to particular clock pulses
-        for pulse in 0..MACHINE_STATE.CLK_MODE.pulse_count {
-            // Theses flags signify a trigger of a clock pulse, only one pulses is active a loop
-            let active_pulse = &MACHINE_STATE.CLK_MODE.pulses[pulse as usize];
-            let P1 = *active_pulse == ClockPulse::P1;
-            let P2 = *active_pulse == ClockPulse::P2;
-            let P3 = *active_pulse == ClockPulse::P3;
-
-            // Add clock delay
-            match *active_pulse {
-                ClockPulse::NONE => todo!(),
-                ClockPulse::P1 => println!("Delay 140ms, pulse P1"),
-                ClockPulse::P2 => println!("Delay 200ms, pulse P2"),
-                ClockPulse::P3 => println!("Delay 100ms, pulse P3"),
-            }
+    let coverage = match &options.coverage {
+        Some(path) => match Coverage::load(path) {
+            Ok(coverage) => Some(coverage),
+            Err(e) => {
+                eprintln!("Failed to load coverage: {}", e);
+                std::process::exit(1);
+            },
+        },
+        None => None,
+    };
 
-            // For the next 140ms to 300ms the cpu will do stuff
-            println!("Current UBF = {:o}", MACHINE_STATE.U_WORD.UBF);
+    let tracer = match &options.trace {
+        Some(path) => match Tracer::create(path, options.trace_format, options.trace_fields) {
+            Ok(tracer) => Some(tracer),
+            Err(e) => {
+                eprintln!("Failed to create trace: {}", e);
+                std::process::exit(1);
+            },
+        },
+        None => None,
+    };
 
-            // Test code only
+    let vcd = match &options.vcd {
+        Some(path) => match VcdWriter::create(path) {
+            Ok(vcd) => Some(vcd),
+            Err(e) => {
+                eprintln!("Failed to create VCD: {}", e);
+                std::process::exit(1);
+            },
+        },
+        None => None,
+    };
 
-            // evaluate everything on the BUS RD
+    //-------------------------------
+    //---------Machine State---------
+    //-------------------------------
+    let mut machine = match Machine::new(ROM, options.start_upp, options.memory_words) {
+        Ok(machine) => machine,
+        Err(e) => {
+            eprintln!("Cannot start: {}", e);
+            std::process::exit(1);
+        },
+    };
+    machine.state.SINCLK = options.sinclk;
+    for name in options.devices.iter() {
+        match unibus::device_by_name(name) {
+            Some(device) => machine.attach(device),
+            None => {
+                eprintln!("Unknown device '{}'", name);
+                std::process::exit(1);
+            },
+        }
+    }
 
-            let bConst = bconstant::evaluate_bconstant(&MACHINE_STATE, MACHINE_STATE.U_WORD.SBC);
-            let B_MUX = data_path::evaluate_bmux(MACHINE_STATE.U_WORD.SBM, MACHINE_STATE.B, bConst);
-            let D_MUX = data_path::evaluate_dmux(MACHINE_STATE.U_WORD.SDM, 0, 0, 0b11100011, 0);
+    machine.session.display = options.mode == RunMode::SingleStep || options.mode == RunMode::FreeRun;
+    machine.session.coverage = coverage;
+    machine.session.tracer = tracer;
+    machine.session.vcd = vcd;
+    for spec in options.watches.iter() {
+        match Watchpoint::parse(spec) {
+            Ok(watchpoint) => machine.session.watchpoints.add(watchpoint),
+            Err(e) => {
+                eprintln!("Invalid watchpoint '{}': {}", spec, e);
+                std::process::exit(1);
+            },
+        }
+    }
 
-            println!("D_MUX: {:b}", D_MUX);
-            println!("B Const {}", bConst);
-            println!("B MUX {}", B_MUX);
-            MACHINE_STATE.D = bConst; // REMOVE THIS AFTER TESTING replace mux
-            MACHINE_STATE.DATA_DISPLAY = bConst;
+    if options.record.is_some() {
+        machine.session.recording = Some(InputLog::new());
+    }
 
+    // A snapshot replaces everything above, devices and front panel included
+    if let Some(path) = &options.restore {
+        if let Err(e) = machine.restore_snapshot(path) {
+            eprintln!("Failed to restore snapshot: {}", e);
+            std::process::exit(1);
+        }
+    }
 
-            // At the end of the machine cycle latch the UWORD from the ROM
-            if P1 || (P2 && MACHINE_STATE.CLK_MODE.mode != ClockMode::CL3) || P3 {
-                // Latch UPP into PUPP
-                MACHINE_STATE.PUPP = MACHINE_STATE.UPP;
+    match &options.replay {
+        // The log holds every input the recorded run had, the key press below included
+        Some(path) => match InputLog::load(path) {
+            Ok(log) => machine.session.replay = Some(Replay::new(log)),
+            Err(e) => {
+                eprintln!("Failed to load input log: {}", e);
+                std::process::exit(1);
+            },
+        },
+        // A restored machine keeps the SWITCH it was saved with
+        None if options.restore.is_some() => {},
+        // TODO: Build UI?
+        None => {
+            let _ = machine.input(Input::Switch(true)); // Fake a key press
+        },
+    }
 
-                // Compute next UPP based off the content of BUBC from the last machine cycle
-                MACHINE_STATE.UPP = MACHINE_STATE.U_WORD.UPF | MACHINE_STATE.BUBC; // Latch in the next UPP
+    if let Some(coverage) = &mut machine.session.coverage {
+        coverage.record_word(machine.state.UPP);
+    }
+    if let Some(vcd) = &mut machine.session.vcd {
+        if let Err(e) = vcd.sample(&machine.state) {
+            eprintln!("Failed to write VCD: {}", e);
+            std::process::exit(1);
+        }
+    }
 
-                // At the start of the next machine cycle BUPP/UPP will point the the "current" microstate.
-                MACHINE_STATE.BUPP = MACHINE_STATE.UPP;
+    //---------------------------------
+    //--------Application Loop---------
+    //---------------------------------
+    match options.mode {
+        RunMode::Debug => {
+            machine.enable_history(options.history);
+            Debugger::new().run(&mut machine)
+        },
+        RunMode::Headless => {
+            machine.predecode();
+            headless(&mut machine, &options)
+        },
+        _ => run(&mut machine, &options),
+    }
 
-                // Clock so latch in new microword
-                MACHINE_STATE.U_WORD = &MICROROM[MACHINE_STATE.UPP as usize];
-                
-                // The BUBC lines are in flux for one machine cycle, we save that state in BUBC_NEXT
-                MACHINE_STATE.BUBC = MACHINE_STATE.BUBC_FLUX;
-                // evaluate_bubc must be called at the end of the machine cycle to allow time for BUTs to settle
-                MACHINE_STATE.BUBC_FLUX = microbranch_control::evaluate_bubc(&MACHINE_STATE);
+    if let Some(path) = &options.save {
+        match machine.save_snapshot(path) {
+            Ok(()) => println!("Snapshot written to {}", path.display()),
+            Err(e) => eprintln!("Failed to write snapshot: {}", e),
+        }
+    }
+    if let (Some(path), Some(recording)) = (&options.record, &machine.session.recording) {
+        match recording.save(path) {
+            Ok(()) => println!("Input log written to {}", path.display()),
+            Err(e) => eprintln!("Failed to write input log: {}", e),
+        }
+    }
+    if let Some(coverage) = &machine.session.coverage {
+        write_coverage(coverage, ROM, options.coverage.as_deref());
+    }
+    if let Some(tracer) = &mut machine.session.tracer {
+        if let Err(e) = tracer.flush() {
+            eprintln!("Failed to write trace: {}", e);
+        }
+    }
+    if let Some(vcd) = &mut machine.session.vcd {
+        if let Err(e) = vcd.flush() {
+            eprintln!("Failed to write VCD: {}", e);
+        }
+    }
+}
+
+// Run the machine until it halts or, when single stepping, the user quits
+fn run(machine: &mut Machine, options: &cli::Options) {
+    let mut pacer = options.pace.map(|scale| Pacer::new(scale, machine.time_ns()));
+    loop {
+        let step = match options.mode {
+            RunMode::InstructionStep => machine.step_instruction().map(|step| println!("{}", step)),
+            _ => machine.step_cycle(),
+        };
+        if let Err(error) = step {
+            let state = machine.state();
+            println!("\nMachine halted: {}", error);
+            println!("UPP = {:o}, PUPP = {:o}, BUPP = {:o}", state.UPP, state.PUPP, state.BUPP);
+            break;
+        }
+
+        // Anything the machine sent to the console
+        let output = machine.take_output();
+        if !output.is_empty() {
+            let mut stdout = std::io::stdout();
+            let _ = stdout.write_all(&output);
+            let _ = stdout.flush();
+        }
 
-                // TODO: Clear switch
+        // Stop on a watchpoint, the state is left as it was at the end of the machine cycle
+        if !machine.session.watch_hits.is_empty() {
+            for hit in machine.session.watch_hits.iter() {
+                println!("\n{}", hit);
             }
+            let state = machine.state();
+            println!("UPP = {:o}, PUPP = {:o}, BUPP = {:o}", state.UPP, state.PUPP, state.BUPP);
+            break;
         }
 
+        // Nothing on the command line can set SWITCH, only a replay can restart a stopped clock
+        let replaying = machine.session.replay.as_ref().is_some_and(|replay| !replay.finished());
+        if machine.clock_stopped() && !replaying {
+            let state = machine.state();
+            println!("\nClock stopped, waiting for SWITCH");
+            println!("UPP = {:o}, PUPP = {:o}, BUPP = {:o}", state.UPP, state.PUPP, state.BUPP);
+            break;
+        }
 
-        // RE-CLOCK
-        //  The next clock cycle must be known before the next machine cycle
-        MACHINE_STATE.CLK_MODE = match &MACHINE_STATE.U_WORD.CLK {
-            6 => &CL3,
-            5 => &CL3,
-            4 => &CL2,
-            3 => &CL2,
-            2 => &CL1,
-            1 => &CL1,
-            _ => panic!("Impossible clock state")
+        // Alow time for the users to watch the simulator, in single step "q" quits
+        match options.mode {
+            RunMode::SingleStep | RunMode::InstructionStep => {
+                let mut line = String::new();
+                let _ = std::io::stdin().read_line(&mut line);
+                if line.trim() == "q" {
+                    break;
+                }
+            },
+            RunMode::FreeRun | RunMode::MaxSpeed => match &mut pacer {
+                Some(pacer) => pacer.pace(machine.time_ns()),
+                None if options.mode == RunMode::FreeRun => thread::sleep(options.cycle_sleep),
+                None => {},
+            },
+            RunMode::Headless | RunMode::Debug => {},
         };
+    }
+}
 
-        // End of machine cycle (logging only!)
-        let elapsed = time_zero.elapsed();
-        println!("Machine cycle elapsed time: {:.2?}\n", elapsed);
+// Machine cycles per headless batch, console output is written out between batches
+const HEADLESS_BATCH: u64 = 65536;
 
-        // The only at the end of the machine cycle can we consider the data output to be stable
-        print_data_bus(MACHINE_STATE.DATA_DISPLAY);
-        print_diagnostic_tool(MACHINE_STATE.PUPP, MACHINE_STATE.BUPP);
+// Run flat out with nothing displayed, then report how far and how fast it got
+fn headless(machine: &mut Machine, options: &cli::Options) {
+    let start = Instant::now();
+    let start_ns = machine.time_ns();
+    let start_cycle = machine.state().CYCLE;
+    let mut cycles = 0;
+    let mut stdout = std::io::stdout();
+    loop {
+        let batch = match options.cycles {
+            Some(limit) if cycles >= limit => break,
+            Some(limit) => (limit - cycles).min(HEADLESS_BATCH),
+            None => HEADLESS_BATCH,
+        };
+        let result = machine.run_headless(batch);
 
-        println!("\nThe values below are that will be used at the start of the next machine cycle");
-        println!("UPP = {:o}", MACHINE_STATE.UPP);
-        println!("CLK = {:?}", MACHINE_STATE.CLK_MODE.mode);
+        let output = machine.take_output();
+        if !output.is_empty() {
+            let _ = stdout.write_all(&output);
+            let _ = stdout.flush();
+        }
 
-        // Alow time for the users to watch the simulator
-        match SINGLE_STEP_EMULATION {
-            true => { let _ = std::io::stdin().read_line(&mut String::new());},
-            false => { thread::sleep(MACHINE_CYCLE_SLEEP);},
-        };
+        match result {
+            Ok(count) => cycles += count,
+            Err(error) => {
+                let state = machine.state();
+                println!("\nMachine halted: {}", error);
+                println!("UPP = {:o}, PUPP = {:o}, BUPP = {:o}", state.UPP, state.PUPP, state.BUPP);
+                break;
+            },
+        }
+        if !machine.session.watch_hits.is_empty() {
+            for hit in machine.session.watch_hits.iter() {
+                println!("\n{}", hit);
+            }
+            break;
+        }
+        let replaying = machine.session.replay.as_ref().is_some_and(|replay| !replay.finished());
+        if machine.clock_stopped() && !replaying {
+            println!("\nClock stopped, waiting for SWITCH");
+            break;
+        }
+    }
+
+    // Machine cycles that finished, one that halts part way isn't counted
+    let cycles = machine.state().CYCLE - start_cycle;
+    let seconds = start.elapsed().as_secs_f64();
+    let emulated_ms = (machine.time_ns() - start_ns) as f64 / 1e6;
+    eprintln!(
+        "{} machine cycles, {:.3} ms emulated in {:.3} s, {:.2} million cycles per second",
+        cycles, emulated_ms, seconds, cycles as f64 / seconds / 1e6,
+    );
+}
+
+fn write_coverage(coverage: &Coverage, ROM: &MicroRom, path: Option<&Path>) {
+    if let Some(path) = path {
+        match coverage.write(path, ROM) {
+            Ok(()) => println!("Coverage written to {}", path.display()),
+            Err(e) => eprintln!("Failed to write coverage: {}", e),
+        }
     }
 }
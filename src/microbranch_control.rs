@@ -1,86 +1,240 @@
+use crate::error::EmulatorError;
 use crate::machinestate::MachineState;
 
-pub fn evaluate_bubc(state: &MachineState) -> u8 {
-    /*
-     * BUBC consists of:
-     * BUBC0 - BUT 17:00 (74LS150)
-     * BUBC0 - BUT 37:20 (74LS150)
-     * BUBC1 - (74LS158)
-     * BUBC2 - (74LS151)
-     * BUBC3 - (74LS151)
-     * BUBC4 - (74LS153)
-     * BUBC5 - (74LS153)
-     */
-
-    let UBF = state.U_WORD.UBF;
-    let UBF_4 = UBF >> 4 & 1;
-    let UBF_0_1_2_3 = UBF & 0b1111; // Used a mux input
+pub fn evaluate_bubc(state: &MachineState) -> Result<u8, EmulatorError> {
+    evaluate_ubf(state.U_WORD.UBF, state)
+}
+
+/*
+ * BUBC consists of:
+ * BUBC0 - BUT 17:00 (74LS150)
+ * BUBC0 - BUT 37:20 (74LS150)
+ * BUBC1 - (74LS158)
+ * BUBC2 - (74LS151)
+ * BUBC3 - (74LS151)
+ * BUBC4 - (74LS153)
+ * BUBC5 - (74LS153)
+ */
+
+// What drives a multiplexer input, the UBF selects one input of each multiplexer
+#[derive(Clone, Copy)]
+enum MuxInput {
+    Low,
+    Switch,
+    DZero,
+    Unimplemented,
+    Unused,
+}
+
+use MuxInput::*;
 
-    let BUBC: u8 = 0;
-    let mut BUBC0: u8 = 0;
-    let mut BUBC1: u8 = 0;
+// E97, BUBC0 for UBF 00-17
+const E97: [MuxInput; 16] = [
+    Low,            // 0 NOOP
+    Unimplemented,  // 1
+    Unimplemented,  // 2
+    Unimplemented,  // 3
+    Unimplemented,  // 4
+    Unimplemented,  // 5
+    Switch,         // 6 BUT(SWITCH)
+    Unimplemented,  // 7
+    Unimplemented,  // 8
+    Unimplemented,  // 9
+    DZero,          // 10 BUT(D=0)
+    Unimplemented,  // 11
+    Unimplemented,  // 12
+    Unimplemented,  // 13
+    Unimplemented,  // 14
+    Unimplemented,  // 15 BUT(IR03)
+];
 
-    // Multiplexers E97 and (E81,E72) are mutually exclusive
+// E81, BUBC0 for UBF 20-37
+const E81: [MuxInput; 16] = [
+    Unimplemented,  // 0 BUT(20)
+    Unimplemented,  // 1
+    Unimplemented,  // 2
+    Unused,         // 3
+    Low,            // 4
+    Unimplemented,  // 5
+    Unimplemented,  // 6
+    Unimplemented,  // 7
+    Unimplemented,  // 8
+    Unimplemented,  // 9
+    Unimplemented,  // 10
+    Unimplemented,  // 11
+    Unimplemented,  // 12
+    Unimplemented,  // 13
+    Unimplemented,  // 14
+    Unimplemented,  // 15
+];
+
+// E72, BUBC1 for UBF 20-37
+const E72: [MuxInput; 16] = [
+    Unimplemented,  // 0
+    Unimplemented,  // 1
+    Unimplemented,  // 2
+    Unused,         // 3
+    Unimplemented,  // 4
+    Unimplemented,  // 5
+    Unimplemented,  // 6
+    Unimplemented,  // 7
+    Unimplemented,  // 8
+    Unimplemented,  // 9
+    Unimplemented,  // 10
+    Unimplemented,  // 11
+    Unimplemented,  // 12
+    Unimplemented,  // 13
+    Unimplemented,  // 14
+    Unimplemented,  // 15
+];
+
+// The multiplexer inputs driving BUBC0 and BUBC1 for a UBF
+// Multiplexers E97 and (E81,E72) are mutually exclusive, E72 is disabled with E97
+fn mux_inputs(UBF: u8) -> [(MuxInput, u8); 2] {
+    let UBF_4 = UBF >> 4 & 1;
+    let UBF_0_1_2_3 = (UBF & 0b1111) as usize; // Used a mux input
     if UBF_4 == 0 {
-        BUBC0 = match UBF_0_1_2_3 {     // E97
-            0b0000 => 0,                // 0 NOOP
-            0b0001 => todo!("E97 1"),   // 1
-            0b0010 => todo!("E97 2"),   // 2
-            0b0011 => todo!("E97 3"),   // 3
-            0b0100 => todo!("E97 4"),   // 4
-            0b0101 => todo!("E97 5"),   // 5
-            0b0110 => state.SWITCH as u8, // 6 BUT(SWITCH)
-            0b0111 => todo!("E97 7"),   // 7
-            0b1000 => todo!("E97 8"),   // 8
-            0b1001 => todo!("E97 9"),   // 9
-            0b1010 => (state.D == 0) as u8,  // 10 BUT(D=0)
-            0b1011 => todo!("E97 11"),  // 11
-            0b1100 => todo!("E97 12"),  // 12
-            0b1101 => todo!("E97 13"),  // 13
-            0b1110 => todo!("E97 14"),  // 14
-            0b1111 => todo!("E97 15"),  // 15 BUT(IR03)
-            _ => panic!("Invalid UBF mux on E97")
-        };
+        [(E97[UBF_0_1_2_3], 0), (Low, 1)]
     } else {
-        BUBC0 = match UBF_0_1_2_3 { // E81
-            0b0000 => todo!("E81 0"),   // 0 BUT(20)
-            0b0001 => todo!("E81 1"),   // 1
-            0b0010 => todo!("E81 2"),   // 2
-            0b0011 => panic!("E81 3 - Not Used"),   // 3
-            0b0100 => 0,                // 4
-            0b0101 => todo!("E81 5"),   // 5
-            0b0110 => todo!("E81 6"),   // 6
-            0b0111 => todo!("E81 7"),   // 7
-            0b1000 => todo!("E81 8"),   // 8
-            0b1001 => todo!("E81 9"),   // 9
-            0b1010 => todo!("E81 10"),  // 10
-            0b1011 => todo!("E81 11"),  // 11
-            0b1100 => todo!("E81 12"),  // 12
-            0b1101 => todo!("E81 13"),  // 13
-            0b1110 => todo!("E81 14"),  // 14
-            0b1111 => todo!("E81 15"),  // 15
-            _ => panic!("Invalid UBF mux on E81")
-        };
-        BUBC1 = match UBF_0_1_2_3 { // E72
-            0b0000 => todo!("E72 0"),   // 0
-            0b0001 => todo!("E72 1"),   // 1
-            0b0010 => todo!("E72 2"),   // 2
-            0b0011 => panic!("E72 3 - Not Used"),   // 3
-            0b0100 => todo!("E72 2"),   // 4
-            0b0101 => todo!("E72 5"),   // 5
-            0b0110 => todo!("E72 6"),   // 6
-            0b0111 => todo!("E72 7"),   // 7
-            0b1000 => todo!("E72 8"),   // 8
-            0b1001 => todo!("E72 9"),   // 9
-            0b1010 => todo!("E72 10"),  // 10
-            0b1011 => todo!("E72 11"),  // 11
-            0b1100 => todo!("E72 12"),  // 12
-            0b1101 => todo!("E72 13"),  // 13
-            0b1110 => todo!("E72 14"),  // 14
-            0b1111 => todo!("E72 15"),  // 15
-            _ => panic!("Invalid UBF mux on E72")
+        [(E81[UBF_0_1_2_3], 0), (E72[UBF_0_1_2_3], 1)]
+    }
+}
+
+// BUBC for a given UBF, the rest of the inputs from the machine state
+pub fn evaluate_ubf(UBF: u8, state: &MachineState) -> Result<u8, EmulatorError> {
+    let mut BUBC: u8 = 0;
+    for (input, bit) in mux_inputs(UBF) {
+        let line = match input {
+            Low => 0,
+            Switch => state.SWITCH as u8,
+            DZero => (state.D == 0) as u8,
+            Unimplemented => return Err(EmulatorError::UnimplementedBut { UBF, UPP: state.UPP }),
+            Unused => return Err(EmulatorError::UnusedBut { UBF, UPP: state.UPP }),
         };
+        BUBC |= line << bit;
+    }
+    Ok(BUBC)
+}
+
+// An error if evaluate_bubc would stop on the UBF of the microword at UPP, whatever the machine state
+pub fn check_ubf(UBF: u8, UPP: u8) -> Result<(), EmulatorError> {
+    for (input, _) in mux_inputs(UBF) {
+        match input {
+            Unimplemented => return Err(EmulatorError::UnimplementedBut { UBF, UPP }),
+            Unused => return Err(EmulatorError::UnusedBut { UBF, UPP }),
+            _ => {},
+        }
+    }
+    Ok(())
+}
+
+// The BUBC lines a UBF sets from SWITCH and from D being zero, so BUBC is
+// SWITCH * switch mask | (D == 0) * D zero mask. None for a UBF evaluate_bubc stops on
+pub fn but_masks(UBF: u8) -> Option<(u8, u8)> {
+    let (mut switch, mut d_zero) = (0, 0);
+    for (input, bit) in mux_inputs(UBF) {
+        match input {
+            Low => {},
+            Switch => switch |= 1 << bit,
+            DZero => d_zero |= 1 << bit,
+            Unimplemented | Unused => return None,
+        }
+    }
+    Some((switch, d_zero))
+}
+
+// Every value evaluate_bubc can produce for a UBF, used to follow the microflow without running it
+// Empty for a UBF evaluate_bubc stops on
+pub fn possible_bubc(UBF: u8) -> Vec<u8> {
+    let mut values = vec![0];
+    for (input, bit) in mux_inputs(UBF) {
+        match input {
+            Low => {},
+            Switch | DZero => {
+                let set: Vec<u8> = values.iter().map(|value| value | 1 << bit).collect();
+                values.extend(set);
+            },
+            Unimplemented | Unused => return Vec::new(),
+        }
+    }
+    values
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::microrom::MICROROM;
+
+    fn state() -> MachineState<'static> {
+        MachineState::new(&MICROROM, 0o44).unwrap()
+    }
+
+    #[test]
+    fn noop() {
+        let state = state();
+        assert_eq!(evaluate_ubf(0o00, &state), Ok(0));
+    }
+
+    #[test]
+    fn but_switch() {
+        let mut state = state();
+        state.SWITCH = false;
+        assert_eq!(evaluate_ubf(0o06, &state), Ok(0));
+        state.SWITCH = true;
+        assert_eq!(evaluate_ubf(0o06, &state), Ok(1));
+    }
+
+    #[test]
+    fn but_d_zero() {
+        let mut state = state();
+        state.D = 0;
+        assert_eq!(evaluate_ubf(0o12, &state), Ok(1));
+        state.D = 0o100000;
+        assert_eq!(evaluate_ubf(0o12, &state), Ok(0));
+    }
+
+    #[test]
+    fn unimplemented_and_unused() {
+        let state = state();
+        // 24 is a no-op on E81 but E72 is not filled in yet
+        for UBF in [0o01, 0o17, 0o20, 0o24, 0o37] {
+            assert_eq!(evaluate_ubf(UBF, &state), Err(EmulatorError::UnimplementedBut { UBF, UPP: 0o44 }), "UBF {:02o}", UBF);
+        }
+        assert_eq!(evaluate_ubf(0o23, &state), Err(EmulatorError::UnusedBut { UBF: 0o23, UPP: 0o44 }));
+    }
+
+    #[test]
+    fn evaluate_bubc_uses_the_u_word() {
+        // Microword 026 is BUT(SWITCH)
+        let mut state = MachineState::new(&MICROROM, 0o26).unwrap();
+        state.SWITCH = true;
+        assert_eq!(evaluate_bubc(&state), Ok(1));
     }
 
-    BUBC | BUBC0 | (BUBC1 << 1)
-}
\ No newline at end of file
+    #[test]
+    fn possible_bubc_matches_evaluate_bubc() {
+        let mut state = state();
+        for UBF in 0..0o40 {
+            let mut produced = Vec::new();
+            let mut stopped = false;
+            for (SWITCH, D) in [(false, 0), (true, 0), (false, 1), (true, 1)] {
+                state.SWITCH = SWITCH;
+                state.D = D;
+                match evaluate_ubf(UBF, &state) {
+                    Ok(BUBC) if !produced.contains(&BUBC) => produced.push(BUBC),
+                    Ok(_) => {},
+                    Err(_) => stopped = true,
+                }
+            }
+            produced.sort();
+            let mut possible = possible_bubc(UBF);
+            possible.sort();
+            if stopped {
+                assert!(produced.is_empty() && possible.is_empty(), "UBF {:02o}", UBF);
+            } else {
+                assert_eq!(possible, produced, "UBF {:02o}", UBF);
+            }
+        }
+    }
+}
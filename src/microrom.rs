@@ -1,6 +1,6 @@
 #![allow(non_snake_case)]
 
-use std::ops::Index;
+use crate::error::EmulatorError;
 
 #[allow(dead_code)]
 #[derive(Debug)]
@@ -66,19 +66,42 @@ pub struct MicroWord  {
 #[derive(Debug)]
 pub struct MicroRom([MicroWord; 256]);
 
-impl Index<usize> for MicroRom {
-    type Output = MicroWord;
-    
-    // This will trigger a panic if an unimplemented micoword is addressed
-    fn index(&self, index: usize) -> &Self::Output {
-        let output = &self.0[index];
+impl MicroRom {
+    pub fn new(words: [MicroWord; 256]) -> MicroRom {
+        MicroRom(words)
+    }
+
+    /// Every microword in address order, implemented or not
+    pub fn words(&self) -> &[MicroWord; 256] {
+        &self.0
+    }
+
+    /// The microword the UPP points to, an unimplemented microword is an error
+    pub fn fetch(&self, UPP: u8, PUPP: u8) -> Result<&MicroWord, EmulatorError> {
+        let output = &self.0[UPP as usize];
         if !output.implemented {
-            todo!("Micoword: {:o}", index);
+            return Err(EmulatorError::UnimplementedMicroword { UPP, PUPP });
         }
-        output
+        Ok(output)
     }
 }
 
+/// Flow chart names and comments for the transcribed microwords, by address
+pub static MICROWORD_LABELS: &[(u8, &str, &str)] = &[
+    (0o026, "CON04", "TEST FOR SWITCH"),
+    (0o027, "CON07", "CONTACT BOUNCE COUNT"),
+    (0o044, "CON08", "TEST COUNT"),
+    (0o046, "CONS06", "NO-OP FOR BUT"),
+    (0o047, "CON09", "INCREMENT COUNT"),
+];
+
+/// The flow chart name and comment of a microword, if it has one
+pub fn microword_label(UPP: u8) -> Option<(&'static str, &'static str)> {
+    MICROWORD_LABELS.iter()
+        .find(|(address, _, _)| *address == UPP)
+        .map(|(_, name, comment)| (*name, *comment))
+}
+
 pub static MICROROM: MicroRom = MicroRom([
     MicroWord { implemented: true, CLK: 2, CIR: 0, WR: 0, CB: 0, CD: 0, CBA: 0, BUS: 0, DAD: 0, SPS: 0, ALU: 0, SBC: 0, SBM: 0, SDM: 0, SBA: 0, UBF: 0, SRX: 0, RIF: 0, UPF: 0o26 }, // 000 000
     MicroWord { implemented: false, CLK: 0, CIR: 0, WR: 0, CB: 0, CD: 0, CBA: 0, BUS: 0, DAD: 0, SPS: 0, ALU: 0, SBC: 0, SBM: 0, SDM: 0, SBA: 0, UBF: 0, SRX: 0, RIF: 0, UPF: 0 }, // 001 001
@@ -0,0 +1,157 @@
+#![allow(non_snake_case)]
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Real-time pacing, holds the emulator back so emulated time keeps up with wall time times scale
+// 1.0 is the speed of a real 11/40, 0.001 a thousand times slower. The pacer only ever waits,
+// an emulator too slow to keep up runs flat out and the scale is not reached.
+
+// Sleeping for less than this is not accurate enough to be worth doing, wait until further ahead
+const MIN_SLEEP: Duration = Duration::from_millis(1);
+
+// Falling further behind than this (a debugger pause, a slow host) starts the pacing again
+// from now, rather than racing to catch up
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+// The host's time, so the pacing can be tested without sleeping
+pub trait HostClock {
+    // Time since some fixed point, only differences are used
+    fn now(&self) -> Duration;
+    fn sleep(&mut self, duration: Duration);
+}
+
+pub struct SystemClock {
+    epoch: Instant,
+}
+
+impl HostClock for SystemClock {
+    fn now(&self) -> Duration {
+        self.epoch.elapsed()
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+pub struct Pacer<C: HostClock = SystemClock> {
+    scale: f64,
+    clock: C,
+    start: Duration,
+    start_ns: u64,
+}
+
+impl Pacer {
+    pub fn new(scale: f64, TIME_NS: u64) -> Pacer {
+        Pacer::with_clock(scale, TIME_NS, SystemClock { epoch: Instant::now() })
+    }
+}
+
+impl<C: HostClock> Pacer<C> {
+    pub fn with_clock(scale: f64, TIME_NS: u64, clock: C) -> Pacer<C> {
+        let start = clock.now();
+        Pacer { scale, clock, start, start_ns: TIME_NS }
+    }
+
+    // Call after each machine cycle with the emulated time
+    pub fn pace(&mut self, TIME_NS: u64) {
+        let target = Duration::from_secs_f64((TIME_NS - self.start_ns) as f64 / self.scale / 1e9);
+        let elapsed = self.clock.now() - self.start;
+        if target > elapsed + MIN_SLEEP {
+            self.clock.sleep(target - elapsed);
+        } else if elapsed > target + MAX_LAG {
+            self.reset(TIME_NS);
+        }
+    }
+
+    pub fn reset(&mut self, TIME_NS: u64) {
+        self.start = self.clock.now();
+        self.start_ns = TIME_NS;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: u64 = 1_000_000;
+
+    // Host time only moves when the test moves it or the pacer sleeps
+    #[derive(Default)]
+    struct TestClock {
+        now: Duration,
+        sleeps: Vec<Duration>,
+    }
+
+    impl HostClock for TestClock {
+        fn now(&self) -> Duration {
+            self.now
+        }
+
+        fn sleep(&mut self, duration: Duration) {
+            self.now += duration;
+            self.sleeps.push(duration);
+        }
+    }
+
+    fn test_pacer(scale: f64) -> Pacer<TestClock> {
+        Pacer::with_clock(scale, 0, TestClock::default())
+    }
+
+    fn sleeps(pacer: &mut Pacer<TestClock>) -> Vec<Duration> {
+        std::mem::take(&mut pacer.clock.sleeps)
+    }
+
+    #[test]
+    fn waits_for_wall_time() {
+        let mut pacer = test_pacer(1.0);
+        pacer.pace(10 * MS);
+        assert_eq!(sleeps(&mut pacer), [Duration::from_millis(10)]);
+
+        // Less than MIN_SLEEP ahead waits for more
+        pacer.pace(10 * MS + MS / 2);
+        pacer.pace(11 * MS);
+        assert!(sleeps(&mut pacer).is_empty());
+        pacer.pace(12 * MS);
+        assert_eq!(sleeps(&mut pacer), [Duration::from_millis(2)]);
+
+        // A thousand times slower
+        let mut slower = test_pacer(0.001);
+        slower.pace(5_000);
+        assert_eq!(sleeps(&mut slower), [Duration::from_millis(5)]);
+    }
+
+    #[test]
+    fn catches_up_within_max_lag() {
+        let mut pacer = test_pacer(1.0);
+        pacer.clock.now = Duration::from_millis(90);
+
+        // Behind, runs on without waiting until emulated time is ahead again
+        pacer.pace(20 * MS);
+        pacer.pace(80 * MS);
+        assert!(sleeps(&mut pacer).is_empty());
+        assert_eq!((pacer.start, pacer.start_ns), (Duration::ZERO, 0));
+        pacer.pace(100 * MS);
+        assert_eq!(sleeps(&mut pacer), [Duration::from_millis(10)]);
+
+        // Exactly MAX_LAG behind still catches up
+        pacer.clock.now = Duration::from_millis(300);
+        pacer.pace(200 * MS);
+        assert_eq!((pacer.start, pacer.start_ns), (Duration::ZERO, 0));
+    }
+
+    #[test]
+    fn resets_past_max_lag() {
+        let mut pacer = test_pacer(1.0);
+        pacer.clock.now = Duration::from_millis(250);
+
+        // A pause, pacing starts again from here rather than running flat out for 230 ms
+        pacer.pace(20 * MS);
+        assert!(sleeps(&mut pacer).is_empty());
+        assert_eq!((pacer.start, pacer.start_ns), (Duration::from_millis(250), 20 * MS));
+        pacer.pace(30 * MS);
+        assert_eq!(sleeps(&mut pacer), [Duration::from_millis(10)]);
+        assert_eq!(pacer.clock.now, Duration::from_millis(260));
+    }
+}
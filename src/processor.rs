@@ -0,0 +1,485 @@
+#![allow(non_snake_case)]
+
+use std::io;
+use std::time::Instant;
+
+use crate::alu;
+use crate::bconstant;
+use crate::clock::{self, ClockMode, ClockPulse, Edge, CL3};
+use crate::coverage::Coverage;
+use crate::data_path;
+use crate::decode::{DecodedRom, DecodedWord};
+use crate::disassembler;
+use crate::debug::{print_data_bus, print_diagnostic_tool};
+use crate::error::EmulatorError;
+use crate::machinestate::{MachineState, Processor};
+use crate::microbranch_control;
+use crate::replay::{InputLog, Replay};
+use crate::trace::{TraceRecord, Tracer};
+use crate::unibus::{self, BusCycle};
+use crate::vcd::VcdWriter;
+use crate::watch::Watchpoints;
+
+// Everything the front end hangs off the processor while it runs
+pub struct Session {
+    pub coverage: Option<Coverage>,
+    pub tracer: Option<Tracer>,
+    pub vcd: Option<VcdWriter>,
+
+    pub watchpoints: Watchpoints,
+
+    // The microROM pre-decoded, when set run_cycles runs whole machine cycles from it where it can
+    pub decoded: Option<Box<DecodedRom>>,
+
+    // External inputs, logged as they are applied or fed back from a log
+    pub recording: Option<InputLog>,
+    pub replay: Option<Replay>,
+
+    // Watchpoints that triggered at the end of the last machine cycle
+    pub watch_hits: Vec<String>,
+
+    // Print the state of the machine after every clock pulse and machine cycle
+    pub display: bool,
+
+    // Bookkeeping for the machine cycle in progress
+    pub cycle_start: Option<Instant>,
+    pub record: Option<TraceRecord>,
+}
+
+impl Session {
+    pub fn new(display: bool) -> Session {
+        Session {
+            coverage: None,
+            tracer: None,
+            vcd: None,
+            watchpoints: Watchpoints::new(),
+            decoded: None,
+            recording: None,
+            replay: None,
+            watch_hits: Vec::new(),
+            display,
+            cycle_start: None,
+            record: None,
+        }
+    }
+}
+
+// Per cycle output, only when the session is displaying
+macro_rules! display {
+    ($session:expr, $($arg:tt)*) => {
+        if $session.display {
+            println!($($arg)*);
+        }
+    };
+}
+
+// Run one machine cycle, an error stops the cycle part way through
+pub fn step_cycle(MACHINE_STATE: &mut MachineState, session: &mut Session) -> Result<(), EmulatorError> {
+    while !step_pulse(MACHINE_STATE, session)? {}
+    Ok(())
+}
+
+// Run up to max_cycles machine cycles, returns the number run
+// Stops early after a cycle that stops the clock or triggers a watchpoint
+pub fn run_cycles(MACHINE_STATE: &mut MachineState, session: &mut Session, max_cycles: u64) -> Result<u64, EmulatorError> {
+    for cycle in 1..=max_cycles {
+        if !run_decoded_cycle(MACHINE_STATE, session) {
+            step_cycle(MACHINE_STATE, session)?;
+        }
+        if MACHINE_STATE.CLK_MODE.mode == ClockMode::OFF || !session.watch_hits.is_empty() {
+            return Ok(cycle);
+        }
+    }
+    Ok(max_cycles)
+}
+
+// Run the next clock pulse, returns true once it completes a machine cycle
+// Each machine cycle may have either one or two clock pulses
+pub fn step_pulse(MACHINE_STATE: &mut MachineState, session: &mut Session) -> Result<bool, EmulatorError> {
+    if let Some(replay) = &mut session.replay {
+        replay.apply_due(MACHINE_STATE);
+    }
+    // Devices run the events due by now, ahead of the processor's next pulse
+    MACHINE_STATE.UNIBUS.run_events(MACHINE_STATE.TIME_NS);
+
+    // An error takes the whole pulse back, the processor is left as it was before it
+    // Everything that can fail at the end of a machine cycle is checked before the bus cycle
+    // (check_microprogram), so memory and the devices never see a pulse that is taken back
+    let before = Processor::capture(MACHINE_STATE);
+    let first = MACHINE_STATE.PULSE == 0;
+    let result = run_pulse(MACHINE_STATE, session);
+    if result.is_err() {
+        before.apply(MACHINE_STATE);
+        if first {
+            session.record = None;
+        }
+    }
+    result
+}
+
+fn run_pulse(MACHINE_STATE: &mut MachineState, session: &mut Session) -> Result<bool, EmulatorError> {
+    // With the clock stopped no microwords run, each pulse is an idle wait for the console
+    // Setting SWITCH (a console switch) restarts it at the UPP it stopped at, the microword
+    // whose CLK stopped it is already in the U WORD register and runs next. Its CLK gives no
+    // length, so that machine cycle is the longest, CL3
+    if MACHINE_STATE.CLK_MODE.mode == ClockMode::OFF {
+        if !MACHINE_STATE.SWITCH {
+            MACHINE_STATE.TIME_NS += MACHINE_STATE.CLK_MODE.pulse_ns[0];
+            write_vcd(session, |vcd| vcd.sample(MACHINE_STATE));
+            return Ok(true);
+        }
+        MACHINE_STATE.CLK_MODE = &CL3;
+        display!(session, "Clock restarted by SWITCH");
+    }
+    if MACHINE_STATE.PULSE == 0 {
+        start_cycle(MACHINE_STATE, session);
+    }
+
+    // The pulse and bus lines the dump needs, from before the pulse latches the next microword
+    let clock = MACHINE_STATE.CLK_MODE;
+    let pulse = MACHINE_STATE.PULSE as usize;
+    let pulse_ns = MACHINE_STATE.TIME_NS + clock.pulse_ns[pulse];
+    let BUS = MACHINE_STATE.U_WORD.BUS;
+
+    clock_pulse(MACHINE_STATE, session)?;
+    MACHINE_STATE.PULSE += 1;
+    if MACHINE_STATE.PULSE < MACHINE_STATE.CLK_MODE.pulse_count {
+        write_vcd(session, |vcd| vcd.pulse(MACHINE_STATE, &clock.pulses[pulse], pulse_ns, BUS));
+        return Ok(false);
+    }
+
+    MACHINE_STATE.PULSE = 0;
+    end_cycle(MACHINE_STATE, session)?;
+    // The last pulse reclocks, the dump has the clock mode for the next cycle change with it
+    write_vcd(session, |vcd| vcd.pulse(MACHINE_STATE, &clock.pulses[pulse], pulse_ns, BUS));
+    Ok(true)
+}
+
+// Write to the signal dump if there is one, a failed write stops dumping
+fn write_vcd(session: &mut Session, write: impl FnOnce(&mut VcdWriter) -> io::Result<()>) {
+    if let Some(vcd) = &mut session.vcd {
+        if let Err(e) = write(vcd) {
+            eprintln!("Failed to write VCD, dump stopped: {}", e);
+            session.vcd = None;
+        }
+    }
+}
+
+fn start_cycle(MACHINE_STATE: &mut MachineState, session: &mut Session) {
+    // Set up next machine cycle
+    if session.display {
+        print!("{esc}[2J{esc}[1;1H", esc = 27 as char); // Clear the screen
+    }
+
+    display!(session, "New Machine Cycle");
+    display!(session, "Current UPP = {:o}", MACHINE_STATE.UPP);
+    display!(session, "Current clk = {:?}", MACHINE_STATE.CLK_MODE.mode);
+
+    if session.display {
+        session.cycle_start = Some(Instant::now()); // Time = 0, start of machine cycle
+    }
+
+    if session.tracer.is_some() {
+        session.record = Some(TraceRecord::start(MACHINE_STATE));
+    }
+    MACHINE_STATE.REGISTER_WRITE = None;
+    MACHINE_STATE.BUS_ACCESS = None;
+}
+
+// A part of the processor clocked by the timing generator
+struct Clocked {
+    edge: Edge,
+    clock: fn(&mut MachineState, &mut Session) -> Result<(), EmulatorError>,
+}
+
+// Everything the timing generator clocks, in the order it is clocked on each pulse
+// New clocked hardware subscribes to its edge here, devices schedule timed events on the Unibus
+const CLOCKED: [Clocked; 6] = [
+    Clocked { edge: Edge::Every, clock: settle_data_path },
+    Clocked { edge: Edge::CycleEnd, clock: check_microprogram },
+    Clocked { edge: Edge::CycleEnd, clock: clock_registers },
+    Clocked { edge: Edge::CycleEnd, clock: clock_bus },
+    Clocked { edge: Edge::CycleEnd, clock: clock_ir },
+    Clocked { edge: Edge::CycleEnd, clock: clock_microprogram },
+];
+
+fn clock_pulse(MACHINE_STATE: &mut MachineState, session: &mut Session) -> Result<(), EmulatorError> {
+    let pulse = MACHINE_STATE.PULSE;
+    let active_pulse = &MACHINE_STATE.CLK_MODE.pulses[pulse as usize];
+
+    // Add clock delay
+    MACHINE_STATE.TIME_NS += MACHINE_STATE.CLK_MODE.pulse_ns[pulse as usize];
+    match *active_pulse {
+        ClockPulse::NONE => unreachable!("a stopped clock has no pulses, step_pulse waits instead"),
+        ClockPulse::P1 => display!(session, "Delay {}ns, pulse P1", MACHINE_STATE.CLK_MODE.pulse_ns[pulse as usize]),
+        ClockPulse::P2 => display!(session, "Delay {}ns, pulse P2", MACHINE_STATE.CLK_MODE.pulse_ns[pulse as usize]),
+        ClockPulse::P3 => display!(session, "Delay {}ns, pulse P3", MACHINE_STATE.CLK_MODE.pulse_ns[pulse as usize]),
+    }
+
+    for part in CLOCKED.iter() {
+        if MACHINE_STATE.CLK_MODE.fires(&part.edge, pulse) {
+            (part.clock)(MACHINE_STATE, session)?;
+        }
+    }
+    Ok(())
+}
+
+// Evaluate the data path, everything here settles before the clock pulse
+fn settle_data_path(MACHINE_STATE: &mut MachineState, session: &mut Session) -> Result<(), EmulatorError> {
+    // For the next 140ms to 300ms the cpu will do stuff
+    display!(session, "Current UBF = {:o}", MACHINE_STATE.U_WORD.UBF);
+
+    // TODO: SRX selects between RIF and the other register address sources, only RIF for now
+    let BUS_RD = MACHINE_STATE.REGISTERS[MACHINE_STATE.U_WORD.RIF as usize];
+    let bConst = bconstant::evaluate_bconstant(MACHINE_STATE, MACHINE_STATE.U_WORD.SBC)?;
+    let B_MUX = data_path::evaluate_bmux(MACHINE_STATE.U_WORD.SBM, MACHINE_STATE.B, bConst);
+    let ALU = alu::evaluate_alu(MACHINE_STATE.U_WORD.ALU, BUS_RD, B_MUX, false);
+    let D_MUX = data_path::evaluate_dmux(MACHINE_STATE.U_WORD.SDM, BUS_RD, MACHINE_STATE.BUS_D, MACHINE_STATE.D, 0);
+
+    display!(session, "D_MUX: {:b}", D_MUX);
+    display!(session, "B Const {}", bConst);
+    display!(session, "B MUX {}", B_MUX);
+    display!(session, "ALU {}", ALU);
+    MACHINE_STATE.B_MUX = B_MUX;
+    MACHINE_STATE.D_MUX = D_MUX;
+    MACHINE_STATE.ALU = ALU;
+    MACHINE_STATE.DATA_DISPLAY = D_MUX;
+    Ok(())
+}
+
+// Clock the data path registers from this machine cycle's outputs
+fn clock_registers(MACHINE_STATE: &mut MachineState, _session: &mut Session) -> Result<(), EmulatorError> {
+    // WRL and WRH write the low and high bytes of the DMUX into the scratchpad
+    let WR = MACHINE_STATE.U_WORD.WR;
+    if WR != 0 {
+        let address = MACHINE_STATE.U_WORD.RIF;
+        let mut value = MACHINE_STATE.REGISTERS[address as usize];
+        if WR & 0b01 != 0 {
+            value = (value & 0xFF00) | (MACHINE_STATE.D_MUX & 0x00FF);
+        }
+        if WR & 0b10 != 0 {
+            value = (value & 0x00FF) | (MACHINE_STATE.D_MUX & 0xFF00);
+        }
+        MACHINE_STATE.REGISTERS[address as usize] = value;
+        MACHINE_STATE.REGISTER_WRITE = Some((address, value));
+    }
+    if MACHINE_STATE.U_WORD.CD == 1 {
+        MACHINE_STATE.D = MACHINE_STATE.ALU;
+    }
+    if MACHINE_STATE.U_WORD.CB == 1 {
+        MACHINE_STATE.B = MACHINE_STATE.D_MUX;
+    }
+
+    // TODO: SBA selects the bus address source, only the ALU for now
+    if MACHINE_STATE.U_WORD.CBA == 1 {
+        MACHINE_STATE.BA = MACHINE_STATE.ALU;
+    }
+    Ok(())
+}
+
+// BGBUS starts a Unibus cycle of the type set by C1BUS and C0BUS at BA, 160000 up is the I/O page
+// DATO and DATOB drive the D register onto the bus
+// The clock stops until SSYN, the transfer completes at once and the wait is added to the time
+fn clock_bus(MACHINE_STATE: &mut MachineState, _session: &mut Session) -> Result<(), EmulatorError> {
+    let BUS = MACHINE_STATE.U_WORD.BUS;
+    if BUS & 0b001 == 0 {
+        return Ok(());
+    }
+    let cycle = match BUS >> 1 {
+        0b00 => BusCycle::DATI,
+        0b01 => BusCycle::DATIP,
+        0b10 => BusCycle::DATO,
+        _ => BusCycle::DATOB,
+    };
+    let address = unibus::bus_address(MACHINE_STATE.BA);
+    MACHINE_STATE.BUS_ACCESS = Some((cycle, address));
+    MACHINE_STATE.TIME_NS += MACHINE_STATE.UNIBUS.wait_ns(address, cycle);
+    let timeout = |_| EmulatorError::BusTimeout { address, UPP: MACHINE_STATE.UPP };
+    match cycle {
+        BusCycle::DATI | BusCycle::DATIP => {
+            MACHINE_STATE.BUS_D = MACHINE_STATE.UNIBUS.read(address).map_err(timeout)?;
+        },
+        BusCycle::DATO | BusCycle::DATOB => {
+            let byte = cycle == BusCycle::DATOB;
+            MACHINE_STATE.UNIBUS.write(address, MACHINE_STATE.D, byte).map_err(timeout)?;
+        },
+    }
+    Ok(())
+}
+
+// CLKIR loads the instruction register from the Unibus data, this is the instruction boundary
+fn clock_ir(MACHINE_STATE: &mut MachineState, _session: &mut Session) -> Result<(), EmulatorError> {
+    if MACHINE_STATE.U_WORD.CIR == 1 {
+        MACHINE_STATE.IR = MACHINE_STATE.BUS_D;
+        MACHINE_STATE.IR_PC = MACHINE_STATE.BA;
+    }
+    Ok(())
+}
+
+// The next microword has to be there, with a BUT and a clock the emulator knows, before
+// anything is clocked at the end of the machine cycle
+// An unimplemented microword counts as reached in the coverage, though the pulse is taken back
+fn check_microprogram(MACHINE_STATE: &mut MachineState, session: &mut Session) -> Result<(), EmulatorError> {
+    let UPP = MACHINE_STATE.U_WORD.UPF | MACHINE_STATE.BUBC;
+    let word = MACHINE_STATE.ROM.fetch(UPP, MACHINE_STATE.UPP).inspect_err(|_| {
+        if let Some(coverage) = &mut session.coverage {
+            coverage.record_word(UPP);
+        }
+    })?;
+    microbranch_control::check_ubf(word.UBF, UPP)?;
+    clock::reclock(word.CLK, false, UPP)?;
+    Ok(())
+}
+
+// At the end of the machine cycle latch the UWORD from the ROM
+fn clock_microprogram(MACHINE_STATE: &mut MachineState, session: &mut Session) -> Result<(), EmulatorError> {
+    // Latch UPP into PUPP
+    MACHINE_STATE.PUPP = MACHINE_STATE.UPP;
+
+    // Compute next UPP based off the content of BUBC from the last machine cycle
+    MACHINE_STATE.UPP = MACHINE_STATE.U_WORD.UPF | MACHINE_STATE.BUBC; // Latch in the next UPP
+
+    // At the start of the next machine cycle BUPP/UPP will point the the "current" microstate.
+    MACHINE_STATE.BUPP = MACHINE_STATE.UPP;
+
+    // Clock so latch in new microword
+    MACHINE_STATE.U_WORD = MACHINE_STATE.ROM.fetch(MACHINE_STATE.UPP, MACHINE_STATE.PUPP)?;
+
+    if let Some(coverage) = &mut session.coverage {
+        coverage.record_word(MACHINE_STATE.UPP);
+    }
+
+    // The BUBC lines are in flux for one machine cycle, we save that state in BUBC_NEXT
+    MACHINE_STATE.BUBC = MACHINE_STATE.BUBC_FLUX;
+    // evaluate_bubc must be called at the end of the machine cycle to allow time for BUTs to settle
+    MACHINE_STATE.BUBC_FLUX = microbranch_control::evaluate_bubc(MACHINE_STATE)?;
+    if let Some(coverage) = &mut session.coverage {
+        if MACHINE_STATE.U_WORD.UBF != 0 {
+            coverage.record_branch(MACHINE_STATE.UPP, MACHINE_STATE.BUBC_FLUX);
+        }
+    }
+
+    // TODO: Clear switch
+    Ok(())
+}
+
+fn end_cycle(MACHINE_STATE: &mut MachineState, session: &mut Session) -> Result<(), EmulatorError> {
+    // RE-CLOCK
+    //  The next clock cycle must be known before the next machine cycle
+    let bus_access = MACHINE_STATE.BUS_ACCESS.is_some();
+    MACHINE_STATE.CLK_MODE = clock::reclock(MACHINE_STATE.U_WORD.CLK, bus_access, MACHINE_STATE.UPP)?;
+
+    // End of machine cycle (logging only!)
+    if let Some(time_zero) = session.cycle_start.take() {
+        display!(session, "Machine cycle elapsed time: {:.2?}, emulated time {}ns\n", time_zero.elapsed(), MACHINE_STATE.TIME_NS);
+    }
+
+    // The only at the end of the machine cycle can we consider the data output to be stable
+    if session.display {
+        print_data_bus(MACHINE_STATE.DATA_DISPLAY);
+        print_diagnostic_tool(MACHINE_STATE.PUPP, MACHINE_STATE.BUPP);
+        let (instruction, _) = disassembler::disassemble_instruction(&MACHINE_STATE.UNIBUS, MACHINE_STATE.IR, MACHINE_STATE.IR_PC);
+        println!("IR = {:06o}  {:06o}: {}", MACHINE_STATE.IR, MACHINE_STATE.IR_PC, instruction);
+    }
+
+    display!(session, "\nThe values below are that will be used at the start of the next machine cycle");
+    display!(session, "UPP = {:o}", MACHINE_STATE.UPP);
+    display!(session, "CLK = {:?}", MACHINE_STATE.CLK_MODE.mode);
+
+    finish_cycle(MACHINE_STATE, session);
+    Ok(())
+}
+
+// Count the machine cycle, check the watchpoints and write its trace record
+fn finish_cycle(MACHINE_STATE: &mut MachineState, session: &mut Session) {
+    MACHINE_STATE.CYCLE += 1;
+
+    session.watch_hits.clear();
+    if !session.watchpoints.list.is_empty() {
+        session.watch_hits = session.watchpoints.check(MACHINE_STATE);
+    }
+
+    if let (Some(tracer), Some(mut record)) = (&mut session.tracer, session.record.take()) {
+        record.finish(MACHINE_STATE);
+        if let Err(e) = tracer.record(&record) {
+            eprintln!("Failed to write trace, tracing stopped: {}", e);
+            session.tracer = None;
+        }
+    }
+}
+
+// Run a whole machine cycle from the pre-decoded microROM, false if it has to go a pulse at a
+// time through step_cycle instead
+// That is a cycle part way through or with the clock stopped, a session displaying, tracing,
+// dumping or replaying, and a cycle that would stop the machine. A failed bus cycle is taken
+// back and run again by step_cycle, which stops the machine the same way.
+fn run_decoded_cycle(MACHINE_STATE: &mut MachineState, session: &mut Session) -> bool {
+    let Some(decoded) = &session.decoded else {
+        return false;
+    };
+    if MACHINE_STATE.PULSE != 0 || MACHINE_STATE.CLK_MODE.mode == ClockMode::OFF || session.display
+        || session.tracer.is_some() || session.vcd.is_some() || session.replay.is_some() {
+        return false;
+    }
+    let word: DecodedWord = *decoded.word(MACHINE_STATE.UPP);
+    let Ok(bConst) = word.bconstant[MACHINE_STATE.SINCLK as usize] else {
+        return false;
+    };
+    let UPP = MACHINE_STATE.U_WORD.UPF | MACHINE_STATE.BUBC;
+    let Some(next) = decoded.word(UPP).next else {
+        return false;
+    };
+
+    let before = word.bus.then(|| Processor::capture(MACHINE_STATE));
+    MACHINE_STATE.REGISTER_WRITE = None;
+    MACHINE_STATE.BUS_ACCESS = None;
+
+    // Nothing the data path reads is clocked before the end of the cycle, so it settles once
+    let BUS_RD = MACHINE_STATE.REGISTERS[word.RIF as usize];
+    let B_MUX = match word.bmux[MACHINE_STATE.SINCLK as usize] {
+        Some(B_MUX) => B_MUX,
+        None => data_path::evaluate_bmux(word.SBM, MACHINE_STATE.B, bConst),
+    };
+    MACHINE_STATE.B_MUX = B_MUX;
+    MACHINE_STATE.ALU = alu::evaluate_alu(word.ALU, BUS_RD, B_MUX, false);
+    MACHINE_STATE.D_MUX = data_path::evaluate_dmux(word.SDM, BUS_RD, MACHINE_STATE.BUS_D, MACHINE_STATE.D, 0);
+    MACHINE_STATE.DATA_DISPLAY = MACHINE_STATE.D_MUX;
+
+    let clock = MACHINE_STATE.CLK_MODE;
+    for pulse in 0..clock.pulse_count as usize {
+        MACHINE_STATE.UNIBUS.run_events(MACHINE_STATE.TIME_NS);
+        MACHINE_STATE.TIME_NS += clock.pulse_ns[pulse];
+    }
+
+    if word.clocks_registers {
+        let _ = clock_registers(MACHINE_STATE, session);
+    }
+    if let Some(before) = before {
+        if clock_bus(MACHINE_STATE, session).is_err() {
+            before.apply(MACHINE_STATE);
+            return false;
+        }
+    }
+    if word.CIR {
+        let _ = clock_ir(MACHINE_STATE, session);
+    }
+
+    // Latch the next microword, it is known to be there with a BUT and a clock the emulator knows
+    MACHINE_STATE.PUPP = MACHINE_STATE.UPP;
+    MACHINE_STATE.UPP = UPP;
+    MACHINE_STATE.BUPP = UPP;
+    MACHINE_STATE.U_WORD = &MACHINE_STATE.ROM.words()[UPP as usize];
+    MACHINE_STATE.BUBC = MACHINE_STATE.BUBC_FLUX;
+    MACHINE_STATE.BUBC_FLUX = (next.switch_mask * MACHINE_STATE.SWITCH as u8)
+        | (next.d_zero_mask * (MACHINE_STATE.D == 0) as u8);
+    if let Some(coverage) = &mut session.coverage {
+        coverage.record_word(UPP);
+        if MACHINE_STATE.U_WORD.UBF != 0 {
+            coverage.record_branch(UPP, MACHINE_STATE.BUBC_FLUX);
+        }
+    }
+    MACHINE_STATE.CLK_MODE = next.clock[MACHINE_STATE.BUS_ACCESS.is_some() as usize];
+
+    finish_cycle(MACHINE_STATE, session);
+    true
+}
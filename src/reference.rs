@@ -0,0 +1,767 @@
+#![allow(non_snake_case)]
+
+// Instruction level PDP-11/40 model, what the microcode should do seen from the programmer's side
+// It runs a whole instruction at a time with none of the KD11-A's internals, so the microcode
+// emulator can be checked against it instruction by instruction (see differential).
+// Covers the basic instruction set, EIS and MFPI/MTPI without memory management. Memory is
+// words from 0 up, the only I/O page register is the PSW at 777776, anything else traps to 4.
+// TODO: FIS, stack limit, T bit traps and interrupts
+
+pub const PSW_ADDRESS: u16 = 0o177776;
+
+const N: u16 = 0o10;
+const Z: u16 = 0o4;
+const V: u16 = 0o2;
+const C: u16 = 0o1;
+
+// The flag if set is true, for building up condition codes
+fn flag(set: bool, flag: u16) -> u16 {
+    if set { flag } else { 0 }
+}
+
+const SP: usize = 6;
+const PC: usize = 7;
+
+// Trap vectors
+pub const BUS_ERROR: u16 = 0o4;
+pub const RESERVED_INSTRUCTION: u16 = 0o10;
+pub const BPT_VECTOR: u16 = 0o14;
+pub const IOT_VECTOR: u16 = 0o20;
+pub const EMT_VECTOR: u16 = 0o30;
+pub const TRAP_VECTOR: u16 = 0o34;
+
+// What one instruction did
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Ran,
+    // Trapped through the vector, the trap has been taken
+    Trap(u16),
+    Halt,
+    Wait,
+}
+
+// Where an operand is
+#[derive(Clone, Copy)]
+enum Location {
+    Register(usize),
+    Memory(u16),
+}
+
+pub struct Reference {
+    pub REGISTERS: [u16; 8],
+    pub PSW: u16,
+    pub memory: Vec<u16>,
+
+    // The instruction wrote the PSW through its address, that wins over the flags it sets
+    psw_written: bool,
+}
+
+impl Reference {
+    pub fn new(memory_words: usize) -> Reference {
+        Reference {
+            REGISTERS: [0; 8],
+            PSW: 0,
+            memory: vec![0; memory_words.min(0o160000 / 2)],
+            psw_written: false,
+        }
+    }
+
+    // Run one instruction, taking any trap it causes
+    pub fn step(&mut self) -> Outcome {
+        match self.execute() {
+            Ok(outcome) => outcome,
+            Err(vector) => self.trap(vector),
+        }
+    }
+
+    pub fn read(&self, address: u16, byte: bool) -> Result<u16, u16> {
+        let word = match address & !1 {
+            PSW_ADDRESS => self.PSW,
+            even => *self.memory.get((even / 2) as usize).ok_or(BUS_ERROR)?,
+        };
+        match (byte, address & 1) {
+            (false, 0) => Ok(word),
+            (false, _) => Err(BUS_ERROR),
+            (true, 0) => Ok(word & 0o377),
+            (true, _) => Ok(word >> 8),
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u16, byte: bool) -> Result<(), u16> {
+        if !byte && address & 1 != 0 {
+            return Err(BUS_ERROR);
+        }
+        let word = match address & !1 {
+            PSW_ADDRESS => {
+                self.psw_written = true;
+                &mut self.PSW
+            },
+            even => self.memory.get_mut((even / 2) as usize).ok_or(BUS_ERROR)?,
+        };
+        *word = match (byte, address & 1) {
+            (false, _) => value,
+            (true, 0) => (*word & 0o177400) | (value & 0o377),
+            (true, _) => (*word & 0o377) | (value << 8),
+        };
+        Ok(())
+    }
+
+    fn fetch(&mut self) -> Result<u16, u16> {
+        let word = self.read(self.REGISTERS[PC], false)?;
+        self.REGISTERS[PC] = self.REGISTERS[PC].wrapping_add(2);
+        Ok(word)
+    }
+
+    fn push(&mut self, value: u16) -> Result<(), u16> {
+        self.REGISTERS[SP] = self.REGISTERS[SP].wrapping_sub(2);
+        self.write(self.REGISTERS[SP], value, false)
+    }
+
+    fn pop(&mut self) -> Result<u16, u16> {
+        let value = self.read(self.REGISTERS[SP], false)?;
+        self.REGISTERS[SP] = self.REGISTERS[SP].wrapping_add(2);
+        Ok(value)
+    }
+
+    // Push the PSW and PC and load them from the vector, a trap that can't do that halts
+    fn trap(&mut self, vector: u16) -> Outcome {
+        let PSW = self.PSW;
+        let taken = self.push(PSW)
+            .and_then(|_| self.push(self.REGISTERS[PC]))
+            .and_then(|_| {
+                self.REGISTERS[PC] = self.read(vector, false)?;
+                self.PSW = self.read(vector + 2, false)?;
+                Ok(())
+            });
+        match taken {
+            Ok(()) => Outcome::Trap(vector),
+            Err(_) => Outcome::Halt,
+        }
+    }
+
+    // Work out where a six bit mode and register field points, doing any increment or decrement
+    fn locate(&mut self, field: u16, byte: bool) -> Result<Location, u16> {
+        let mode = (field >> 3) & 0b111;
+        let register = (field & 0b111) as usize;
+        // Bytes step by one, except through SP and PC which stay even
+        let step = if byte && register < SP { 1 } else { 2 };
+        let address = match mode {
+            0 => return Ok(Location::Register(register)),
+            1 => self.REGISTERS[register],
+            2 => {
+                let address = self.REGISTERS[register];
+                self.REGISTERS[register] = address.wrapping_add(step);
+                address
+            },
+            3 => {
+                let pointer = self.REGISTERS[register];
+                self.REGISTERS[register] = pointer.wrapping_add(2);
+                self.read(pointer, false)?
+            },
+            4 => {
+                self.REGISTERS[register] = self.REGISTERS[register].wrapping_sub(step);
+                self.REGISTERS[register]
+            },
+            5 => {
+                self.REGISTERS[register] = self.REGISTERS[register].wrapping_sub(2);
+                self.read(self.REGISTERS[register], false)?
+            },
+            6 => {
+                let index = self.fetch()?;
+                self.REGISTERS[register].wrapping_add(index)
+            },
+            _ => {
+                let index = self.fetch()?;
+                self.read(self.REGISTERS[register].wrapping_add(index), false)?
+            },
+        };
+        Ok(Location::Memory(address))
+    }
+
+    fn get(&self, location: Location, byte: bool) -> Result<u16, u16> {
+        match location {
+            Location::Register(register) if byte => Ok(self.REGISTERS[register] & 0o377),
+            Location::Register(register) => Ok(self.REGISTERS[register]),
+            Location::Memory(address) => self.read(address, byte),
+        }
+    }
+
+    // A byte written to a register replaces only its low byte
+    fn set(&mut self, location: Location, value: u16, byte: bool) -> Result<(), u16> {
+        match location {
+            Location::Register(register) if byte => {
+                self.REGISTERS[register] = (self.REGISTERS[register] & 0o177400) | (value & 0o377);
+                Ok(())
+            },
+            Location::Register(register) => {
+                self.REGISTERS[register] = value;
+                Ok(())
+            },
+            Location::Memory(address) => self.write(address, value, byte),
+        }
+    }
+
+    fn flags(&mut self, mask: u16, set: u16) {
+        if self.psw_written {
+            return;
+        }
+        self.PSW = (self.PSW & !mask) | (set & mask);
+    }
+
+    fn carry(&self) -> u16 {
+        self.PSW & C
+    }
+
+    fn execute(&mut self) -> Result<Outcome, u16> {
+        self.psw_written = false;
+        let IR = self.fetch()?;
+        let byte = IR & 0o100000 != 0;
+        let (sign, mask) = if byte { (0o200, 0o377) } else { (0o100000, 0o177777) };
+        let nz = |value: u16| {
+            let value = value & mask;
+            flag(value & sign != 0, N) | flag(value == 0, Z)
+        };
+        let register = ((IR >> 6) & 0b111) as usize;
+        let source = (IR >> 6) & 0o77;
+        let destination = IR & 0o77;
+
+        // Double operand, all but 0 and 7
+        if (0o010000..=0o060000).contains(&(IR & 0o070000)) {
+            let opcode = (IR >> 12) & 0b111;
+            // ADD and SUB are word only, 16 is SUB
+            let byte = byte && opcode != 0o6;
+            let (sign, mask) = if byte { (0o200, 0o377) } else { (0o100000, 0o177777) };
+            let nz = |value: u16| {
+                let value = value & mask;
+                flag(value & sign != 0, N) | flag(value == 0, Z)
+            };
+            let source = self.locate(source, byte).and_then(|location| self.get(location, byte))?;
+            let location = self.locate(destination, byte)?;
+            let subtract = IR & 0o170000 == 0o160000;
+            if opcode == 1 {
+                // MOVB to a register sign extends
+                if byte {
+                    if let Location::Register(register) = location {
+                        self.REGISTERS[register] = source as u8 as i8 as i16 as u16;
+                        self.flags(N | Z | V, nz(source));
+                        return Ok(Outcome::Ran);
+                    }
+                }
+                self.set(location, source, byte)?;
+                self.flags(N | Z | V, nz(source));
+                return Ok(Outcome::Ran);
+            }
+            let value = self.get(location, byte)?;
+            match (opcode, subtract) {
+                (2, _) => {
+                    // CMP source - destination
+                    let result = source.wrapping_sub(value) & mask;
+                    let overflow = (source ^ value) & (source ^ result) & sign != 0;
+                    let borrow = source & mask < value & mask;
+                    self.flags(N | Z | V | C, nz(result) | flag(overflow, V) | flag(borrow, C));
+                },
+                (3, _) => self.flags(N | Z | V, nz(source & value)),
+                (4, _) => {
+                    let result = value & !source;
+                    self.set(location, result, byte)?;
+                    self.flags(N | Z | V, nz(result));
+                },
+                (5, _) => {
+                    let result = value | source;
+                    self.set(location, result, byte)?;
+                    self.flags(N | Z | V, nz(result));
+                },
+                (6, false) => {
+                    let (result, carry) = value.overflowing_add(source);
+                    let overflow = !(source ^ value) & (source ^ result) & 0o100000 != 0;
+                    self.set(location, result, false)?;
+                    self.flags(N | Z | V | C, nz(result) | flag(overflow, V) | flag(carry, C));
+                },
+                (6, true) => {
+                    let (result, borrow) = value.overflowing_sub(source);
+                    let overflow = (source ^ value) & (value ^ result) & 0o100000 != 0;
+                    self.set(location, result, false)?;
+                    self.flags(N | Z | V | C, nz(result) | flag(overflow, V) | flag(borrow, C));
+                },
+                _ => unreachable!(),
+            }
+            return Ok(Outcome::Ran);
+        }
+
+        match IR {
+            0o000000 => Ok(Outcome::Halt),
+            0o000001 => Ok(Outcome::Wait),
+            0o000002 | 0o000006 => {
+                self.REGISTERS[PC] = self.pop()?;
+                self.PSW = self.pop()?;
+                Ok(Outcome::Ran)
+            },
+            0o000003 => Err(BPT_VECTOR),
+            0o000004 => Err(IOT_VECTOR),
+            0o000005 => Ok(Outcome::Ran), // RESET, no devices to reset
+            0o000100..=0o000177 => match self.locate(destination, false)? {
+                Location::Register(_) => Err(BUS_ERROR),
+                Location::Memory(address) => {
+                    self.REGISTERS[PC] = address;
+                    Ok(Outcome::Ran)
+                },
+            },
+            0o000200..=0o000207 => {
+                let register = (IR & 0b111) as usize;
+                self.REGISTERS[PC] = self.REGISTERS[register];
+                self.REGISTERS[register] = self.pop()?;
+                Ok(Outcome::Ran)
+            },
+            0o000240..=0o000277 => {
+                let flags = IR & 0o17;
+                if IR & 0o20 != 0 {
+                    self.PSW |= flags;
+                } else {
+                    self.PSW &= !flags;
+                }
+                Ok(Outcome::Ran)
+            },
+            0o000300..=0o000377 => {
+                let location = self.locate(destination, false)?;
+                let result = self.get(location, false)?.swap_bytes();
+                self.set(location, result, false)?;
+                // Flags from the new low byte
+                let flags = flag(result & 0o200 != 0, N) | flag(result & 0o377 == 0, Z);
+                self.flags(N | Z | V | C, flags);
+                Ok(Outcome::Ran)
+            },
+            0o000400..=0o003777 | 0o100000..=0o103777 => {
+                if self.branch(IR) {
+                    let offset = (IR & 0o377) as u8 as i8 as i16;
+                    self.REGISTERS[PC] = self.REGISTERS[PC].wrapping_add((offset * 2) as u16);
+                }
+                Ok(Outcome::Ran)
+            },
+            0o004000..=0o004777 => match self.locate(destination, false)? {
+                Location::Register(_) => Err(BUS_ERROR),
+                Location::Memory(address) => {
+                    self.push(self.REGISTERS[register])?;
+                    self.REGISTERS[register] = self.REGISTERS[PC];
+                    self.REGISTERS[PC] = address;
+                    Ok(Outcome::Ran)
+                },
+            },
+            0o005000..=0o006377 | 0o105000..=0o106377 => {
+                self.single_operand(IR, byte, sign, mask)?;
+                Ok(Outcome::Ran)
+            },
+            0o006400..=0o006477 => {
+                self.REGISTERS[SP] = self.REGISTERS[PC].wrapping_add((IR & 0o77) * 2);
+                self.REGISTERS[PC] = self.REGISTERS[5];
+                self.REGISTERS[5] = self.pop()?;
+                Ok(Outcome::Ran)
+            },
+            // Without memory management the previous space is the current one
+            0o006500..=0o006577 => {
+                let value = self.locate(destination, false).and_then(|location| self.get(location, false))?;
+                self.push(value)?;
+                self.flags(N | Z | V, nz(value));
+                Ok(Outcome::Ran)
+            },
+            0o006600..=0o006677 => {
+                let value = self.pop()?;
+                let location = self.locate(destination, false)?;
+                self.set(location, value, false)?;
+                self.flags(N | Z | V, nz(value));
+                Ok(Outcome::Ran)
+            },
+            0o006700..=0o006777 => {
+                let location = self.locate(destination, false)?;
+                let result = if self.PSW & N != 0 { 0o177777 } else { 0 };
+                self.set(location, result, false)?;
+                self.flags(Z | V, flag(result == 0, Z));
+                Ok(Outcome::Ran)
+            },
+            0o070000..=0o073777 => {
+                self.eis(IR, register, destination)?;
+                Ok(Outcome::Ran)
+            },
+            0o074000..=0o074777 => {
+                let location = self.locate(destination, false)?;
+                let result = self.get(location, false)? ^ self.REGISTERS[register];
+                self.set(location, result, false)?;
+                self.flags(N | Z | V, nz(result));
+                Ok(Outcome::Ran)
+            },
+            0o077000..=0o077777 => {
+                self.REGISTERS[register] = self.REGISTERS[register].wrapping_sub(1);
+                if self.REGISTERS[register] != 0 {
+                    self.REGISTERS[PC] = self.REGISTERS[PC].wrapping_sub((IR & 0o77) * 2);
+                }
+                Ok(Outcome::Ran)
+            },
+            0o104000..=0o104377 => Err(EMT_VECTOR),
+            0o104400..=0o104777 => Err(TRAP_VECTOR),
+            _ => Err(RESERVED_INSTRUCTION),
+        }
+    }
+
+    // True if the branch in bits 15 and 10:8 is taken
+    fn branch(&self, IR: u16) -> bool {
+        let [n, z, v, c] = [N, Z, V, C].map(|flag| self.PSW & flag != 0);
+        match ((IR >> 8) & 0b111) | ((IR >> 12) & 0b1000) {
+            0o01 => true,
+            0o02 => !z,
+            0o03 => z,
+            0o04 => n == v,
+            0o05 => n != v,
+            0o06 => !z && n == v,
+            0o07 => z || n != v,
+            0o10 => !n,
+            0o11 => n,
+            0o12 => !c && !z,
+            0o13 => c || z,
+            0o14 => !v,
+            0o15 => v,
+            0o16 => !c,
+            _ => c,
+        }
+    }
+
+    fn single_operand(&mut self, IR: u16, byte: bool, sign: u16, mask: u16) -> Result<(), u16> {
+        let location = self.locate(IR & 0o77, byte)?;
+        let value = self.get(location, byte)? & mask;
+        let carry = self.carry();
+        let negative = |value: u16| value & sign != 0;
+        // (result, V, C), None for a flag the instruction leaves alone
+        let (result, overflow, carry_out) = match (IR >> 6) & 0o77 {
+            0o50 => (0, Some(false), Some(false)),
+            0o51 => (!value & mask, Some(false), Some(true)),
+            0o52 => {
+                let result = value.wrapping_add(1) & mask;
+                (result, Some(result == sign), None)
+            },
+            0o53 => {
+                let result = value.wrapping_sub(1) & mask;
+                (result, Some(value == sign), None)
+            },
+            0o54 => {
+                let result = value.wrapping_neg() & mask;
+                (result, Some(result == sign), Some(result != 0))
+            },
+            0o55 => {
+                let result = value.wrapping_add(carry) & mask;
+                (result, Some(value == sign - 1 && carry != 0), Some(value == mask && carry != 0))
+            },
+            0o56 => {
+                let result = value.wrapping_sub(carry) & mask;
+                (result, Some(value == sign && carry != 0), Some(value == 0 && carry != 0))
+            },
+            0o57 => (value, Some(false), Some(false)),
+            0o60 => {
+                let result = (value >> 1) | if carry != 0 { sign } else { 0 };
+                let carry_out = value & 1 != 0;
+                (result, Some(negative(result) != carry_out), Some(carry_out))
+            },
+            0o61 => {
+                let result = ((value << 1) | carry) & mask;
+                let carry_out = negative(value);
+                (result, Some(negative(result) != carry_out), Some(carry_out))
+            },
+            0o62 => {
+                let result = (value >> 1) | (value & sign);
+                let carry_out = value & 1 != 0;
+                (result, Some(negative(result) != carry_out), Some(carry_out))
+            },
+            _ => {
+                let result = (value << 1) & mask;
+                let carry_out = negative(value);
+                (result, Some(negative(result) != carry_out), Some(carry_out))
+            },
+        };
+        // TST only reads
+        if (IR >> 6) & 0o77 != 0o57 {
+            self.set(location, result, byte)?;
+        }
+        let mut flags = flag(negative(result), N) | flag(result == 0, Z);
+        let mut changed = N | Z;
+        if let Some(overflow) = overflow {
+            flags |= flag(overflow, V);
+            changed |= V;
+        }
+        if let Some(carry_out) = carry_out {
+            flags |= flag(carry_out, C);
+            changed |= C;
+        }
+        self.flags(changed, flags);
+        Ok(())
+    }
+
+    // MUL, DIV, ASH and ASHC
+    fn eis(&mut self, IR: u16, register: usize, source: u16) -> Result<(), u16> {
+        let source = self.locate(source, false).and_then(|location| self.get(location, false))?;
+        let odd = register | 1;
+        match (IR >> 9) & 0b11 {
+            0 => {
+                let product = (self.REGISTERS[register] as i16 as i32) * (source as i16 as i32);
+                self.REGISTERS[register] = (product >> 16) as u16;
+                self.REGISTERS[odd] = product as u16;
+                let overflow = !(-0o100000..=0o77777).contains(&product);
+                let flags = flag(product < 0, N) | flag(product == 0, Z) | flag(overflow, C);
+                self.flags(N | Z | V | C, flags);
+            },
+            1 => {
+                let dividend = ((self.REGISTERS[register] as u32) << 16 | self.REGISTERS[odd] as u32) as i32;
+                let divisor = source as i16 as i32;
+                if divisor == 0 {
+                    self.flags(N | Z | V | C, V | C);
+                    return Ok(());
+                }
+                let quotient = dividend.wrapping_div(divisor);
+                if !(-0o100000..=0o77777).contains(&quotient) {
+                    self.flags(N | Z | V | C, V);
+                    return Ok(());
+                }
+                self.REGISTERS[register] = quotient as u16;
+                self.REGISTERS[odd] = dividend.wrapping_rem(divisor) as u16;
+                self.flags(N | Z | V | C, flag(quotient < 0, N) | flag(quotient == 0, Z));
+            },
+            2 => {
+                let shift = (source as i16) << 10 >> 10;
+                let (result, carry, overflow) = arithmetic_shift(self.REGISTERS[register] as u32, 16, shift);
+                let result = result as u16;
+                self.REGISTERS[register] = result;
+                let flags = flag(result & 0o100000 != 0, N) | flag(result == 0, Z) | flag(overflow, V) | flag(carry, C);
+                self.flags(N | Z | V | C, flags);
+            },
+            _ => {
+                let value = (self.REGISTERS[register] as u32) << 16 | self.REGISTERS[odd] as u32;
+                let shift = (source as i16) << 10 >> 10;
+                let (result, carry, overflow) = arithmetic_shift(value, 32, shift);
+                self.REGISTERS[register] = (result >> 16) as u16;
+                self.REGISTERS[odd] = result as u16;
+                let flags = flag(result & 0x8000_0000 != 0, N) | flag(result == 0, Z) | flag(overflow, V) | flag(carry, C);
+                self.flags(N | Z | V | C, flags);
+            },
+        }
+        Ok(())
+    }
+}
+
+// ASH and ASHC a bit at a time on a value bits wide, shift from -32 to 31
+// Returns the result, C the last bit shifted out and V set if the sign changed at any step
+fn arithmetic_shift(value: u32, bits: u32, shift: i16) -> (u32, bool, bool) {
+    let sign = 1 << (bits - 1);
+    let mask = u32::MAX >> (32 - bits);
+    let (mut value, mut carry, mut overflow) = (value & mask, false, false);
+    for _ in 0..shift.unsigned_abs() {
+        if shift > 0 {
+            carry = value & sign != 0;
+            let shifted = (value << 1) & mask;
+            overflow |= (shifted ^ value) & sign != 0;
+            value = shifted;
+        } else {
+            carry = value & 1 != 0;
+            value = value >> 1 | (value & sign);
+        }
+    }
+    (value, carry, overflow)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Run the program at 1000 with SP at 1000, the registers and PSW as given, up to the
+    // first thing that isn't an ordinary instruction, memory past the program is HALTs
+    fn run(program: &[u16], REGISTERS: [u16; 6], PSW: u16) -> (Reference, Outcome) {
+        let mut model = Reference::new(0o2000);
+        model.memory[0o1000 / 2..0o1000 / 2 + program.len()].copy_from_slice(program);
+        model.REGISTERS[..6].copy_from_slice(&REGISTERS);
+        model.REGISTERS[SP] = 0o1000;
+        model.REGISTERS[PC] = 0o1000;
+        model.PSW = PSW;
+        for _ in 0..100 {
+            let outcome = model.step();
+            if outcome != Outcome::Ran {
+                return (model, outcome);
+            }
+        }
+        panic!("still running after 100 instructions");
+    }
+
+    #[test]
+    fn add_sub_cmp_flags() {
+        // (instruction, R0, R1, R1 after, NZVC)
+        let table = [
+            (0o060001, 0o000001, 0o077777, 0o100000, N | V),      // ADD R0,R1
+            (0o060001, 0o000001, 0o177777, 0o000000, Z | C),      // ADD R0,R1
+            (0o160001, 0o000001, 0o100000, 0o077777, V),          // SUB R0,R1
+            (0o160001, 0o000002, 0o000001, 0o177777, N | C),      // SUB R0,R1
+            (0o020001, 0o000001, 0o000002, 0o000002, N | C),      // CMP R0,R1
+            (0o020001, 0o100000, 0o000001, 0o000001, V),          // CMP R0,R1
+        ];
+        for (IR, R0, R1, result, flags) in table {
+            let (model, outcome) = run(&[IR], [R0, R1, 0, 0, 0, 0], 0);
+            assert_eq!(outcome, Outcome::Halt);
+            assert_eq!((model.REGISTERS[1], model.PSW), (result, flags), "{:06o}", IR);
+        }
+    }
+
+    #[test]
+    fn byte_operations() {
+        // MOVB R0,R1 sign extends into a register
+        let (model, _) = run(&[0o110001], [0o000200, 0o123456, 0, 0, 0, 0], 0);
+        assert_eq!((model.REGISTERS[1], model.PSW), (0o177600, N));
+
+        // MOVB (R0)+,(R1)+ steps by one and writes only the byte
+        let (model, _) = run(&[0o112021, 0o000000, 0o011000, 0o111111], [0o1005, 0o1006, 0, 0, 0, 0], 0);
+        assert_eq!(&model.REGISTERS[..2], &[0o1006, 0o1007]);
+        assert_eq!(model.memory[0o1006 / 2], 0o111022);
+
+        // INCB wraps at a byte
+        let (model, _) = run(&[0o105200], [0o000177, 0, 0, 0, 0, 0], 0);
+        assert_eq!((model.REGISTERS[0], model.PSW), (0o000200, N | V));
+    }
+
+    #[test]
+    fn shifts_and_swab() {
+        let (model, _) = run(&[0o006200], [0o100001, 0, 0, 0, 0, 0], 0);          // ASR R0
+        assert_eq!((model.REGISTERS[0], model.PSW), (0o140000, N | C));
+        let (model, _) = run(&[0o006100], [0o100000, 0, 0, 0, 0, 0], C);          // ROL R0
+        assert_eq!((model.REGISTERS[0], model.PSW), (0o000001, V | C));
+        let (model, _) = run(&[0o000300], [0o000377, 0, 0, 0, 0, 0], N | Z | V | C);   // SWAB R0
+        assert_eq!((model.REGISTERS[0], model.PSW), (0o177400, Z));
+    }
+
+    #[test]
+    fn eis() {
+        // MUL R1,R0
+        let (model, _) = run(&[0o070001], [0o000400, 0o001000, 0, 0, 0, 0], 0);
+        assert_eq!((model.REGISTERS[0], model.REGISTERS[1], model.PSW), (0o000002, 0o000000, C));
+        // DIV R2,R0, 100001 / 10
+        let (model, _) = run(&[0o071002], [0o000001, 0o000001, 0o000010, 0, 0, 0], 0);
+        assert_eq!((model.REGISTERS[0], model.REGISTERS[1], model.PSW), (0o020000, 0o000001, 0));
+        // DIV by zero leaves the registers
+        let (model, _) = run(&[0o071002], [0o000001, 0o000001, 0, 0, 0, 0], 0);
+        assert_eq!((model.REGISTERS[0], model.PSW), (0o000001, V | C));
+        // ASH R1,R0, right by one
+        let (model, _) = run(&[0o072001], [0o000003, 0o000077, 0, 0, 0, 0], 0);
+        assert_eq!((model.REGISTERS[0], model.PSW), (0o000001, C));
+    }
+
+    #[test]
+    fn ash_and_ashc_shifts() {
+        // (instruction, R0, R1, R1 shift or R2 shift, R0 and R1 after, NZVC)
+        let table = [
+            // ASH R2,R0 by 2, the sign changes on the way and back again
+            (0o072002, 0o040000, 0, 2, 0o000000, 0, Z | V | C),
+            (0o072002, 0o020000, 0, 2, 0o100000, 0, N | V),
+            (0o072002, 0o140000, 0, 1, 0o100000, 0, N | C),
+            // Right by 32, the most there is, leaves the sign
+            (0o072002, 0o100000, 0, 0o40, 0o177777, 0, N | C),
+            (0o072002, 0o077777, 0, 0o40, 0o000000, 0, Z),
+            (0o072002, 0o000001, 0, 0o77, 0o000000, 0, Z | C),
+            (0o072002, 0o000001, 0, 0o37, 0o000000, 0, Z | V),
+            // ASHC R2,R0
+            (0o073002, 0o040000, 0o000000, 2, 0o000000, 0o000000, Z | V | C),
+            (0o073002, 0o000000, 0o100000, 1, 0o000001, 0o000000, 0),
+            (0o073002, 0o100000, 0o000000, 0o40, 0o177777, 0o177777, N | C),
+            (0o073002, 0o000001, 0o000000, 0o60, 0o000000, 0o000001, 0),
+        ];
+        for (IR, R0, R1, shift, R0_after, R1_after, flags) in table {
+            let (model, _) = run(&[IR], [R0, R1, shift, 0, 0, 0], 0);
+            assert_eq!((model.REGISTERS[0], model.REGISTERS[1], model.PSW), (R0_after, R1_after, flags),
+                "{:06o} {:06o} {:06o} by {:02o}", IR, R0, R1, shift);
+        }
+    }
+
+    #[test]
+    fn control_flow() {
+        // MOV #3,R0  SOB R0,.  HALT
+        let (model, outcome) = run(&[0o012700, 0o000003, 0o077001, 0o000000], [0; 6], 0);
+        assert_eq!(outcome, Outcome::Halt);
+        assert_eq!((model.REGISTERS[0], model.REGISTERS[PC]), (0, 0o1010));
+
+        // JSR PC,1010  then RTS PC back and HALT
+        let (model, outcome) = run(&[0o004767, 0o000004, 0o000000, 0o000000, 0o000207], [0; 6], 0);
+        assert_eq!(outcome, Outcome::Halt);
+        assert_eq!((model.REGISTERS[SP], model.REGISTERS[PC]), (0o1000, 0o1006));
+
+        // BNE not taken with Z set, BEQ taken
+        let (model, _) = run(&[0o001001, 0o001401], [0; 6], Z);
+        assert_eq!(model.REGISTERS[PC], 0o1010);
+    }
+
+    #[test]
+    fn mark() {
+        // The MACRO-11 calling sequence, two arguments and MARK 2 pushed, R5 pointing at the MARK
+        //  1000 MOV R5,-(SP)  MOV #11,-(SP)  MOV #22,-(SP)  MOV #MARK 2,-(SP)  MOV SP,R5
+        //  1020 JSR PC,@#1100  HALT
+        //  1100 MOV 4(R5),R0  RTS R5, which runs the MARK off the stack back to 1024
+        let mut program = vec![0; 0o110 / 2];
+        program[..12].copy_from_slice(&[
+            0o010546, 0o012746, 0o000011, 0o012746, 0o000022, 0o012746, 0o006402, 0o010605,
+            0o004737, 0o001100, 0o000000, 0o000000,
+        ]);
+        program[0o100 / 2..0o100 / 2 + 3].copy_from_slice(&[0o016500, 0o000004, 0o000205]);
+        let (model, outcome) = run(&program, [0, 0, 0, 0, 0, 0o4321], 0);
+        assert_eq!(outcome, Outcome::Halt);
+        assert_eq!((model.REGISTERS[0], model.REGISTERS[5]), (0o11, 0o4321));
+        assert_eq!((model.REGISTERS[SP], model.REGISTERS[PC]), (0o1000, 0o1026));
+
+        // MARK 0 on its own, SP just past it and the PC from R5
+        let (model, outcome) = run(&[0o006400, 0o001234], [0, 0, 0, 0, 0, 0o1100], 0);
+        assert_eq!(outcome, Outcome::Halt);
+        assert_eq!((model.REGISTERS[5], model.REGISTERS[SP], model.REGISTERS[PC]), (0o1234, 0o1004, 0o1102));
+    }
+
+    #[test]
+    fn rti_and_rtt() {
+        // PC then PSW popped, the T bit and priority with them
+        for IR in [0o000002, 0o000006] {
+            let mut model = Reference::new(0o2000);
+            model.memory[0o1000 / 2] = IR;
+            model.memory[0o700 / 2..0o704 / 2].copy_from_slice(&[0o1100, 0o357]);
+            model.REGISTERS[SP] = 0o700;
+            model.REGISTERS[PC] = 0o1000;
+            assert_eq!(model.step(), Outcome::Ran, "{:06o}", IR);
+            assert_eq!((model.REGISTERS[PC], model.PSW, model.REGISTERS[SP]), (0o1100, 0o357, 0o704), "{:06o}", IR);
+        }
+
+        // Back from a trap to the instruction after the one that trapped
+        let mut model = Reference::new(0o2000);
+        model.memory[0o20 / 2..0o24 / 2].copy_from_slice(&[0o1100, 0o340]);
+        model.memory[0o1000 / 2] = 0o000004;
+        model.memory[0o1100 / 2] = 0o000006;
+        model.REGISTERS[SP] = 0o1000;
+        model.REGISTERS[PC] = 0o1000;
+        model.PSW = N | C;
+        assert_eq!(model.step(), Outcome::Trap(IOT_VECTOR));
+        assert_eq!(model.step(), Outcome::Ran);
+        assert_eq!((model.REGISTERS[PC], model.PSW, model.REGISTERS[SP]), (0o1002, N | C, 0o1000));
+    }
+
+    #[test]
+    fn traps() {
+        let vectors = |model: &mut Reference| {
+            model.memory[0o4 / 2] = 0o500;
+            model.memory[0o6 / 2] = 0o340;
+        };
+        // MOV (R0),R1 from an odd address traps to 4 with the PSW and PC pushed
+        let mut model = Reference::new(0o2000);
+        vectors(&mut model);
+        model.memory[0o1000 / 2] = 0o011001;
+        model.REGISTERS = [0o1001, 0, 0, 0, 0, 0, 0o1000, 0o1000];
+        model.PSW = N;
+        assert_eq!(model.step(), Outcome::Trap(BUS_ERROR));
+        assert_eq!((model.REGISTERS[PC], model.PSW, model.REGISTERS[SP]), (0o500, 0o340, 0o774));
+        assert_eq!(&model.memory[0o774 / 2..0o1000 / 2], &[0o1002, N]);
+
+        // Past the end of memory too, and a reserved instruction to 10
+        assert_eq!(model.read(0o4000, false), Err(BUS_ERROR));
+        let (_, outcome) = run(&[0o000007], [0; 6], 0);
+        assert_eq!(outcome, Outcome::Trap(RESERVED_INSTRUCTION));
+
+        // The PSW is in the I/O page, MOV #17,@#177776
+        let (model, _) = run(&[0o012737, 0o000017, PSW_ADDRESS], [0; 6], 0);
+        assert_eq!(model.PSW, 0o17);
+        let (model, _) = run(&[0o005037, PSW_ADDRESS], [0; 6], N | V | C);
+        assert_eq!(model.PSW, 0);
+    }
+}
@@ -0,0 +1,134 @@
+#![allow(non_snake_case)]
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::machinestate::MachineState;
+
+// Record and replay of everything from outside the machine
+//
+// The log is a plain text file, one input per line, in the order they happened:
+//  <time_ns> switch <0|1>
+//  <time_ns> console <character>
+// time_ns is the emulated time of the clock pulse that ran next after the input, the character
+// is in octal. Lines starting with # are comments.
+// Emulated time moves on with every clock pulse, idle pulses while the clock is stopped
+// included, so it places an input exactly where the machine cycle count can't.
+// A replay has to start from the same machine as the recording, same options or snapshot.
+// TODO: Disk completion timing, once there is a disk
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Input {
+    // The SWITCH flip-flop, set by the front panel switches
+    Switch(bool),
+
+    // A character typed at the console terminal
+    Console(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InputEvent {
+    pub TIME_NS: u64,
+    pub input: Input,
+}
+
+// Apply an input to the machine, as the front panel or terminal would
+pub fn apply(state: &mut MachineState, input: Input) -> Result<(), String> {
+    match input {
+        Input::Switch(value) => state.SWITCH = value,
+        Input::Console(character) => {
+            if !state.UNIBUS.type_character(character) {
+                return Err(String::from("no console terminal attached"));
+            }
+        },
+    }
+    Ok(())
+}
+
+pub struct InputLog {
+    pub events: Vec<InputEvent>,
+}
+
+impl InputLog {
+    pub fn new() -> InputLog {
+        InputLog { events: Vec::new() }
+    }
+
+    // Log an input applied before the machine's next clock pulse
+    pub fn record(&mut self, state: &MachineState, input: Input) {
+        self.events.push(InputEvent { TIME_NS: state.TIME_NS, input });
+    }
+
+    pub fn load(path: &Path) -> io::Result<InputLog> {
+        let text = fs::read_to_string(path)?;
+        let mut log = InputLog::new();
+        for (number, line) in text.lines().enumerate() {
+            let invalid = || io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}:{}: invalid input '{}'", path.display(), number + 1, line),
+            );
+            if line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (time, input) = match fields.as_slice() {
+                [] => continue,
+                [time, "switch", "0"] => (time, Input::Switch(false)),
+                [time, "switch", "1"] => (time, Input::Switch(true)),
+                [time, "console", character] => {
+                    (time, Input::Console(u8::from_str_radix(character, 8).map_err(|_| invalid())?))
+                },
+                _ => return Err(invalid()),
+            };
+            log.events.push(InputEvent { TIME_NS: time.parse().map_err(|_| invalid())?, input });
+        }
+        Ok(log)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut text = String::from("# time_ns input\n");
+        for event in self.events.iter() {
+            let input = match event.input {
+                Input::Switch(value) => format!("switch {}", value as u8),
+                Input::Console(character) => format!("console {:03o}", character),
+            };
+            text += &format!("{} {}\n", event.TIME_NS, input);
+        }
+        fs::write(path, text)
+    }
+}
+
+impl Default for InputLog {
+    fn default() -> InputLog {
+        InputLog::new()
+    }
+}
+
+// Feeds a log back into the machine at the emulated times it was recorded at
+pub struct Replay {
+    events: Vec<InputEvent>,
+    next: usize,
+}
+
+impl Replay {
+    pub fn new(log: InputLog) -> Replay {
+        Replay { events: log.events, next: 0 }
+    }
+
+    // Apply every input due before the machine's next clock pulse
+    pub fn apply_due(&mut self, state: &mut MachineState) {
+        while let Some(event) = self.events.get(self.next) {
+            if event.TIME_NS > state.TIME_NS {
+                break;
+            }
+            // It was applied when it was recorded, so it applies again on the same machine
+            let _ = apply(state, event.input);
+            self.next += 1;
+        }
+    }
+
+    pub fn finished(&self) -> bool {
+        self.next == self.events.len()
+    }
+}
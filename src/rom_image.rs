@@ -0,0 +1,294 @@
+#![allow(non_snake_case)]
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::microrom::{MicroRom, MicroWord};
+
+// The control store is 256 words of 56 bits, here split over fourteen 256x4 PROM images.
+// The fields are packed into U WORD bits 55:00 as below, ROM_CHIPS then says which U WORD
+// bit each chip's outputs O3-O0 drive and which of them are stored inverted, the images hold
+// O3-O0 in the low nibble of each byte as a programmer reads the part.
+//
+//  Bits    Field
+//  07:00   UPF
+//  11:08   RIF
+//  15:12   SRX
+//  20:16   UBF
+//  21      SBA
+//  23:22   SDM
+//  27:24   SBM
+//  31:28   SBC
+//  36:32   ALU
+//  39:37   SPS
+//  43:40   DAD
+//  46:44   BUS
+//  47      CBA
+//  48      CD
+//  49      CB
+//  51:50   WR
+//  52      CIR
+//  55:53   CLK
+//
+// TODO: ROM_CHIPS is the emulator's own layout, chip N on bits (4N+3):(4N) in order and all
+// active high. The KD11-A's chip numbers, output order and inverted outputs are on the control
+// store sheets of the print set, until they are transcribed into ROM_CHIPS these images don't
+// program parts for a real board and images read from one won't import.
+//
+// PROMs have no unprogrammed words, so export also writes urom_map.bin, a byte per address,
+// 1 where the microword is implemented. Without it every word imports as implemented.
+
+pub const ROM_WORDS: usize = 256;
+pub const ROM_CHIP_COUNT: usize = 14;
+pub const ROM_CHIP_WIDTH: usize = 4;
+
+// One PROM, the U WORD bit each output drives, O0 first, and a mask of the outputs that are
+// stored inverted
+pub struct RomChip {
+    pub bits: [u8; ROM_CHIP_WIDTH],
+    pub inverted: u8,
+}
+
+impl RomChip {
+    // What the chip holds for a microword's 56 bits, as the programmer reads it
+    pub fn nibble(&self, bits: u64) -> u8 {
+        let mut nibble = 0;
+        for (output, bit) in self.bits.iter().enumerate() {
+            nibble |= (((bits >> bit) & 1) as u8) << output;
+        }
+        nibble ^ self.inverted
+    }
+
+    // The microword bits a nibble read from the chip drives
+    pub fn bits(&self, nibble: u8) -> u64 {
+        let nibble = nibble ^ self.inverted;
+        self.bits.iter().enumerate()
+            .map(|(output, bit)| (((nibble >> output) & 1) as u64) << bit)
+            .fold(0, |bits, bit| bits | bit)
+    }
+}
+
+pub const ROM_CHIPS: [RomChip; ROM_CHIP_COUNT] = [
+    RomChip { bits: [0, 1, 2, 3],     inverted: 0 },
+    RomChip { bits: [4, 5, 6, 7],     inverted: 0 },
+    RomChip { bits: [8, 9, 10, 11],   inverted: 0 },
+    RomChip { bits: [12, 13, 14, 15], inverted: 0 },
+    RomChip { bits: [16, 17, 18, 19], inverted: 0 },
+    RomChip { bits: [20, 21, 22, 23], inverted: 0 },
+    RomChip { bits: [24, 25, 26, 27], inverted: 0 },
+    RomChip { bits: [28, 29, 30, 31], inverted: 0 },
+    RomChip { bits: [32, 33, 34, 35], inverted: 0 },
+    RomChip { bits: [36, 37, 38, 39], inverted: 0 },
+    RomChip { bits: [40, 41, 42, 43], inverted: 0 },
+    RomChip { bits: [44, 45, 46, 47], inverted: 0 },
+    RomChip { bits: [48, 49, 50, 51], inverted: 0 },
+    RomChip { bits: [52, 53, 54, 55], inverted: 0 },
+];
+
+pub struct RomField {
+    pub name: &'static str,
+    pub lsb: u8,
+    pub width: u8,
+    pub get: fn(&MicroWord) -> u8,
+    pub set: fn(&mut MicroWord, u8),
+}
+
+pub const ROM_FIELDS: [RomField; 18] = [
+    RomField { name: "UPF", lsb: 0,  width: 8, get: |w| w.UPF, set: |w, v| w.UPF = v },
+    RomField { name: "RIF", lsb: 8,  width: 4, get: |w| w.RIF, set: |w, v| w.RIF = v },
+    RomField { name: "SRX", lsb: 12, width: 4, get: |w| w.SRX, set: |w, v| w.SRX = v },
+    RomField { name: "UBF", lsb: 16, width: 5, get: |w| w.UBF, set: |w, v| w.UBF = v },
+    RomField { name: "SBA", lsb: 21, width: 1, get: |w| w.SBA, set: |w, v| w.SBA = v },
+    RomField { name: "SDM", lsb: 22, width: 2, get: |w| w.SDM, set: |w, v| w.SDM = v },
+    RomField { name: "SBM", lsb: 24, width: 4, get: |w| w.SBM, set: |w, v| w.SBM = v },
+    RomField { name: "SBC", lsb: 28, width: 4, get: |w| w.SBC, set: |w, v| w.SBC = v },
+    RomField { name: "ALU", lsb: 32, width: 5, get: |w| w.ALU, set: |w, v| w.ALU = v },
+    RomField { name: "SPS", lsb: 37, width: 3, get: |w| w.SPS, set: |w, v| w.SPS = v },
+    RomField { name: "DAD", lsb: 40, width: 4, get: |w| w.DAD, set: |w, v| w.DAD = v },
+    RomField { name: "BUS", lsb: 44, width: 3, get: |w| w.BUS, set: |w, v| w.BUS = v },
+    RomField { name: "CBA", lsb: 47, width: 1, get: |w| w.CBA, set: |w, v| w.CBA = v },
+    RomField { name: "CD",  lsb: 48, width: 1, get: |w| w.CD, set: |w, v| w.CD = v },
+    RomField { name: "CB",  lsb: 49, width: 1, get: |w| w.CB, set: |w, v| w.CB = v },
+    RomField { name: "WR",  lsb: 50, width: 2, get: |w| w.WR, set: |w, v| w.WR = v },
+    RomField { name: "CIR", lsb: 52, width: 1, get: |w| w.CIR, set: |w, v| w.CIR = v },
+    RomField { name: "CLK", lsb: 53, width: 3, get: |w| w.CLK, set: |w, v| w.CLK = v },
+];
+
+// Pack a microword into the 56 bits of the images, an error if a field doesn't fit its bits
+pub fn microword_to_bits(word: &MicroWord) -> Result<u64, String> {
+    let mut bits: u64 = 0;
+    for field in ROM_FIELDS.iter() {
+        let mask = (1u64 << field.width) - 1;
+        let value = (field.get)(word) as u64;
+        if value > mask {
+            return Err(format!("field {} value {:o} does not fit in {} bits", field.name, value, field.width));
+        }
+        bits |= value << field.lsb;
+    }
+    Ok(bits)
+}
+
+// Unpack the 56 bits of one address into a microword
+pub fn bits_to_microword(bits: u64, implemented: bool) -> MicroWord {
+    let mut word = MicroWord {
+        implemented, CLK: 0, CIR: 0, WR: 0, CB: 0, CD: 0, CBA: 0, BUS: 0, DAD: 0,
+        SPS: 0, ALU: 0, SBC: 0, SBM: 0, SDM: 0, SBA: 0, UBF: 0, SRX: 0, RIF: 0, UPF: 0,
+    };
+    for field in ROM_FIELDS.iter() {
+        let mask = (1u64 << field.width) - 1;
+        (field.set)(&mut word, ((bits >> field.lsb) & mask) as u8);
+    }
+    word
+}
+
+// Split the microROM into one 256 byte image per PROM, data in the low nibble
+pub fn chip_images(rom: &MicroRom) -> Result<Vec<[u8; ROM_WORDS]>, String> {
+    let mut images = vec![[0u8; ROM_WORDS]; ROM_CHIP_COUNT];
+    for (address, word) in rom.words().iter().enumerate() {
+        let bits = microword_to_bits(word).map_err(|e| format!("microword {:03o}: {}", address, e))?;
+        for (chip, image) in ROM_CHIPS.iter().zip(images.iter_mut()) {
+            image[address] = chip.nibble(bits);
+        }
+    }
+    Ok(images)
+}
+
+// Intel HEX with 16 byte data records, as accepted by most PROM programmers
+pub fn intel_hex(image: &[u8]) -> String {
+    let mut hex = String::new();
+    for (record, chunk) in image.chunks(16).enumerate() {
+        let address = (record * 16) as u16;
+        let mut checksum: u8 = (chunk.len() as u8)
+            .wrapping_add((address >> 8) as u8)
+            .wrapping_add(address as u8);
+        hex += &format!(":{:02X}{:04X}00", chunk.len(), address);
+        for byte in chunk {
+            hex += &format!("{:02X}", byte);
+            checksum = checksum.wrapping_add(*byte);
+        }
+        hex += &format!("{:02X}\n", 0u8.wrapping_sub(checksum));
+    }
+    hex += ":00000001FF\n";
+    hex
+}
+
+// Write urom_NN.bin and urom_NN.hex for every chip, and urom_map.bin, into the directory
+pub fn export(rom: &MicroRom, directory: &Path) -> io::Result<()> {
+    let images = chip_images(rom).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    fs::create_dir_all(directory)?;
+    for (chip, image) in images.iter().enumerate() {
+        fs::write(directory.join(format!("urom_{:02}.bin", chip)), image)?;
+        fs::write(directory.join(format!("urom_{:02}.hex", chip)), intel_hex(image))?;
+    }
+    let map: Vec<u8> = rom.words().iter().map(|word| word.implemented as u8).collect();
+    fs::write(directory.join(MAP_FILE), map)
+}
+
+const MAP_FILE: &str = "urom_map.bin";
+
+// Read the images export wrote back into a microROM, the inverse of export
+pub fn import(directory: &Path) -> io::Result<MicroRom> {
+    let read = |name: String| -> io::Result<Vec<u8>> {
+        let path = directory.join(name);
+        let image = fs::read(&path)?;
+        if image.len() != ROM_WORDS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is {} bytes, expected {}", path.display(), image.len(), ROM_WORDS),
+            ));
+        }
+        Ok(image)
+    };
+
+    let mut bits = [0u64; ROM_WORDS];
+    for (number, chip) in ROM_CHIPS.iter().enumerate() {
+        let image = read(format!("urom_{:02}.bin", number))?;
+        for (address, nibble) in image.iter().enumerate() {
+            bits[address] |= chip.bits(nibble & 0b1111);
+        }
+    }
+    let map = match read(String::from(MAP_FILE)) {
+        Ok(map) => map,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => vec![1; ROM_WORDS],
+        Err(e) => return Err(e),
+    };
+    Ok(MicroRom::new(std::array::from_fn(|address| bits_to_microword(bits[address], map[address] != 0))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::microrom::MICROROM;
+
+    fn directory(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("rom_image_{}_{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn export_import_round_trip() {
+        let directory = directory("round_trip");
+        export(&MICROROM, &directory).unwrap();
+        let rom = import(&directory).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+
+        for (address, (expected, actual)) in MICROROM.words().iter().zip(rom.words().iter()).enumerate() {
+            assert_eq!(expected.implemented, actual.implemented, "microword {:03o}", address);
+            assert_eq!(microword_to_bits(expected), microword_to_bits(actual), "microword {:03o}", address);
+        }
+    }
+
+    // An implemented word of all zeros is a jump to 000, not an unprogrammed word
+    #[test]
+    fn zero_word_stays_implemented() {
+        let mut words: [MicroWord; ROM_WORDS] = std::array::from_fn(|_| bits_to_microword(0, false));
+        words[0o100].implemented = true;
+        let directory = directory("zero_word");
+        export(&MicroRom::new(words), &directory).unwrap();
+        let rom = import(&directory).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert!(rom.words()[0o100].implemented);
+        assert!(!rom.words()[0o101].implemented);
+    }
+
+    // 027 CON07 CONTACT BOUNCE COUNT
+    //  UPF 044, SDM 2, SBM 17, SBC 14, ALU 32, CD, CLK 4
+    #[test]
+    fn microword_packs_into_the_chips() {
+        let images = chip_images(&MICROROM).unwrap();
+        let nibbles: Vec<u8> = images.iter().map(|image| image[0o027]).collect();
+        assert_eq!(nibbles, [0o4, 0o2, 0, 0, 0, 0o10, 0o17, 0o14, 0o12, 0o1, 0, 0, 0o1, 0o10]);
+
+        // Every U WORD bit is on exactly one chip output
+        let mut driven = 0u64;
+        for chip in ROM_CHIPS.iter() {
+            for bit in chip.bits {
+                assert_eq!(driven & 1 << bit, 0, "bit {} on two outputs", bit);
+                driven |= 1 << bit;
+            }
+        }
+        assert_eq!(driven, (1 << 56) - 1);
+    }
+
+    // An inverted output holds the complement of its bit, and reads back as the bit
+    #[test]
+    fn inverted_outputs() {
+        let chip = RomChip { bits: [53, 54, 55, 52], inverted: 0b0101 };
+        let bits = 0o4 << 53; // CLK 4
+        assert_eq!(chip.nibble(bits), 0b0100 ^ 0b0101);
+        assert_eq!(chip.bits(chip.nibble(bits)), bits);
+        assert_eq!(chip.nibble(0), 0b0101);
+    }
+
+    #[test]
+    fn field_overflow_is_an_error() {
+        let mut word = bits_to_microword(0, true);
+        word.CLK = 0o10;
+        assert_eq!(microword_to_bits(&word).unwrap_err(), "field CLK value 10 does not fit in 3 bits");
+
+        let mut words: [MicroWord; ROM_WORDS] = std::array::from_fn(|_| bits_to_microword(0, false));
+        words[0o12].SBA = 2;
+        assert!(chip_images(&MicroRom::new(words)).unwrap_err().starts_with("microword 012"));
+    }
+}
@@ -0,0 +1,69 @@
+#![allow(non_snake_case)]
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+// Timed events in emulated time, for the Unibus devices to run on their own between bus cycles
+// Each device has at most one event pending, the time it next wants its event called.
+// Events at the same time run in the order they were scheduled.
+// Nothing here is saved, it is rebuilt by asking every device again after a restore or step back.
+
+pub struct Scheduler {
+    // (time in ns, sequence, device index), soonest first
+    events: BinaryHeap<Reverse<(u64, u64, usize)>>,
+    sequence: u64,
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler {
+            events: BinaryHeap::new(),
+            sequence: 0,
+        }
+    }
+
+    // Run device's event at time_ns, replacing any it already has
+    pub fn schedule(&mut self, time_ns: u64, device: usize) {
+        self.cancel(device);
+        self.events.push(Reverse((time_ns, self.sequence, device)));
+        self.sequence += 1;
+    }
+
+    pub fn cancel(&mut self, device: usize) {
+        self.events.retain(|Reverse((_, _, index))| *index != device);
+    }
+
+    pub fn clear(&mut self) {
+        self.events.clear();
+    }
+
+    // When the next event is due, None if nothing is scheduled
+    pub fn next_ns(&self) -> Option<u64> {
+        self.events.peek().map(|Reverse((time_ns, _, _))| *time_ns)
+    }
+
+    // Take the next event due at or before now_ns, (time, device index)
+    pub fn pop_due(&mut self, now_ns: u64) -> Option<(u64, usize)> {
+        match self.next_ns() {
+            Some(time_ns) if time_ns <= now_ns => {
+                let Reverse((time_ns, _, device)) = self.events.pop()?;
+                Some((time_ns, device))
+            },
+            _ => None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Scheduler {
+        Scheduler::new()
+    }
+}
@@ -0,0 +1,331 @@
+#![allow(non_snake_case)]
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::clock::{ClockMode, ClockState, CL1, CL2, CL3, OFF};
+use crate::machinestate::MachineState;
+use crate::unibus::{self, Unibus};
+
+// Snapshot of the whole machine, little endian throughout
+//
+//  "USNP"  u16 version
+//  u8      UPP, PUPP, BUPP, BUBC, BUBC_FLUX, clock mode (0 OFF, 1 CL1, 2 CL2, 3 CL3), SINCLK, SWITCH
+//  u32     PULSE
+//  u16     B, D, IR, IR_PC, PSW, BA, BUS_D, B_MUX, D_MUX, ALU, DATA_DISPLAY
+//  u16     R0 to R17
+//  u64     CYCLE, TIME_NS
+//  u32     memory size in words, followed by the words
+//  u8      device count, then for each a u8 name length, the name and the device's own state
+//
+// The U WORD register and the clock mode are references into the microROM and the clock
+// constants, so the UPP and the clock mode number are saved and the references rebuilt.
+// HALTED and the last cycle's register write and bus access are not saved, a restored
+// machine runs on from the saved point unless its UPP is at an unimplemented microword.
+// Device events are not saved either, the devices are asked for them again.
+// Version 1 had no TIME_NS, the devices' events can't be put back without it so it isn't read.
+
+pub const SNAPSHOT_VERSION: u16 = 2;
+
+pub fn to_bytes(state: &MachineState) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(state.UNIBUS.memory.len() * 2 + 128);
+    bytes.extend_from_slice(b"USNP");
+    bytes.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+
+    bytes.extend_from_slice(&[
+        state.UPP, state.PUPP, state.BUPP, state.BUBC, state.BUBC_FLUX,
+        clock_number(state.CLK_MODE), state.SINCLK as u8, state.SWITCH as u8,
+    ]);
+    bytes.extend_from_slice(&state.PULSE.to_le_bytes());
+    for value in [
+        state.B, state.D, state.IR, state.IR_PC, state.PSW, state.BA, state.BUS_D,
+        state.B_MUX, state.D_MUX, state.ALU, state.DATA_DISPLAY,
+    ] {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    for register in state.REGISTERS.iter() {
+        bytes.extend_from_slice(&register.to_le_bytes());
+    }
+    bytes.extend_from_slice(&state.CYCLE.to_le_bytes());
+    bytes.extend_from_slice(&state.TIME_NS.to_le_bytes());
+
+    bytes.extend_from_slice(&(state.UNIBUS.memory.len() as u32).to_le_bytes());
+    for word in state.UNIBUS.memory.iter() {
+        bytes.extend_from_slice(&word.to_le_bytes());
+    }
+
+    bytes.push(state.UNIBUS.devices.len() as u8);
+    for device in state.UNIBUS.devices.iter() {
+        let name = device.name();
+        bytes.push(name.len() as u8);
+        bytes.extend_from_slice(name.as_bytes());
+        device.save(&mut bytes);
+    }
+    bytes
+}
+
+// Replace the whole machine with a snapshot, the state is untouched if the snapshot is bad
+// The microROM stays as it is, the snapshot only says where in it the machine was
+pub fn from_bytes(state: &mut MachineState, bytes: &[u8]) -> Result<(), String> {
+    let mut reader = Reader::new(bytes);
+    if reader.bytes(4)? != b"USNP" {
+        return Err(String::from("not a snapshot"));
+    }
+    let version = reader.u16()?;
+    if version != SNAPSHOT_VERSION {
+        return Err(format!("snapshot version {} is not supported, expected {}", version, SNAPSHOT_VERSION));
+    }
+
+    let UPP = reader.u8()?;
+    let PUPP = reader.u8()?;
+    let BUPP = reader.u8()?;
+    let BUBC = reader.u8()?;
+    let BUBC_FLUX = reader.u8()?;
+    let clock = reader.u8()?;
+    let CLK_MODE = clock_state(clock).ok_or(format!("unknown clock mode {}", clock))?;
+    let SINCLK = reader.u8()? != 0;
+    let SWITCH = reader.u8()? != 0;
+    let PULSE = reader.u32()?;
+    if PULSE >= CLK_MODE.pulse_count {
+        return Err(format!("pulse {} is past the end of the {:?} machine cycle", PULSE, CLK_MODE.mode));
+    }
+    let B = reader.u16()?;
+    let D = reader.u16()?;
+    let IR = reader.u16()?;
+    let IR_PC = reader.u16()?;
+    let PSW = reader.u16()?;
+    let BA = reader.u16()?;
+    let BUS_D = reader.u16()?;
+    let B_MUX = reader.u16()?;
+    let D_MUX = reader.u16()?;
+    let ALU = reader.u16()?;
+    let DATA_DISPLAY = reader.u16()?;
+    let mut REGISTERS = [0; 16];
+    for register in REGISTERS.iter_mut() {
+        *register = reader.u16()?;
+    }
+    let CYCLE = reader.u64()?;
+    let TIME_NS = reader.u64()?;
+
+    let words = reader.u32()? as usize;
+    let mut UNIBUS = Unibus::new(words);
+    if UNIBUS.memory.len() != words {
+        return Err(format!("{} words of memory reach into the I/O page", words));
+    }
+    for word in UNIBUS.memory.iter_mut() {
+        *word = reader.u16()?;
+    }
+
+    let devices = reader.u8()?;
+    for _ in 0..devices {
+        let length = reader.u8()? as usize;
+        let name = String::from_utf8_lossy(reader.bytes(length)?).to_string();
+        let mut device = unibus::device_by_name(&name).ok_or(format!("unknown device '{}'", name))?;
+        device.restore(&mut reader)?;
+        UNIBUS.attach(device);
+    }
+    UNIBUS.schedule_all(TIME_NS);
+
+    if !reader.finished() {
+        return Err(String::from("unexpected data after the end of the snapshot"));
+    }
+
+    *state = MachineState {
+        ROM: state.ROM,
+        DATA_DISPLAY,
+        CLK_MODE,
+        PULSE,
+        SINCLK,
+        U_WORD: &state.ROM.words()[UPP as usize],
+        UPP,
+        BUPP,
+        PUPP,
+        BUBC,
+        BUBC_FLUX,
+        SWITCH,
+        B,
+        D,
+        REGISTERS,
+        IR,
+        IR_PC,
+        PSW,
+        BA,
+        BUS_D,
+        UNIBUS,
+        B_MUX,
+        D_MUX,
+        ALU,
+        REGISTER_WRITE: None,
+        BUS_ACCESS: None,
+        CYCLE,
+        TIME_NS,
+        HALTED: state.ROM.fetch(UPP, PUPP).err(),
+    };
+    Ok(())
+}
+
+pub fn save(state: &MachineState, path: &Path) -> io::Result<()> {
+    fs::write(path, to_bytes(state))
+}
+
+pub fn restore(state: &mut MachineState, path: &Path) -> io::Result<()> {
+    let bytes = fs::read(path)?;
+    from_bytes(state, &bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))
+}
+
+fn clock_number(clock: &ClockState) -> u8 {
+    match clock.mode {
+        ClockMode::OFF => 0,
+        ClockMode::CL1 => 1,
+        ClockMode::CL2 => 2,
+        ClockMode::CL3 => 3,
+    }
+}
+
+fn clock_state(number: u8) -> Option<&'static ClockState> {
+    match number {
+        0 => Some(&OFF),
+        1 => Some(&CL1),
+        2 => Some(&CL2),
+        3 => Some(&CL3),
+        _ => None,
+    }
+}
+
+// Little endian fields out of a snapshot, running off the end is an error
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader { bytes, position: 0 }
+    }
+
+    pub fn bytes(&mut self, length: usize) -> Result<&'a [u8], String> {
+        let end = self.position + length;
+        let bytes = self.bytes.get(self.position..end).ok_or("snapshot ends early")?;
+        self.position = end;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    pub fn finished(&self) -> bool {
+        self.position == self.bytes.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::microrom::MICROROM;
+
+    // TIME_NS follows the header, the microprogram bytes, PULSE, the registers and CYCLE
+    const TIME_NS_OFFSET: usize = 4 + 2 + 8 + 4 + 11 * 2 + 16 * 2 + 8;
+
+    fn machine() -> MachineState<'static> {
+        let mut state = MachineState::new(&MICROROM, 0).unwrap();
+        state.UNIBUS = Unibus::new(1024);
+        state.UNIBUS.attach(unibus::device_by_name("dl11").unwrap());
+        state.UNIBUS.attach(unibus::device_by_name("kw11l").unwrap());
+        state
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut state = machine();
+        state.UPP = 0o026;
+        state.PUPP = 0o046;
+        state.BUBC = 0o001;
+        state.CLK_MODE = &CL3;
+        state.SWITCH = true;
+        state.PULSE = 1;
+        state.D = 0o123456;
+        state.BA = 0o177566;
+        state.PSW = 0o340;
+        state.REGISTERS[7] = 0o1000;
+        state.REGISTERS[0o17] = 0o777;
+        state.CYCLE = 131045;
+        state.TIME_NS = 0o1234567;
+        state.UNIBUS.write(0o1000, 0o012737, false).unwrap();
+        state.UNIBUS.write(unibus::bus_address(0o177566), 'K' as u16, false).unwrap();
+        let bytes = to_bytes(&state);
+
+        let mut restored = machine();
+        from_bytes(&mut restored, &bytes).unwrap();
+        assert_eq!((restored.UPP, restored.PUPP, restored.BUBC, restored.U_WORD.UPF), (0o026, 0o046, 0o001, MICROROM.words()[0o026].UPF));
+        assert_eq!((clock_number(restored.CLK_MODE), restored.SWITCH, restored.PULSE), (3, true, 1));
+        assert_eq!((restored.D, restored.BA, restored.PSW), (0o123456, 0o177566, 0o340));
+        assert_eq!(restored.REGISTERS, state.REGISTERS);
+        assert_eq!((restored.CYCLE, restored.TIME_NS), (131045, 0o1234567));
+        assert_eq!(restored.UNIBUS.peek(0o1000), Some(0o012737));
+        assert_eq!(restored.HALTED, None);
+        assert_eq!(to_bytes(&restored), bytes);
+    }
+
+    #[test]
+    fn rejects_version_1() {
+        let mut bytes = to_bytes(&machine());
+        bytes[4..6].copy_from_slice(&1u16.to_le_bytes());
+        bytes.drain(TIME_NS_OFFSET..TIME_NS_OFFSET + 8);
+
+        let mut state = machine();
+        state.D = 0o7;
+        assert_eq!(from_bytes(&mut state, &bytes).unwrap_err(), "snapshot version 1 is not supported, expected 2");
+        assert_eq!(state.D, 0o7);
+    }
+
+    #[test]
+    fn rejects_bad_snapshots() {
+        let bytes = to_bytes(&machine());
+        let mut state = machine();
+
+        let mut magic = bytes.clone();
+        magic[0..4].copy_from_slice(b"USNQ");
+        assert_eq!(from_bytes(&mut state, &magic).unwrap_err(), "not a snapshot");
+
+        assert_eq!(from_bytes(&mut state, &bytes[..bytes.len() - 1]).unwrap_err(), "snapshot ends early");
+
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert_eq!(from_bytes(&mut state, &longer).unwrap_err(), "unexpected data after the end of the snapshot");
+
+        let mut clock = bytes.clone();
+        clock[11] = 4;
+        assert_eq!(from_bytes(&mut state, &clock).unwrap_err(), "unknown clock mode 4");
+
+        // CL1 has one pulse, and CL3 two
+        for (mode, PULSE, error) in [
+            (1, 1, "pulse 1 is past the end of the CL1 machine cycle"),
+            (3, 2, "pulse 2 is past the end of the CL3 machine cycle"),
+            (0, 1, "pulse 1 is past the end of the OFF machine cycle"),
+        ] {
+            let mut pulse = bytes.clone();
+            pulse[11] = mode;
+            pulse[14..18].copy_from_slice(&(PULSE as u32).to_le_bytes());
+            assert_eq!(from_bytes(&mut state, &pulse).unwrap_err(), error);
+        }
+        let mut pulse = bytes.clone();
+        pulse[11] = 3;
+        pulse[14..18].copy_from_slice(&1u32.to_le_bytes());
+        from_bytes(&mut state, &pulse).unwrap();
+        assert_eq!(state.PULSE, 1);
+    }
+}
@@ -0,0 +1,331 @@
+#![allow(non_snake_case)]
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::clock::ClockMode;
+use crate::machinestate::MachineState;
+use crate::snapshot::Reader;
+
+// Per machine cycle trace log
+//
+// Text traces are one line per machine cycle with the selected fields as NAME=value, octal
+// for the microprogram fields and hex for the data path.
+//
+// Binary traces start with a header:
+//  "UTRC"      magic
+//  u16         format version
+//  u32         selected fields
+// followed by one record per machine cycle: the u64 cycle number, then each selected field in
+// the order of TRACE_FIELDS. UPP, PUPP, UBF, BUBC and CLK are one byte, the data path values two.
+// A register write is an address byte (0xFF for none) and a two byte value.
+// Everything is little endian. read() reads a binary trace back.
+
+pub const TRACE_VERSION: u16 = 1;
+
+pub const TRACE_UPP: u32 = 1 << 0;
+pub const TRACE_PUPP: u32 = 1 << 1;
+pub const TRACE_UBF: u32 = 1 << 2;
+pub const TRACE_BUBC: u32 = 1 << 3;
+pub const TRACE_CLK: u32 = 1 << 4;
+pub const TRACE_BMUX: u32 = 1 << 5;
+pub const TRACE_DMUX: u32 = 1 << 6;
+pub const TRACE_ALU: u32 = 1 << 7;
+pub const TRACE_D: u32 = 1 << 8;
+pub const TRACE_B: u32 = 1 << 9;
+pub const TRACE_WR: u32 = 1 << 10;
+pub const TRACE_ALL: u32 = (1 << 11) - 1;
+
+// Field names as accepted by parse_fields, in record order
+pub const TRACE_FIELDS: [(&str, u32); 11] = [
+    ("upp", TRACE_UPP),
+    ("pupp", TRACE_PUPP),
+    ("ubf", TRACE_UBF),
+    ("bubc", TRACE_BUBC),
+    ("clk", TRACE_CLK),
+    ("bmux", TRACE_BMUX),
+    ("dmux", TRACE_DMUX),
+    ("alu", TRACE_ALU),
+    ("d", TRACE_D),
+    ("b", TRACE_B),
+    ("wr", TRACE_WR),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    Text,
+    Binary,
+}
+
+// Everything the trace knows about one machine cycle
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceRecord {
+    pub CYCLE: u64,
+    pub UPP: u8,
+    pub PUPP: u8,
+    pub UBF: u8,
+    pub BUBC: u8,
+    pub CLK: u8,
+    pub B_MUX: u16,
+    pub D_MUX: u16,
+    pub ALU: u16,
+    pub D: u16,
+    pub B: u16,
+    pub REGISTER_WRITE: Option<(u8, u16)>,
+}
+
+impl TraceRecord {
+    // A record with nothing but the cycle number, for fields a trace left out
+    fn empty(CYCLE: u64) -> TraceRecord {
+        TraceRecord {
+            CYCLE, UPP: 0, PUPP: 0, UBF: 0, BUBC: 0, CLK: 0,
+            B_MUX: 0, D_MUX: 0, ALU: 0, D: 0, B: 0, REGISTER_WRITE: None,
+        }
+    }
+
+    // Capture the microprogram side at the start of a machine cycle,
+    // the data path is filled in by finish() once the cycle has run
+    pub fn start(state: &MachineState) -> TraceRecord {
+        TraceRecord {
+            CYCLE: state.CYCLE,
+            UPP: state.UPP,
+            PUPP: state.PUPP,
+            UBF: state.U_WORD.UBF,
+            BUBC: state.BUBC,
+            CLK: clock_mode_number(&state.CLK_MODE.mode),
+            B_MUX: 0,
+            D_MUX: 0,
+            ALU: 0,
+            D: 0,
+            B: 0,
+            REGISTER_WRITE: None,
+        }
+    }
+
+    pub fn finish(&mut self, state: &MachineState) {
+        self.B_MUX = state.B_MUX;
+        self.D_MUX = state.D_MUX;
+        self.ALU = state.ALU;
+        self.D = state.D;
+        self.B = state.B;
+        self.REGISTER_WRITE = state.REGISTER_WRITE;
+    }
+}
+
+pub fn clock_mode_number(mode: &ClockMode) -> u8 {
+    match mode {
+        ClockMode::OFF => 0,
+        ClockMode::CL1 => 1,
+        ClockMode::CL2 => 2,
+        ClockMode::CL3 => 3,
+    }
+}
+
+// Turn a comma separated list of field names, or "all", into a field mask
+pub fn parse_fields(list: &str) -> Result<u32, String> {
+    let mut fields = 0;
+    for name in list.split(',').map(|name| name.trim().to_lowercase()) {
+        if name == "all" {
+            fields |= TRACE_ALL;
+            continue;
+        }
+        match TRACE_FIELDS.iter().find(|(field, _)| *field == name) {
+            Some((_, bit)) => fields |= bit,
+            None => return Err(format!("unknown trace field '{}'", name)),
+        }
+    }
+    Ok(fields)
+}
+
+// One machine cycle as a line of the text trace, with the fields selected
+pub fn text_line(record: &TraceRecord, fields: u32) -> String {
+    let mut line = format!("{:>10}", record.CYCLE);
+    let mut field = |bit: u32, text: String| {
+        if fields & bit != 0 {
+            line += " ";
+            line += &text;
+        }
+    };
+    field(TRACE_UPP, format!("UPP={:03o}", record.UPP));
+    field(TRACE_PUPP, format!("PUPP={:03o}", record.PUPP));
+    field(TRACE_UBF, format!("UBF={:02o}", record.UBF));
+    field(TRACE_BUBC, format!("BUBC={:02o}", record.BUBC));
+    field(TRACE_CLK, format!("CLK={}", record.CLK));
+    field(TRACE_BMUX, format!("BMUX={:04x}", record.B_MUX));
+    field(TRACE_DMUX, format!("DMUX={:04x}", record.D_MUX));
+    field(TRACE_ALU, format!("ALU={:04x}", record.ALU));
+    field(TRACE_D, format!("D={:04x}", record.D));
+    field(TRACE_B, format!("B={:04x}", record.B));
+    field(TRACE_WR, match record.REGISTER_WRITE {
+        Some((address, value)) => format!("R{:02o}<={:04x}", address, value),
+        None => String::from("R--"),
+    });
+    line
+}
+
+pub struct Tracer {
+    writer: BufWriter<File>,
+    format: TraceFormat,
+    fields: u32,
+}
+
+impl Tracer {
+    pub fn create(path: &Path, format: TraceFormat, fields: u32) -> io::Result<Tracer> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        if format == TraceFormat::Binary {
+            writer.write_all(b"UTRC")?;
+            writer.write_all(&TRACE_VERSION.to_le_bytes())?;
+            writer.write_all(&fields.to_le_bytes())?;
+        }
+        Ok(Tracer { writer, format, fields })
+    }
+
+    pub fn record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        match self.format {
+            TraceFormat::Text => self.write_text(record),
+            TraceFormat::Binary => self.write_binary(record),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    fn write_text(&mut self, record: &TraceRecord) -> io::Result<()> {
+        writeln!(self.writer, "{}", text_line(record, self.fields))
+    }
+
+    fn write_binary(&mut self, record: &TraceRecord) -> io::Result<()> {
+        let mut bytes: Vec<u8> = Vec::with_capacity(32);
+        bytes.extend_from_slice(&record.CYCLE.to_le_bytes());
+        let fields = self.fields;
+        let selected = |bit: u32| fields & bit != 0;
+        if selected(TRACE_UPP) { bytes.push(record.UPP); }
+        if selected(TRACE_PUPP) { bytes.push(record.PUPP); }
+        if selected(TRACE_UBF) { bytes.push(record.UBF); }
+        if selected(TRACE_BUBC) { bytes.push(record.BUBC); }
+        if selected(TRACE_CLK) { bytes.push(record.CLK); }
+        if selected(TRACE_BMUX) { bytes.extend_from_slice(&record.B_MUX.to_le_bytes()); }
+        if selected(TRACE_DMUX) { bytes.extend_from_slice(&record.D_MUX.to_le_bytes()); }
+        if selected(TRACE_ALU) { bytes.extend_from_slice(&record.ALU.to_le_bytes()); }
+        if selected(TRACE_D) { bytes.extend_from_slice(&record.D.to_le_bytes()); }
+        if selected(TRACE_B) { bytes.extend_from_slice(&record.B.to_le_bytes()); }
+        if selected(TRACE_WR) {
+            let (address, value) = record.REGISTER_WRITE.unwrap_or((0xFF, 0));
+            bytes.push(address);
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        self.writer.write_all(&bytes)
+    }
+}
+
+// Read a binary trace back, the fields it selected and its records with the rest left zero
+pub fn from_bytes(bytes: &[u8]) -> Result<(u32, Vec<TraceRecord>), String> {
+    let mut reader = Reader::new(bytes);
+    if reader.bytes(4).ok() != Some(b"UTRC".as_slice()) {
+        return Err(String::from("not a binary trace"));
+    }
+    let version = reader.u16()?;
+    if version != TRACE_VERSION {
+        return Err(format!("trace version {} is not supported, expected {}", version, TRACE_VERSION));
+    }
+    let fields = reader.u32()?;
+
+    let mut records = Vec::new();
+    while !reader.finished() {
+        let record = read_record(&mut reader, fields)
+            .map_err(|_| format!("trace ends part way through record {}", records.len()))?;
+        records.push(record);
+    }
+    Ok((fields, records))
+}
+
+pub fn read(path: &Path) -> io::Result<(u32, Vec<TraceRecord>)> {
+    let bytes = fs::read(path)?;
+    from_bytes(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))
+}
+
+fn read_record(reader: &mut Reader, fields: u32) -> Result<TraceRecord, String> {
+    let selected = |bit: u32| fields & bit != 0;
+    let mut record = TraceRecord::empty(reader.u64()?);
+    if selected(TRACE_UPP) { record.UPP = reader.u8()?; }
+    if selected(TRACE_PUPP) { record.PUPP = reader.u8()?; }
+    if selected(TRACE_UBF) { record.UBF = reader.u8()?; }
+    if selected(TRACE_BUBC) { record.BUBC = reader.u8()?; }
+    if selected(TRACE_CLK) { record.CLK = reader.u8()?; }
+    if selected(TRACE_BMUX) { record.B_MUX = reader.u16()?; }
+    if selected(TRACE_DMUX) { record.D_MUX = reader.u16()?; }
+    if selected(TRACE_ALU) { record.ALU = reader.u16()?; }
+    if selected(TRACE_D) { record.D = reader.u16()?; }
+    if selected(TRACE_B) { record.B = reader.u16()?; }
+    if selected(TRACE_WR) {
+        let address = reader.u8()?;
+        let value = reader.u16()?;
+        record.REGISTER_WRITE = if address == 0xFF { None } else { Some((address, value)) };
+    }
+    Ok(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records() -> Vec<TraceRecord> {
+        vec![
+            TraceRecord {
+                CYCLE: 1, UPP: 0o026, PUPP: 0o000, UBF: 0o06, BUBC: 0, CLK: 3,
+                B_MUX: 0x1234, D_MUX: 0x5678, ALU: 0x9ABC, D: 0xDEF0, B: 0x0F0F, REGISTER_WRITE: None,
+            },
+            TraceRecord {
+                CYCLE: 2, UPP: 0o046, PUPP: 0o026, UBF: 0, BUBC: 1, CLK: 1,
+                B_MUX: 1, D_MUX: 2, ALU: 3, D: 4, B: 5, REGISTER_WRITE: Some((0o15, 0o20)),
+            },
+        ]
+    }
+
+    fn write(name: &str, fields: u32, records: &[TraceRecord]) -> Vec<u8> {
+        let path = std::env::temp_dir().join(format!("trace_{}_{}.bin", name, std::process::id()));
+        let mut tracer = Tracer::create(&path, TraceFormat::Binary, fields).unwrap();
+        for record in records {
+            tracer.record(record).unwrap();
+        }
+        tracer.flush().unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let bytes = write("all", TRACE_ALL, &records());
+        assert_eq!(from_bytes(&bytes).unwrap(), (TRACE_ALL, records()));
+    }
+
+    #[test]
+    fn binary_round_trip_some_fields() {
+        let fields = TRACE_UPP | TRACE_D | TRACE_WR;
+        let bytes = write("some", fields, &records());
+        // Header, then 8 + 1 + 2 + 3 bytes a record
+        assert_eq!(bytes.len(), 10 + 2 * 14);
+
+        let (read_fields, read) = from_bytes(&bytes).unwrap();
+        assert_eq!(read_fields, fields);
+        // The fields left out read back as zero
+        let expected: Vec<TraceRecord> = records().iter().map(|r| TraceRecord {
+            UPP: r.UPP, D: r.D, REGISTER_WRITE: r.REGISTER_WRITE, ..TraceRecord::empty(r.CYCLE)
+        }).collect();
+        assert_eq!(read, expected);
+    }
+
+    #[test]
+    fn bad_binary_traces() {
+        let bytes = write("bad", TRACE_ALL, &records());
+        assert_eq!(from_bytes(b"UTRX\x01\x00").unwrap_err(), "not a binary trace");
+        assert!(from_bytes(&bytes[..bytes.len() - 1]).unwrap_err().contains("record 1"));
+
+        let mut version = bytes.clone();
+        version[4] = 2;
+        assert!(from_bytes(&version).unwrap_err().contains("version 2"));
+    }
+}
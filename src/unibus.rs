@@ -0,0 +1,411 @@
+#![allow(non_snake_case)]
+#![allow(clippy::upper_case_acronyms)]
+
+use std::collections::VecDeque;
+
+use crate::scheduler::Scheduler;
+use crate::snapshot::Reader;
+
+// The Unibus, memory from address 0 upwards and devices in the I/O page
+// Addresses are 18 bits, data is transferred a word (or byte for DATOB) at a time
+
+pub const IO_PAGE: u32 = 0o760000;
+pub const ADDRESS_MASK: u32 = 0o777777;
+
+// Without memory management the processor's 16 bit addresses reach the I/O page from 160000 up,
+// the top two address bits are driven for them
+pub fn bus_address(BA: u16) -> u32 {
+    let address = BA as u32;
+    if address >= 0o160000 {
+        address | 0o600000
+    } else {
+        address
+    }
+}
+
+// Time the processor clock is stopped for a bus cycle, from BGBUS until SSYN comes back
+// TODO: Check against the MM11-L and device timing in the print set
+pub const MEMORY_READ_NS: u64 = 500;
+pub const MEMORY_WRITE_NS: u64 = 400;
+pub const DEVICE_NS: u64 = 300;
+
+// Unibus transfers, selected by C1BUS and C0BUS
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BusCycle {
+    DATI,
+    DATIP,
+    DATO,
+    DATOB,
+}
+
+// Nothing answered with SSYN, the processor traps through 4
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BusTimeout {
+    pub address: u32,
+}
+
+pub trait Device {
+    // The name device_by_name knows the device by, snapshots use it to rebuild the Unibus
+    fn name(&self) -> &'static str;
+
+    // True if the device has a register at this (even) address
+    fn handles(&self, address: u32) -> bool;
+
+    fn read(&mut self, address: u32) -> u16;
+
+    // For a byte write only the addressed byte of value is meaningful
+    fn write(&mut self, address: u32, value: u16, byte: bool);
+
+    // Characters sent to the console since the last call
+    fn take_output(&mut self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    // A character typed at the keyboard, false if the device has no keyboard
+    fn input(&mut self, _character: u8) -> bool {
+        false
+    }
+
+    // When the device next wants event called, None for not until something changes
+    // Asked again after every event, bus cycle to the device and restore, with the emulated time then
+    fn next_event_ns(&self, _now_ns: u64) -> Option<u64> {
+        None
+    }
+
+    // Runs at the emulated time next_event_ns asked for, a transfer finishing or a clock ticking
+    fn event(&mut self, _time_ns: u64) {}
+
+    // Register and buffer contents for a snapshot, restore takes back what save wrote
+    fn save(&self, bytes: &mut Vec<u8>);
+    fn restore(&mut self, reader: &mut Reader) -> Result<(), String>;
+}
+
+// What a bus cycle overwrote, so it can be put back when stepping backwards
+pub enum BusChange {
+    Memory { index: usize, word: u16 },
+    Device { index: usize, state: Vec<u8> },
+}
+
+pub struct Unibus {
+    // Memory in words, it never reaches into the I/O page
+    pub memory: Vec<u16>,
+
+    pub devices: Vec<Box<dyn Device>>,
+
+    // While set, every change the bus makes is logged here first
+    pub journal: Option<Vec<BusChange>>,
+
+    // Device events, and the emulated time the processor has reached
+    pub events: Scheduler,
+    pub now_ns: u64,
+}
+
+impl Unibus {
+    pub fn new(memory_words: usize) -> Unibus {
+        Unibus {
+            memory: vec![0; memory_words.min((IO_PAGE / 2) as usize)],
+            devices: Vec::new(),
+            journal: None,
+            events: Scheduler::new(),
+            now_ns: 0,
+        }
+    }
+
+    pub fn attach(&mut self, device: Box<dyn Device>) {
+        self.devices.push(device);
+        self.schedule(self.devices.len() - 1);
+    }
+
+    // Run every device event due by now_ns, in time order
+    pub fn run_events(&mut self, now_ns: u64) {
+        self.now_ns = now_ns;
+        while let Some((time_ns, index)) = self.events.pop_due(now_ns) {
+            self.journal_device(index);
+            self.devices[index].event(time_ns);
+            if let Some(next_ns) = self.devices[index].next_event_ns(time_ns) {
+                self.events.schedule(next_ns.max(time_ns + 1), index);
+            }
+        }
+    }
+
+    // Ask every device for its next event again, after the devices or the time were put back
+    pub fn schedule_all(&mut self, now_ns: u64) {
+        self.now_ns = now_ns;
+        self.events.clear();
+        for index in 0..self.devices.len() {
+            self.schedule(index);
+        }
+    }
+
+    fn schedule(&mut self, index: usize) {
+        match self.devices[index].next_event_ns(self.now_ns) {
+            Some(time_ns) => self.events.schedule(time_ns, index),
+            None => self.events.cancel(index),
+        }
+    }
+
+    pub fn read(&mut self, address: u32) -> Result<u16, BusTimeout> {
+        let address = address & ADDRESS_MASK & !1;
+        if let Some(word) = self.memory.get((address / 2) as usize) {
+            return Ok(*word);
+        }
+        // Reading a device register can change the device, DL11 RBUF clears DONE
+        match self.devices.iter().position(|device| device.handles(address)) {
+            Some(index) => {
+                self.journal_device(index);
+                let word = self.devices[index].read(address);
+                self.schedule(index);
+                Ok(word)
+            },
+            None => Err(BusTimeout { address }),
+        }
+    }
+
+    // How long a bus cycle to address keeps the processor waiting
+    pub fn wait_ns(&self, address: u32, cycle: BusCycle) -> u64 {
+        let in_memory = ((address & ADDRESS_MASK) / 2) < self.memory.len() as u32;
+        match (in_memory, cycle) {
+            (false, _) => DEVICE_NS,
+            (true, BusCycle::DATI | BusCycle::DATIP) => MEMORY_READ_NS,
+            (true, BusCycle::DATO | BusCycle::DATOB) => MEMORY_WRITE_NS,
+        }
+    }
+
+    // Read memory without a bus cycle, None for the I/O page and non-existent memory
+    pub fn peek(&self, address: u32) -> Option<u16> {
+        let address = address & ADDRESS_MASK & !1;
+        self.memory.get((address / 2) as usize).copied()
+    }
+
+    pub fn write(&mut self, address: u32, value: u16, byte: bool) -> Result<(), BusTimeout> {
+        let address = address & ADDRESS_MASK;
+        let index = (address / 2) as usize;
+        if let Some(word) = self.memory.get_mut(index) {
+            if let Some(journal) = &mut self.journal {
+                journal.push(BusChange::Memory { index, word: *word });
+            }
+            *word = match (byte, address & 1) {
+                (false, _) => value,
+                (true, 0) => (*word & 0xFF00) | (value & 0x00FF),
+                (true, _) => (*word & 0x00FF) | (value & 0xFF00),
+            };
+            return Ok(());
+        }
+        match self.devices.iter().position(|device| device.handles(address & !1)) {
+            Some(index) => {
+                self.journal_device(index);
+                self.devices[index].write(address, value, byte);
+                self.schedule(index);
+                Ok(())
+            },
+            None => Err(BusTimeout { address }),
+        }
+    }
+
+    fn journal_device(&mut self, index: usize) {
+        if let Some(journal) = &mut self.journal {
+            let mut state = Vec::new();
+            self.devices[index].save(&mut state);
+            journal.push(BusChange::Device { index, state });
+        }
+    }
+
+    // Put back what the changes overwrote, latest first
+    pub fn undo(&mut self, changes: Vec<BusChange>) {
+        for change in changes.into_iter().rev() {
+            match change {
+                BusChange::Memory { index, word } => self.memory[index] = word,
+                BusChange::Device { index, state } => {
+                    // The state came from the same device, it always reads back
+                    let _ = self.devices[index].restore(&mut Reader::new(&state));
+                },
+            }
+        }
+    }
+
+    // Give a typed character to the first device with a keyboard, false if there is none
+    pub fn type_character(&mut self, character: u8) -> bool {
+        match self.devices.iter_mut().position(|device| device.input(character)) {
+            Some(index) => {
+                self.schedule(index);
+                true
+            },
+            None => false,
+        }
+    }
+
+    // Console output from every device that has some
+    pub fn take_output(&mut self) -> Vec<u8> {
+        self.devices.iter_mut().flat_map(|device| device.take_output()).collect()
+    }
+}
+
+// The Unibus device a command line name refers to
+pub fn device_by_name(name: &str) -> Option<Box<dyn Device>> {
+    match name {
+        "dl11" => Some(Box::new(DL11::new())),
+        "kw11l" => Some(Box::new(KW11L::new())),
+        _ => None,
+    }
+}
+
+// DL11 console terminal interface at 777560
+//  777560 RCSR  receiver status, bit 7 DONE, bit 6 interrupt enable
+//  777562 RBUF  received character
+//  777564 XCSR  transmitter status, bit 7 READY, bit 6 interrupt enable
+//  777566 XBUF  character to send
+// Characters typed at the console are queued in input, characters sent collect in output
+// TODO: Interrupts, and character timing with an event to set READY once a character is sent
+pub struct DL11 {
+    pub RCSR: u16,
+    pub RBUF: u16,
+    pub XCSR: u16,
+    pub input: VecDeque<u8>,
+    pub output: Vec<u8>,
+}
+
+impl DL11 {
+    pub fn new() -> DL11 {
+        DL11 {
+            RCSR: 0,
+            RBUF: 0,
+            XCSR: 0o200,
+            input: VecDeque::new(),
+            output: Vec::new(),
+        }
+    }
+}
+
+impl Default for DL11 {
+    fn default() -> DL11 {
+        DL11::new()
+    }
+}
+
+impl Device for DL11 {
+    fn name(&self) -> &'static str {
+        "dl11"
+    }
+
+    fn handles(&self, address: u32) -> bool {
+        (0o777560..=0o777566).contains(&address)
+    }
+
+    fn read(&mut self, address: u32) -> u16 {
+        match address {
+            0o777560 => {
+                // Pick up the next typed character once the last one has been read
+                if self.RCSR & 0o200 == 0 {
+                    if let Some(character) = self.input.pop_front() {
+                        self.RBUF = character as u16;
+                        self.RCSR |= 0o200;
+                    }
+                }
+                self.RCSR
+            },
+            0o777562 => {
+                self.RCSR &= !0o200;
+                self.RBUF
+            },
+            0o777564 => self.XCSR,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, address: u32, value: u16, _byte: bool) {
+        match address & !1 {
+            0o777560 => self.RCSR = (self.RCSR & 0o200) | (value & 0o100),
+            0o777564 => self.XCSR = (self.XCSR & 0o200) | (value & 0o100),
+            0o777566 => self.output.push((value & 0o177) as u8),
+            _ => {},
+        }
+    }
+
+    fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    fn input(&mut self, character: u8) -> bool {
+        self.input.push_back(character);
+        true
+    }
+
+    fn save(&self, bytes: &mut Vec<u8>) {
+        for register in [self.RCSR, self.RBUF, self.XCSR] {
+            bytes.extend_from_slice(&register.to_le_bytes());
+        }
+        bytes.extend_from_slice(&(self.input.len() as u32).to_le_bytes());
+        bytes.extend(self.input.iter());
+        bytes.extend_from_slice(&(self.output.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.output);
+    }
+
+    fn restore(&mut self, reader: &mut Reader) -> Result<(), String> {
+        self.RCSR = reader.u16()?;
+        self.RBUF = reader.u16()?;
+        self.XCSR = reader.u16()?;
+        let length = reader.u32()? as usize;
+        self.input = reader.bytes(length)?.iter().copied().collect();
+        let length = reader.u32()? as usize;
+        self.output = reader.bytes(length)?.to_vec();
+        Ok(())
+    }
+}
+
+// KW11-L line time clock at 777546
+//  777546 LKS  bit 7 MONITOR set each line clock tick, bit 6 interrupt enable
+// Ticks come from the mains, every LINE_PERIOD_NS of emulated time from power up
+// TODO: Interrupts
+pub const LINE_PERIOD_NS: u64 = 16_666_667;
+
+pub struct KW11L {
+    pub LKS: u16,
+}
+
+impl KW11L {
+    pub fn new() -> KW11L {
+        KW11L { LKS: 0o200 }
+    }
+}
+
+impl Default for KW11L {
+    fn default() -> KW11L {
+        KW11L::new()
+    }
+}
+
+impl Device for KW11L {
+    fn name(&self) -> &'static str {
+        "kw11l"
+    }
+
+    fn handles(&self, address: u32) -> bool {
+        address == 0o777546
+    }
+
+    fn read(&mut self, _address: u32) -> u16 {
+        self.LKS
+    }
+
+    fn write(&mut self, _address: u32, value: u16, _byte: bool) {
+        // Writing clears MONITOR, only the interrupt enable is writable
+        self.LKS = value & 0o100;
+    }
+
+    fn next_event_ns(&self, now_ns: u64) -> Option<u64> {
+        Some((now_ns / LINE_PERIOD_NS + 1) * LINE_PERIOD_NS)
+    }
+
+    fn event(&mut self, _time_ns: u64) {
+        self.LKS |= 0o200;
+    }
+
+    fn save(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&self.LKS.to_le_bytes());
+    }
+
+    fn restore(&mut self, reader: &mut Reader) -> Result<(), String> {
+        self.LKS = reader.u16()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,225 @@
+#![allow(non_snake_case)]
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::clock::ClockPulse;
+use crate::machinestate::MachineState;
+use crate::trace;
+
+// Value Change Dump of the processor signals, for GTKWave and other waveform viewers
+// Times are emulated nanoseconds, so a dump lines up with a logic analyser capture of a real
+// KD11-A. Each clock pulse is drawn PULSE_WIDTH_NS wide from the time it comes, the microprogram,
+// clock mode and data path values change at the pulse that clocks them. MSYN is high for the
+// time the clock is stopped waiting for SSYN on a Unibus cycle.
+// TODO: PULSE_WIDTH_NS is a guess, check against the clock circuit in the print set
+
+pub const PULSE_WIDTH_NS: u64 = 20;
+
+// Signal name and width in bits, in the order of the values passed to change()
+const SIGNALS: [(&str, u32); 17] = [
+    ("UPP", 8),
+    ("PUPP", 8),
+    ("BUBC", 6),
+    ("CLK", 2),
+    ("P1", 1),
+    ("P2", 1),
+    ("P3", 1),
+    ("BMUX", 16),
+    ("DMUX", 16),
+    ("D", 16),
+    ("B", 16),
+    ("BA", 16),
+    ("BGBUS", 1),
+    ("C1BUS", 1),
+    ("C0BUS", 1),
+    ("MSYN", 1),
+    ("SWITCH", 1),
+];
+
+const P1: usize = 4;
+const BGBUS: usize = 12;
+const MSYN: usize = 15;
+
+pub struct VcdWriter {
+    writer: BufWriter<File>,
+
+    // The last value written for each signal, None until the first
+    values: [Option<u64>; SIGNALS.len()],
+
+    // The time of the last timestamp written
+    time_ns: Option<u64>,
+}
+
+impl VcdWriter {
+    pub fn create(path: &Path) -> io::Result<VcdWriter> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "$version pdp11-microcode-emulator $end")?;
+        writeln!(writer, "$timescale 1ns $end")?;
+        writeln!(writer, "$scope module kd11a $end")?;
+        for (index, (name, width)) in SIGNALS.iter().enumerate() {
+            let kind = if *width == 1 { "wire" } else { "reg" };
+            writeln!(writer, "$var {} {} {} {} $end", kind, width, identifier(index), name)?;
+        }
+        writeln!(writer, "$upscope $end")?;
+        writeln!(writer, "$enddefinitions $end")?;
+        Ok(VcdWriter {
+            writer,
+            values: [None; SIGNALS.len()],
+            time_ns: None,
+        })
+    }
+
+    // Dump a clock pulse that came at pulse_ns, call once it has run
+    // BUS is the bus control field of the microword the pulse ran
+    pub fn pulse(&mut self, state: &MachineState, pulse: &ClockPulse, pulse_ns: u64, BUS: u8) -> io::Result<()> {
+        let mut values = self.signals(state);
+        values[P1] = (*pulse == ClockPulse::P1) as u64;
+        values[P1 + 1] = (*pulse == ClockPulse::P2) as u64;
+        values[P1 + 2] = (*pulse == ClockPulse::P3) as u64;
+        values[BGBUS] = (BUS & 0b001 != 0) as u64;
+        values[BGBUS + 1] = (BUS & 0b100 != 0) as u64;
+        values[BGBUS + 2] = (BUS & 0b010 != 0) as u64;
+        // Only a bus cycle stops the clock part way through a pulse
+        let wait = state.TIME_NS > pulse_ns;
+        values[MSYN] = wait as u64;
+        self.change(pulse_ns, &values)?;
+
+        values[P1..P1 + 3].fill(0);
+        self.change(pulse_ns + PULSE_WIDTH_NS, &values)?;
+        if wait {
+            values[MSYN] = 0;
+            self.change(state.TIME_NS, &values)?;
+        }
+        Ok(())
+    }
+
+    // Dump the machine as it is now, between clock pulses: at the start and while the clock is stopped
+    // The clock pulses and bus control lines stay as the last pulse left them
+    pub fn sample(&mut self, state: &MachineState) -> io::Result<()> {
+        let mut values = self.signals(state);
+        for index in (P1..P1 + 3).chain(BGBUS..=MSYN) {
+            values[index] = self.values[index].unwrap_or(0);
+        }
+        self.change(state.TIME_NS, &values)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    // Every signal but the clock pulses and bus control lines, those are filled in by the caller
+    fn signals(&self, state: &MachineState) -> [u64; SIGNALS.len()] {
+        let mut values = [0; SIGNALS.len()];
+        values[0] = state.UPP as u64;
+        values[1] = state.PUPP as u64;
+        values[2] = state.BUBC as u64;
+        values[3] = trace::clock_mode_number(&state.CLK_MODE.mode) as u64;
+        values[7] = state.B_MUX as u64;
+        values[8] = state.D_MUX as u64;
+        values[9] = state.D as u64;
+        values[10] = state.B as u64;
+        values[11] = state.BA as u64;
+        values[16] = state.SWITCH as u64;
+        values
+    }
+
+    // Write the signals that changed, at time_ns
+    // Times never go backwards in a dump, an earlier time is written at the last one
+    fn change(&mut self, time_ns: u64, values: &[u64; SIGNALS.len()]) -> io::Result<()> {
+        let time_ns = time_ns.max(self.time_ns.unwrap_or(0));
+        for (index, value) in values.iter().enumerate() {
+            if self.values[index] == Some(*value) {
+                continue;
+            }
+            if self.time_ns != Some(time_ns) {
+                writeln!(self.writer, "#{}", time_ns)?;
+                self.time_ns = Some(time_ns);
+            }
+            let (_, width) = SIGNALS[index];
+            if width == 1 {
+                writeln!(self.writer, "{}{}", value, identifier(index))?;
+            } else {
+                writeln!(self.writer, "b{:b} {}", value, identifier(index))?;
+            }
+            self.values[index] = Some(*value);
+        }
+        Ok(())
+    }
+}
+
+// VCD identifiers are printable characters from ! on
+fn identifier(index: usize) -> char {
+    (b'!' + index as u8) as char
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::Machine;
+    use crate::microrom::MICROROM;
+    use crate::replay::Input;
+
+    // The first cycles of the console loop, 000 in CL1 then 026 in CL3, 046 in CL1, 027 in CL2
+    fn dump(cycles: usize) -> String {
+        let path = std::env::temp_dir().join(format!("vcd_{}_{}", cycles, std::process::id()));
+        let mut machine = Machine::new(&MICROROM, 0, 0).unwrap();
+        machine.state.SINCLK = false;
+        machine.input(Input::Switch(true)).unwrap();
+        let mut vcd = VcdWriter::create(&path).unwrap();
+        vcd.sample(&machine.state).unwrap();
+        machine.session.vcd = Some(vcd);
+        for _ in 0..cycles {
+            machine.step_cycle().unwrap();
+        }
+        machine.session.vcd.take().unwrap().flush().unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        text
+    }
+
+    // The changes to one signal, (time, value)
+    fn changes(text: &str, name: &str) -> Vec<(u64, String)> {
+        let identifier = identifier(SIGNALS.iter().position(|(signal, _)| *signal == name).unwrap());
+        let mut time = 0;
+        let mut changes = Vec::new();
+        for line in text.lines() {
+            if let Some(stamp) = line.strip_prefix('#') {
+                time = stamp.parse().unwrap();
+            } else if let Some(value) = line.strip_suffix(identifier).filter(|_| !line.starts_with('$')) {
+                changes.push((time, value.trim_start_matches('b').trim_end().to_string()));
+            }
+        }
+        changes
+    }
+
+    fn at(changes: &[(u64, &str)]) -> Vec<(u64, String)> {
+        changes.iter().map(|(time, value)| (*time, value.to_string())).collect()
+    }
+
+    #[test]
+    fn header() {
+        let text = dump(0);
+        assert!(text.starts_with("$version pdp11-microcode-emulator $end\n$timescale 1ns $end\n$scope module kd11a $end\n"));
+        assert!(text.contains("$var reg 8 ! UPP $end\n"));
+        assert!(text.contains("$var reg 2 $ CLK $end\n"));
+        assert!(text.contains("$var wire 1 % P1 $end\n$var wire 1 & P2 $end\n$var wire 1 ' P3 $end\n"));
+        assert!(text.contains("$upscope $end\n$enddefinitions $end\n#0\n"));
+    }
+
+    #[test]
+    fn console_loop() {
+        let text = dump(4);
+        let stamps: Vec<&str> = text.lines().filter(|line| line.starts_with('#')).collect();
+        assert_eq!(stamps, ["#0", "#140", "#160", "#340", "#360", "#440", "#460", "#580", "#600", "#780", "#800"]);
+
+        assert_eq!(changes(&text, "P1"), at(&[(0, "0"), (140, "1"), (160, "0"), (580, "1"), (600, "0")]));
+        assert_eq!(changes(&text, "P2"), at(&[(0, "0"), (340, "1"), (360, "0"), (780, "1"), (800, "0")]));
+        assert_eq!(changes(&text, "P3"), at(&[(0, "0"), (440, "1"), (460, "0")]));
+
+        // Each cycle's last pulse reclocks, CL3 for 026 at 140, CL1 for 046 at 440, CL2 for 027 at 580
+        assert_eq!(changes(&text, "CLK"), at(&[(0, "1"), (140, "11"), (440, "1"), (580, "10"), (780, "11")]));
+        assert_eq!(changes(&text, "UPP"), at(&[(0, "0"), (140, "10110"), (440, "100110"), (580, "10111"), (780, "100100")]));
+    }
+}
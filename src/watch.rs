@@ -0,0 +1,409 @@
+#![allow(non_snake_case)]
+
+use crate::machinestate::MachineState;
+
+// Watchpoints, checked at the end of every machine cycle
+//
+//  reg <n>             A write to scratchpad register n
+//  d <value>           D changes to value
+//  bus <low> [<high>]  A Unibus cycle to an address in low..=high
+//  priority            The PSW priority, bits 7:5, changes
+//  expr <expression>   The expression changes from false to true
+//
+// Numbers are octal. Expressions are made of MachineState field names (as in the debugger),
+// octal numbers, parentheses and the operators below, loosest binding first:
+//  ||
+//  &&
+//  == != < <= > >=
+//  | ^
+//  &
+//  + -
+//  ! (unary)
+
+pub enum Watchpoint {
+    RegisterWrite(u8),
+    DBecomes(u16),
+    BusAccess { low: u32, high: u32 },
+    PriorityChange,
+    Expression { text: String, expression: Expression },
+}
+
+impl Watchpoint {
+    pub fn parse(spec: &str) -> Result<Watchpoint, String> {
+        let spec = spec.trim();
+        let (kind, rest) = spec.split_once(char::is_whitespace).unwrap_or((spec, ""));
+        let rest = rest.trim();
+        let arguments: Vec<&str> = rest.split_whitespace().collect();
+        match kind.to_lowercase().as_str() {
+            "reg" => {
+                let register = octal(arguments.first().ok_or("reg needs a register number")?)?;
+                if register > 0o17 {
+                    return Err(format!("no register {:o}", register));
+                }
+                Ok(Watchpoint::RegisterWrite(register as u8))
+            },
+            "d" => Ok(Watchpoint::DBecomes(octal(arguments.first().ok_or("d needs a value")?)? as u16)),
+            "bus" => {
+                let low = octal(arguments.first().ok_or("bus needs an address")?)? as u32;
+                let high = match arguments.get(1) {
+                    Some(high) => octal(high)? as u32,
+                    None => low,
+                };
+                Ok(Watchpoint::BusAccess { low, high })
+            },
+            "priority" => Ok(Watchpoint::PriorityChange),
+            "expr" => Ok(Watchpoint::Expression {
+                text: rest.to_string(),
+                expression: Expression::parse(rest)?,
+            }),
+            _ => Err(format!("unknown watchpoint '{}', expected reg, d, bus, priority or expr", kind)),
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Watchpoint::RegisterWrite(register) => format!("write to R{:o}", register),
+            Watchpoint::DBecomes(value) => format!("D becomes {:06o}", value),
+            Watchpoint::BusAccess { low, high } => format!("Unibus access to {:06o}-{:06o}", low, high),
+            Watchpoint::PriorityChange => String::from("PSW priority change"),
+            Watchpoint::Expression { text, .. } => format!("expression {}", text),
+        }
+    }
+}
+
+pub struct Watchpoints {
+    pub list: Vec<Watchpoint>,
+
+    // Values from the end of the last machine cycle, for the watchpoints that trigger on a change
+    last_D: Option<u16>,
+    last_priority: Option<u16>,
+    last_expressions: Vec<bool>,
+}
+
+impl Watchpoints {
+    pub fn new() -> Watchpoints {
+        Watchpoints {
+            list: Vec::new(),
+            last_D: None,
+            last_priority: None,
+            last_expressions: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, watchpoint: Watchpoint) {
+        self.list.push(watchpoint);
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<Watchpoint> {
+        if index < self.list.len() {
+            if index < self.last_expressions.len() {
+                self.last_expressions.remove(index);
+            }
+            Some(self.list.remove(index))
+        } else {
+            None
+        }
+    }
+
+    // Check every watchpoint against the machine cycle that just finished,
+    // returns a description of each one that triggered
+    pub fn check(&mut self, state: &MachineState) -> Vec<String> {
+        let mut hits = Vec::new();
+        self.last_expressions.resize(self.list.len(), false);
+
+        let priority = (state.PSW >> 5) & 0b111;
+        for (index, watchpoint) in self.list.iter().enumerate() {
+            let hit = match watchpoint {
+                Watchpoint::RegisterWrite(register) => {
+                    matches!(state.REGISTER_WRITE, Some((address, _)) if address == *register)
+                },
+                Watchpoint::DBecomes(value) => state.D == *value && self.last_D != Some(*value),
+                Watchpoint::BusAccess { low, high } => {
+                    matches!(state.BUS_ACCESS, Some((_, address)) if (*low..=*high).contains(&address))
+                },
+                Watchpoint::PriorityChange => self.last_priority.is_some_and(|last| last != priority),
+                Watchpoint::Expression { expression, .. } => {
+                    let value = expression.evaluate(state) != 0;
+                    let rising = value && !self.last_expressions[index];
+                    self.last_expressions[index] = value;
+                    rising
+                },
+            };
+            if hit {
+                hits.push(format!("Watchpoint {}: {}", index, watchpoint.describe()));
+            }
+        }
+
+        self.last_D = Some(state.D);
+        self.last_priority = Some(priority);
+        hits
+    }
+}
+
+impl Default for Watchpoints {
+    fn default() -> Watchpoints {
+        Watchpoints::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+    Or,
+    And,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Add,
+    Subtract,
+}
+
+impl Operator {
+    fn binding(&self) -> u8 {
+        match self {
+            Operator::Or => 1,
+            Operator::And => 2,
+            Operator::Equal | Operator::NotEqual | Operator::Less |
+            Operator::LessEqual | Operator::Greater | Operator::GreaterEqual => 3,
+            Operator::BitOr | Operator::BitXor => 4,
+            Operator::BitAnd => 5,
+            Operator::Add | Operator::Subtract => 6,
+        }
+    }
+
+    fn apply(&self, left: u64, right: u64) -> u64 {
+        match self {
+            Operator::Or => (left != 0 || right != 0) as u64,
+            Operator::And => (left != 0 && right != 0) as u64,
+            Operator::Equal => (left == right) as u64,
+            Operator::NotEqual => (left != right) as u64,
+            Operator::Less => (left < right) as u64,
+            Operator::LessEqual => (left <= right) as u64,
+            Operator::Greater => (left > right) as u64,
+            Operator::GreaterEqual => (left >= right) as u64,
+            Operator::BitOr => left | right,
+            Operator::BitXor => left ^ right,
+            Operator::BitAnd => left & right,
+            Operator::Add => left.wrapping_add(right),
+            Operator::Subtract => left.wrapping_sub(right),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(u64),
+    Field(String),
+    Operator(Operator),
+    Not,
+    Open,
+    Close,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    Number(u64),
+    Field(String),
+    Not(Box<Expression>),
+    Binary(Operator, Box<Expression>, Box<Expression>),
+}
+
+impl Expression {
+    pub fn parse(text: &str) -> Result<Expression, String> {
+        let tokens = tokenize(text)?;
+        let mut position = 0;
+        let expression = parse_binary(&tokens, &mut position, 0)?;
+        if position != tokens.len() {
+            return Err(format!("unexpected {:?} in expression", tokens[position]));
+        }
+        Ok(expression)
+    }
+
+    // Unknown field names read as 0, parse() has already checked them
+    pub fn evaluate(&self, state: &MachineState) -> u64 {
+        match self {
+            Expression::Number(value) => *value,
+            Expression::Field(name) => state.field(name).unwrap_or(0),
+            Expression::Not(operand) => (operand.evaluate(state) == 0) as u64,
+            Expression::Binary(operator, left, right) => operator.apply(left.evaluate(state), right.evaluate(state)),
+        }
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let characters: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < characters.len() {
+        let c = characters[i];
+        let next = characters.get(i + 1).copied();
+        let (token, length) = match (c, next) {
+            (' ' | '\t', _) => { i += 1; continue; },
+            ('|', Some('|')) => (Token::Operator(Operator::Or), 2),
+            ('&', Some('&')) => (Token::Operator(Operator::And), 2),
+            ('=', Some('=')) => (Token::Operator(Operator::Equal), 2),
+            ('!', Some('=')) => (Token::Operator(Operator::NotEqual), 2),
+            ('<', Some('=')) => (Token::Operator(Operator::LessEqual), 2),
+            ('>', Some('=')) => (Token::Operator(Operator::GreaterEqual), 2),
+            ('<', _) => (Token::Operator(Operator::Less), 1),
+            ('>', _) => (Token::Operator(Operator::Greater), 1),
+            ('|', _) => (Token::Operator(Operator::BitOr), 1),
+            ('^', _) => (Token::Operator(Operator::BitXor), 1),
+            ('&', _) => (Token::Operator(Operator::BitAnd), 1),
+            ('+', _) => (Token::Operator(Operator::Add), 1),
+            ('-', _) => (Token::Operator(Operator::Subtract), 1),
+            ('!', _) => (Token::Not, 1),
+            ('(', _) => (Token::Open, 1),
+            (')', _) => (Token::Close, 1),
+            _ if c.is_ascii_alphanumeric() || c == '_' => {
+                let word: String = characters[i..].iter()
+                    .take_while(|c| c.is_ascii_alphanumeric() || **c == '_')
+                    .collect();
+                let token = if word.chars().all(|c| c.is_ascii_digit()) {
+                    Token::Number(octal(&word)?)
+                } else if MachineState::is_field(&word) {
+                    Token::Field(word.to_uppercase())
+                } else {
+                    return Err(format!("unknown field '{}'", word));
+                };
+                let length = word.len();
+                (token, length)
+            },
+            _ => return Err(format!("unexpected '{}' in expression", c)),
+        };
+        tokens.push(token);
+        i += length;
+    }
+    Ok(tokens)
+}
+
+// Precedence climbing, binds every operator tighter than minimum
+fn parse_binary(tokens: &[Token], position: &mut usize, minimum: u8) -> Result<Expression, String> {
+    let mut left = parse_operand(tokens, position)?;
+    while let Some(Token::Operator(operator)) = tokens.get(*position) {
+        if operator.binding() <= minimum {
+            break;
+        }
+        *position += 1;
+        let right = parse_binary(tokens, position, operator.binding())?;
+        left = Expression::Binary(*operator, Box::new(left), Box::new(right));
+    }
+    Ok(left)
+}
+
+fn parse_operand(tokens: &[Token], position: &mut usize) -> Result<Expression, String> {
+    let token = tokens.get(*position).ok_or("expression ends early")?;
+    *position += 1;
+    match token {
+        Token::Number(value) => Ok(Expression::Number(*value)),
+        Token::Field(name) => Ok(Expression::Field(name.clone())),
+        Token::Not => Ok(Expression::Not(Box::new(parse_operand(tokens, position)?))),
+        Token::Open => {
+            let expression = parse_binary(tokens, position, 0)?;
+            match tokens.get(*position) {
+                Some(Token::Close) => {
+                    *position += 1;
+                    Ok(expression)
+                },
+                _ => Err(String::from("missing ) in expression")),
+            }
+        },
+        _ => Err(format!("unexpected {:?} in expression", token)),
+    }
+}
+
+fn octal(text: &str) -> Result<u64, String> {
+    u64::from_str_radix(text, 8).map_err(|_| format!("'{}' is not an octal number", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::microrom::MICROROM;
+
+    fn number(value: u64) -> Box<Expression> {
+        Box::new(Expression::Number(value))
+    }
+
+    fn field(name: &str) -> Box<Expression> {
+        Box::new(Expression::Field(name.to_string()))
+    }
+
+    fn evaluate(text: &str, state: &MachineState) -> u64 {
+        Expression::parse(text).unwrap().evaluate(state)
+    }
+
+    #[test]
+    fn precedence() {
+        use Operator::*;
+        assert_eq!(Expression::parse("1 + 2 & 3").unwrap(),
+            Expression::Binary(BitAnd, Box::new(Expression::Binary(Add, number(1), number(2))), number(3)));
+        assert_eq!(Expression::parse("UPP == 47 && D > 100").unwrap(),
+            Expression::Binary(And,
+                Box::new(Expression::Binary(Equal, field("UPP"), number(0o47))),
+                Box::new(Expression::Binary(Greater, field("D"), number(0o100)))));
+        assert_eq!(Expression::parse("!D == 0").unwrap(),
+            Expression::Binary(Equal, Box::new(Expression::Not(field("D"))), number(0)));
+        // Left to right at the same binding
+        assert_eq!(Expression::parse("7 - 2 - 1").unwrap(),
+            Expression::Binary(Subtract, Box::new(Expression::Binary(Subtract, number(7), number(2))), number(1)));
+
+        let state = MachineState::new(&MICROROM, 0).unwrap();
+        for (text, value) in [
+            ("1 || 0 && 0", 1), ("(1 || 0) && 0", 0),
+            ("1 | 2 == 3", 1), ("1 | (2 == 3)", 1), ("6 & 3 ^ 1", 3), ("6 ^ 3 & 1", 7),
+            ("2 + 3 & 4", 4), ("10 - 1", 7), ("0 - 1", u64::MAX),
+            ("!0 + 1", 2), ("!(0 + 1)", 0), ("!!5", 1),
+            ("3 < 4 == 1", 1), ("4 <= 4", 1), ("4 >= 5", 0), ("4 != 5", 1),
+        ] {
+            assert_eq!(evaluate(text, &state), value, "{}", text);
+        }
+    }
+
+    #[test]
+    fn octal_literals() {
+        assert_eq!(Expression::parse("177777").unwrap(), Expression::Number(0o177777));
+        assert_eq!(Expression::parse("010").unwrap(), Expression::Number(8));
+        assert!(Expression::parse("8").unwrap_err().contains("not an octal number"));
+        assert!(Expression::parse("1779").unwrap_err().contains("not an octal number"));
+        assert!(Expression::parse("77777777777777777777777").unwrap_err().contains("not an octal number"));
+    }
+
+    #[test]
+    fn register_and_bus_terms() {
+        let mut state = MachineState::new(&MICROROM, 0).unwrap();
+        state.REGISTERS[0o7] = 0o1000;
+        state.REGISTERS[0o17] = 0o5;
+        state.BA = 0o177566;
+        state.BUS_D = 0o200;
+        assert_eq!(Expression::parse("r17 + R7").unwrap(), Expression::Binary(Operator::Add, field("R17"), field("R7")));
+        assert_eq!(evaluate("R7 + r17", &state), 0o1005);
+        assert_eq!(evaluate("BA == 177566 && BUS_D & 200", &state), 1);
+        assert_eq!(evaluate("ba - 177560", &state), 0o6);
+        assert!(Expression::parse("R20").unwrap_err().contains("unknown field 'R20'"));
+        assert!(Expression::parse("R8").unwrap_err().contains("unknown field 'R8'"));
+
+        assert!(matches!(Watchpoint::parse("bus 177560 177566"), Ok(Watchpoint::BusAccess { low: 0o177560, high: 0o177566 })));
+        assert!(matches!(Watchpoint::parse("bus 1000"), Ok(Watchpoint::BusAccess { low: 0o1000, high: 0o1000 })));
+        assert!(matches!(Watchpoint::parse("reg 17"), Ok(Watchpoint::RegisterWrite(0o17))));
+        assert!(Watchpoint::parse("reg 20").is_err());
+    }
+
+    #[test]
+    fn malformed_expressions() {
+        for text in [
+            "", " ", "UPP ==", "== 1", "(UPP", "UPP)", "()", "UPP 1", "!", "1 +* 2",
+            "UPP = 1", "foo", "0x10", "UPP $ 1", "((((", "))", "D >",
+        ] {
+            assert!(Expression::parse(text).is_err(), "{:?}", text);
+        }
+        for spec in ["", "expr", "expr (", "bus", "bus x", "d", "reg", "watch 1"] {
+            assert!(Watchpoint::parse(spec).is_err(), "{:?}", spec);
+        }
+    }
+}
@@ -0,0 +1,58 @@
+#![allow(non_snake_case)]
+
+// The clock stopped by a CLKOFF microword, and restarted by SSYN or the console
+
+use pdp11_microcode_emulator::clock::ClockMode;
+use pdp11_microcode_emulator::microrom::{MicroRom, MicroWord};
+use pdp11_microcode_emulator::replay::Input;
+use pdp11_microcode_emulator::rom_image::bits_to_microword;
+use pdp11_microcode_emulator::Machine;
+
+// 000 in CL1 -> 001 with the CLK given -> 002 in CL1, looping
+fn rom(CLK: u8, build_000: impl Fn(&mut MicroWord)) -> MicroRom {
+    let mut words: [MicroWord; 256] = std::array::from_fn(|_| bits_to_microword(0, false));
+    for (UPP, clock, UPF) in [(0, 2, 1), (1, CLK, 2), (2, 2, 2)] {
+        words[UPP].implemented = true;
+        words[UPP].CLK = clock;
+        words[UPP].UPF = UPF;
+    }
+    build_000(&mut words[0]);
+    MicroRom::new(words)
+}
+
+#[test]
+fn clkoff_stops_until_switch() {
+    for CLK in [0, 7] {
+        let ROM = rom(CLK, |_| {});
+        let mut machine = Machine::new(&ROM, 0, 0).unwrap();
+        machine.step_cycle().unwrap();
+        assert!(machine.clock_stopped(), "CLK {}", CLK);
+        assert_eq!((machine.state.UPP, machine.state.CYCLE, machine.state.TIME_NS), (0o001, 1, 140));
+
+        // Nothing runs while it waits, time goes on in 300 ns steps
+        for _ in 0..3 {
+            machine.step_cycle().unwrap();
+        }
+        assert_eq!((machine.state.UPP, machine.state.CYCLE, machine.state.TIME_NS), (0o001, 1, 1040));
+        assert!(machine.clock_stopped());
+
+        // SWITCH restarts it with the stopped microword, 001, as a CL3 cycle
+        machine.input(Input::Switch(true)).unwrap();
+        machine.step_cycle().unwrap();
+        assert_eq!((machine.state.PUPP, machine.state.UPP, machine.state.CYCLE, machine.state.TIME_NS), (0o001, 0o002, 2, 1340));
+        assert_eq!(machine.state.CLK_MODE.mode, ClockMode::CL1);
+    }
+}
+
+// After a bus cycle SSYN has already come back, CLKOFF doesn't wait
+#[test]
+fn clkoff_after_a_bus_cycle_runs_on() {
+    // DATI from 0
+    let ROM = rom(0, |word| word.BUS = 0b001);
+    let mut machine = Machine::new(&ROM, 0, 1).unwrap();
+    machine.step_cycle().unwrap();
+    assert!(!machine.clock_stopped());
+    assert_eq!(machine.state.CLK_MODE.mode, ClockMode::CL3);
+    machine.step_cycle().unwrap();
+    assert_eq!((machine.state.PUPP, machine.state.UPP), (0o001, 0o002));
+}
@@ -0,0 +1,133 @@
+#![allow(non_snake_case)]
+
+// The console loop, the only microcode transcribed so far
+//  000 -> 026 TEST FOR SWITCH -> 046 NO-OP FOR BUT -> 027 CONTACT BOUNCE COUNT
+//      -> 044 TEST COUNT <-> 047 INCREMENT COUNT, until D counts round to 0 and 044 branches to 045
+
+use pdp11_microcode_emulator::clock::ClockMode;
+use pdp11_microcode_emulator::debugger::Debugger;
+use pdp11_microcode_emulator::machine::{StepEnd, INSTRUCTION_CYCLE_LIMIT};
+use pdp11_microcode_emulator::replay::Input;
+use pdp11_microcode_emulator::{EmulatorError, Machine, MICROROM};
+
+fn machine(SINCLK: bool) -> Machine<'static> {
+    let mut machine = Machine::new(&MICROROM, 0, 0).unwrap();
+    machine.state.SINCLK = SINCLK;
+    machine.input(Input::Switch(true)).unwrap();
+    machine
+}
+
+#[test]
+fn microword_sequence() {
+    let mut machine = machine(false);
+    let mut sequence = vec![machine.state().UPP];
+    for _ in 0..8 {
+        machine.step_cycle().unwrap();
+        sequence.push(machine.state().UPP);
+    }
+    assert_eq!(sequence, [0o000, 0o026, 0o046, 0o027, 0o044, 0o047, 0o044, 0o047, 0o044]);
+}
+
+#[test]
+fn bounce_count_starts_at_20_and_counts_up() {
+    let mut machine = machine(false);
+    machine.run_until(4, |_| false).unwrap();
+    assert_eq!(machine.state().UPP, 0o044);
+    assert_eq!(machine.state().D, 0o20);
+
+    // Each pass through 047 adds one
+    for count in 0o21..0o30 {
+        machine.run_until(2, |_| false).unwrap();
+        assert_eq!(machine.state().UPP, 0o044);
+        assert_eq!(machine.state().D, count);
+    }
+}
+
+// Each microword's CLK field picks the clock for the cycle after it
+#[test]
+fn clock_modes() {
+    let mut machine = machine(false);
+    let mut modes = Vec::new();
+    for _ in 0..6 {
+        machine.step_cycle().unwrap();
+        modes.push(format!("{:?}", machine.state().CLK_MODE.mode));
+    }
+    assert_eq!(modes, ["CL3", "CL1", "CL2", "CL3", "CL3", "CL3"]);
+    assert_ne!(machine.state().CLK_MODE.mode, ClockMode::OFF);
+}
+
+#[test]
+fn debounce_runs_to_the_end_of_the_microcode() {
+    let mut machine = machine(false);
+    let error = machine.run_until(u64::MAX, |_| false).unwrap_err();
+    assert_eq!(error, EmulatorError::UnimplementedMicroword { UPP: 0o45, PUPP: 0o47 });
+    assert_eq!(machine.state().CYCLE, 131045);
+    // The branch on D = 0 takes effect a cycle late, out of 047. Its machine cycle is taken
+    // back when 045 isn't there, so the machine stops about to run 047 with the count at 0
+    assert_eq!((machine.state().UPP, machine.state().PUPP, machine.state().D), (0o047, 0o044, 0));
+    assert_eq!(machine.halted(), Some(error));
+}
+
+#[test]
+fn single_clock_skips_the_debounce() {
+    let mut machine = machine(true);
+    let error = machine.run_until(u64::MAX, |_| false).unwrap_err();
+    assert_eq!(error, EmulatorError::UnimplementedMicroword { UPP: 0o45, PUPP: 0o47 });
+    assert_eq!(machine.state().CYCLE, 5);
+}
+
+#[test]
+fn waits_for_switch() {
+    let mut machine = Machine::new(&MICROROM, 0, 0).unwrap();
+    let mut visited = Vec::new();
+    machine.run_until(20, |state| {
+        visited.push(state.UPP);
+        false
+    }).unwrap();
+    assert!(visited.iter().all(|UPP| [0o026, 0o046].contains(UPP)), "{:?}", visited);
+
+    // Then the switch lets it through to the count
+    machine.input(Input::Switch(true)).unwrap();
+    machine.run_until(4, |state| state.UPP == 0o027).unwrap();
+    assert_eq!(machine.state().UPP, 0o027);
+}
+
+#[test]
+fn predecoded_matches_direct() {
+    let mut direct = machine(false);
+    let mut decoded = machine(false);
+    decoded.predecode();
+    for _ in 0..1000 {
+        direct.step_cycle().unwrap();
+        decoded.run_headless(1).unwrap();
+        let (a, b) = (direct.state(), decoded.state());
+        assert_eq!((a.UPP, a.PUPP, a.BUBC, a.B_MUX, a.D_MUX, a.ALU, a.D, a.TIME_NS), (b.UPP, b.PUPP, b.BUBC, b.B_MUX, b.D_MUX, b.ALU, b.D, b.TIME_NS));
+    }
+}
+
+// Nothing in the console loop clocks the IR, so an instruction step gives up instead of spinning
+#[test]
+fn instruction_step_stops_without_a_fetch() {
+    let mut machine = Machine::new(&MICROROM, 0, 0).unwrap();
+    let step = machine.step_instruction().unwrap();
+    assert_eq!(step.end, StepEnd::CycleLimit);
+    assert_eq!(step.cycles, INSTRUCTION_CYCLE_LIMIT);
+    assert_eq!(machine.halted(), None);
+}
+
+// The BUT at 026 takes effect out of 046, so the debugger stops there with the branch in UPP
+#[test]
+fn until_branch_on_the_switch_test() {
+    let mut machine = machine(false);
+    let mut debugger = Debugger::new();
+    assert!(debugger.command("ub 026", &mut machine).unwrap());
+    assert_eq!((machine.state().PUPP, machine.state().UPP), (0o046, 0o027));
+    assert_eq!(machine.state().CYCLE, 3);
+
+    // Without the switch 026 never branches, the loop goes round until the switch comes on
+    let mut machine = Machine::new(&MICROROM, 0, 0).unwrap();
+    debugger.command("s 10", &mut machine).unwrap();
+    machine.input(Input::Switch(true)).unwrap();
+    debugger.command("ub 026 027", &mut machine).unwrap();
+    assert_eq!((machine.state().PUPP, machine.state().UPP), (0o046, 0o027));
+}
@@ -0,0 +1,73 @@
+#![allow(non_snake_case)]
+
+// The microcode against the instruction level reference model, on random instruction sequences
+
+use pdp11_microcode_emulator::differential::{self, ORIGIN};
+use pdp11_microcode_emulator::machine::INSTRUCTION_CYCLE_LIMIT;
+use pdp11_microcode_emulator::microrom::{MicroRom, MicroWord};
+use pdp11_microcode_emulator::reference::{Outcome, Reference};
+use pdp11_microcode_emulator::rom_image::bits_to_microword;
+use pdp11_microcode_emulator::MICROROM;
+
+const SEEDS: u64 = 200;
+const INSTRUCTIONS: usize = 20;
+
+// Every case runs on the reference to a HALT, either its own or the one the trap vectors point at
+#[test]
+fn random_cases_halt_on_the_reference() {
+    for seed in 1..=SEEDS {
+        let case = differential::random_case(seed, INSTRUCTIONS);
+        let mut model = Reference::new(case.memory.len());
+        model.REGISTERS = case.REGISTERS;
+        model.PSW = case.PSW;
+        model.memory.copy_from_slice(&case.memory);
+        let mut outcomes = Vec::new();
+        while outcomes.len() <= INSTRUCTIONS + 1 {
+            outcomes.push(model.step());
+            if outcomes.last() == Some(&Outcome::Halt) {
+                break;
+            }
+        }
+        assert_eq!(outcomes.last(), Some(&Outcome::Halt), "seed {}: {:?}", seed, outcomes);
+    }
+}
+
+#[test]
+fn same_seed_same_case() {
+    let (first, second) = (differential::random_case(7, INSTRUCTIONS), differential::random_case(7, INSTRUCTIONS));
+    assert_eq!((first.REGISTERS, first.PSW, &first.memory), (second.REGISTERS, second.PSW, &second.memory));
+    assert_ne!(first.memory, differential::random_case(8, INSTRUCTIONS).memory);
+}
+
+// The console loop is all the microcode there is, so it never gets to a fetch
+#[test]
+fn divergence_has_the_microcycles() {
+    let divergence = differential::run(&MICROROM, &differential::random_case(1, INSTRUCTIONS)).unwrap_err();
+    assert_eq!((divergence.instruction, divergence.PC), (0, ORIGIN));
+    assert!(divergence.differences[0].starts_with("microcode stopped before the first fetch"), "{}", divergence);
+    assert!(divergence.trace.len() > 5);
+    assert!(divergence.trace[1].contains("UPP=026"), "{}", divergence);
+}
+
+// Microcode that loops without fetching gives up rather than hanging the test
+#[test]
+fn no_fetch_gives_up() {
+    let mut words: [MicroWord; 256] = std::array::from_fn(|_| bits_to_microword(0, false));
+    words[0].implemented = true;
+    words[0].CLK = 2;
+    let ROM = MicroRom::new(words);
+    let divergence = differential::run(&ROM, &differential::random_case(1, INSTRUCTIONS)).unwrap_err();
+    assert_eq!(divergence.differences[0],
+        format!("microcode stopped before the first fetch: no instruction fetch in {} machine cycles", INSTRUCTION_CYCLE_LIMIT));
+    assert_eq!(divergence.trace.len() as u64, INSTRUCTION_CYCLE_LIMIT);
+}
+
+#[test]
+#[ignore = "the microcode doesn't fetch instructions yet"]
+fn microcode_matches_the_reference() {
+    for seed in 1..=SEEDS {
+        if let Err(divergence) = differential::run(&MICROROM, &differential::random_case(seed, INSTRUCTIONS)) {
+            panic!("{}", divergence);
+        }
+    }
+}
@@ -0,0 +1,154 @@
+#![allow(non_snake_case)]
+
+// Every EmulatorError, and the machine left as it was before the clock pulse that failed
+
+use pdp11_microcode_emulator::microrom::{MicroRom, MicroWord};
+use pdp11_microcode_emulator::rom_image::bits_to_microword;
+use pdp11_microcode_emulator::unibus::device_by_name;
+use pdp11_microcode_emulator::{EmulatorError, Machine, MachineState, MICROROM};
+
+// 000 -> 001 in CL1, 001 looping on itself unless build changes them
+fn rom(build: impl Fn(&mut [MicroWord; 256])) -> MicroRom {
+    let mut words: [MicroWord; 256] = std::array::from_fn(|_| bits_to_microword(0, false));
+    for (UPP, UPF) in [(0, 1), (1, 1)] {
+        words[UPP].implemented = true;
+        words[UPP].CLK = 2;
+        words[UPP].UPF = UPF;
+    }
+    build(&mut words);
+    MicroRom::new(words)
+}
+
+// SDM 10 puts D on the DMUX, so WR writes D into the scratchpad
+const SDM_D: u8 = 0b10;
+
+// BGBUS with C1BUS set, D to BA
+const DATO: u8 = 0b101;
+
+#[derive(Debug, PartialEq)]
+struct Processor {
+    UPP: u8,
+    PUPP: u8,
+    BUPP: u8,
+    BUBC: u8,
+    BUBC_FLUX: u8,
+    PULSE: u32,
+    CYCLE: u64,
+    TIME_NS: u64,
+    D: u16,
+    B: u16,
+    BA: u16,
+    REGISTERS: [u16; 16],
+    REGISTER_WRITE: Option<(u8, u16)>,
+}
+
+fn processor(state: &MachineState) -> Processor {
+    Processor {
+        UPP: state.UPP, PUPP: state.PUPP, BUPP: state.BUPP, BUBC: state.BUBC, BUBC_FLUX: state.BUBC_FLUX,
+        PULSE: state.PULSE, CYCLE: state.CYCLE, TIME_NS: state.TIME_NS,
+        D: state.D, B: state.B, BA: state.BA, REGISTERS: state.REGISTERS, REGISTER_WRITE: state.REGISTER_WRITE,
+    }
+}
+
+// Run until the machine stops, checking it stopped as it was before the failing pulse
+fn stops(machine: &mut Machine) -> EmulatorError {
+    for _ in 0..10 {
+        let before = processor(machine.state());
+        if let Err(error) = machine.step_pulse() {
+            assert_eq!(processor(machine.state()), before, "{}", error);
+            assert_eq!(machine.halted(), Some(error));
+            assert_eq!(machine.step_pulse(), Err(error));
+            return error;
+        }
+    }
+    panic!("the machine didn't stop");
+}
+
+#[test]
+fn unimplemented_microword() {
+    // 000 writes D into R1 on its way to an unimplemented 002
+    let ROM = rom(|words| {
+        words[0].UPF = 2;
+        words[0].WR = 0b11;
+        words[0].RIF = 1;
+        words[0].SDM = SDM_D;
+        words[0].CD = 1;
+    });
+    let mut machine = Machine::new(&ROM, 0, 0).unwrap();
+    machine.state.D = 0o1234;
+    assert_eq!(stops(&mut machine), EmulatorError::UnimplementedMicroword { UPP: 0o002, PUPP: 0o000 });
+    assert_eq!(machine.state().REGISTERS[1], 0);
+}
+
+#[test]
+fn fetch_past_the_transcribed_words() {
+    assert_eq!(MICROROM.fetch(0o377, 0o200).unwrap_err(), EmulatorError::UnimplementedMicroword { UPP: 0o377, PUPP: 0o200 });
+    assert!(MICROROM.fetch(0o026, 0o000).is_ok());
+}
+
+// A BUT is evaluated as its microword is latched, the error stops the cycle before it
+#[test]
+fn unimplemented_but() {
+    let ROM = rom(|words| words[1].UBF = 0o01);
+    let mut machine = Machine::new(&ROM, 0, 0).unwrap();
+    assert_eq!(stops(&mut machine), EmulatorError::UnimplementedBut { UBF: 0o01, UPP: 0o001 });
+    assert_eq!((machine.state().UPP, machine.state().CYCLE), (0o000, 0));
+}
+
+#[test]
+fn unused_but() {
+    let ROM = rom(|words| words[1].UBF = 0o23);
+    let mut machine = Machine::new(&ROM, 0, 0).unwrap();
+    assert_eq!(stops(&mut machine), EmulatorError::UnusedBut { UBF: 0o23, UPP: 0o001 });
+}
+
+#[test]
+fn unimplemented_constant() {
+    let ROM = rom(|words| words[1].SBC = 0o03);
+    let mut machine = Machine::new(&ROM, 0, 0).unwrap();
+    assert_eq!(stops(&mut machine), EmulatorError::UnimplementedConstant { SBC: 0o03, UPP: 0o001 });
+    assert_eq!((machine.state().UPP, machine.state().CYCLE), (0o001, 1));
+}
+
+#[test]
+fn invalid_constant() {
+    let ROM = rom(|words| words[0].SBC = 0o04);
+    let mut machine = Machine::new(&ROM, 0, 0).unwrap();
+    assert_eq!(stops(&mut machine), EmulatorError::InvalidConstant { SBC: 0o04, UPP: 0o000 });
+    assert_eq!(machine.state().TIME_NS, 0);
+}
+
+// CLK is three bits in the ROM, only a hand made microword can have more
+#[test]
+fn invalid_clock() {
+    let ROM = rom(|words| words[1].CLK = 0o10);
+    let mut machine = Machine::new(&ROM, 0, 0).unwrap();
+    assert_eq!(stops(&mut machine), EmulatorError::InvalidClock { CLK: 0o10, UPP: 0o001 });
+}
+
+#[test]
+fn bus_timeout() {
+    let ROM = rom(|words| {
+        words[0].BUS = DATO;
+        words[0].CD = 1;
+    });
+    let mut machine = Machine::new(&ROM, 0, 0o1000).unwrap();
+    machine.state.BA = 0o2000;
+    machine.state.D = 0o7;
+    assert_eq!(stops(&mut machine), EmulatorError::BusTimeout { address: 0o2000, UPP: 0o000 });
+}
+
+// The microword after a DATO isn't there, the device never sees the write
+#[test]
+fn no_bus_cycle_from_a_failed_cycle() {
+    let ROM = rom(|words| {
+        words[0].BUS = DATO;
+        words[0].UPF = 2;
+    });
+    let mut machine = Machine::new(&ROM, 0, 0).unwrap();
+    machine.attach(device_by_name("dl11").unwrap());
+    machine.state.BA = 0o177566;
+    machine.state.D = 'K' as u16;
+    assert_eq!(stops(&mut machine), EmulatorError::UnimplementedMicroword { UPP: 0o002, PUPP: 0o000 });
+    assert!(machine.take_output().is_empty());
+}
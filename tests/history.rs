@@ -0,0 +1,125 @@
+#![allow(non_snake_case)]
+
+// Stepping back over machine cycles, to exactly the machine there was before them
+
+use pdp11_microcode_emulator::microrom::{MicroRom, MicroWord};
+use pdp11_microcode_emulator::rom_image::bits_to_microword;
+use pdp11_microcode_emulator::snapshot;
+use pdp11_microcode_emulator::unibus::device_by_name;
+use pdp11_microcode_emulator::Machine;
+
+// ALU functions, M and S3-S0
+const ALU_A: u8 = 0b00000;
+const ALU_A_MINUS_1: u8 = 0b01111;
+
+// SDM 10 puts D on the DMUX
+const SDM_D: u8 = 0b10;
+
+// BGBUS with C1BUS set, D to BA
+const DATO: u8 = 0b101;
+
+// A loop counting R0 down two at a time, writing each count to memory at the count and to
+// the console terminal at R1, in CL1 but for the memory write in CL3
+fn rom() -> MicroRom {
+    let mut words: [MicroWord; 256] = std::array::from_fn(|_| bits_to_microword(0, false));
+    for (UPP, word) in words.iter_mut().enumerate().take(7) {
+        word.implemented = true;
+        word.CLK = 2;
+        word.UPF = (UPP as u8 + 1) % 7;
+    }
+    for UPP in [0, 2] {
+        // D and BA <- R0 - 1
+        words[UPP].ALU = ALU_A_MINUS_1;
+        words[UPP].CD = 1;
+        words[UPP].CBA = 1;
+    }
+    for UPP in [1, 3] {
+        // R0 <- D
+        words[UPP].SDM = SDM_D;
+        words[UPP].WR = 0b11;
+    }
+    words[4].BUS = DATO;
+    words[4].CLK = 5;
+    // BA <- R1
+    words[5].RIF = 1;
+    words[5].ALU = ALU_A;
+    words[5].CBA = 1;
+    words[6].BUS = DATO;
+    MicroRom::new(words)
+}
+
+fn machine(ROM: &MicroRom, cycles: usize) -> Machine<'_> {
+    let mut machine = Machine::new(ROM, 0, 1024).unwrap();
+    machine.attach(device_by_name("dl11").unwrap());
+    machine.attach(device_by_name("kw11l").unwrap());
+    machine.state.REGISTERS[0] = 0o2000;
+    machine.state.REGISTERS[1] = 0o177566;
+    machine.enable_history(cycles);
+    machine
+}
+
+#[test]
+fn step_back_restores_state_and_memory() {
+    let ROM = rom();
+    let mut machine = machine(&ROM, 1024);
+
+    let mut snapshots = Vec::new();
+    for _ in 0..70 {
+        snapshots.push(snapshot::to_bytes(&machine.state));
+        machine.step_cycle().unwrap();
+    }
+    assert_eq!(machine.state.REGISTERS[0], 0o1754);
+    assert_eq!(machine.state.UNIBUS.peek(0o1754), Some(0o1754));
+    assert_eq!(machine.take_output().len(), 10);
+
+    while let Some(before) = snapshots.pop() {
+        assert!(machine.step_back(), "{} cycles left", snapshots.len() + 1);
+        assert_eq!(snapshot::to_bytes(&machine.state), before, "{} cycles left", snapshots.len());
+    }
+    assert!(!machine.step_back());
+    assert_eq!(machine.state.UNIBUS.peek(0o1754), Some(0));
+
+    // And it runs forwards again the same
+    for _ in 0..70 {
+        machine.step_cycle().unwrap();
+    }
+    assert_eq!((machine.state.REGISTERS[0], machine.state.CYCLE), (0o1754, 70));
+}
+
+#[test]
+fn step_back_part_way_through_a_cycle() {
+    let ROM = rom();
+    let mut machine = machine(&ROM, 1024);
+    for _ in 0..4 {
+        machine.step_cycle().unwrap();
+    }
+
+    // 004's DATO, a CL3 cycle of two pulses with the bus cycle in the second
+    let before = snapshot::to_bytes(&machine.state);
+    assert!(!machine.step_pulse().unwrap());
+    assert!(machine.step_back());
+    assert_eq!(snapshot::to_bytes(&machine.state), before);
+
+    assert!(!machine.step_pulse().unwrap());
+    assert!(machine.step_pulse().unwrap());
+    assert_eq!(machine.state.UNIBUS.peek(0o1776), Some(0o1776));
+    assert!(machine.step_back());
+    assert_eq!(snapshot::to_bytes(&machine.state), before);
+    assert_eq!(machine.state.UNIBUS.peek(0o1776), Some(0));
+}
+
+#[test]
+fn history_is_bounded() {
+    let ROM = rom();
+    let mut machine = machine(&ROM, 10);
+    let mut snapshots = Vec::new();
+    for _ in 0..21 {
+        snapshots.push(snapshot::to_bytes(&machine.state));
+        machine.step_cycle().unwrap();
+    }
+    for _ in 0..10 {
+        assert!(machine.step_back());
+    }
+    assert_eq!(snapshot::to_bytes(&machine.state), snapshots[11]);
+    assert!(!machine.step_back());
+}
@@ -0,0 +1,185 @@
+#![allow(non_snake_case)]
+
+// DEC's MAINDEC diagnostics, loaded from their absolute loader tapes and run on the microcode
+// The tapes aren't part of the emulator. The diagnostic tests are ignored unless asked for with
+// --ignored, and then need MAINDEC_DIR set to a directory with the tapes in it, the diagnostic's
+// name somewhere in each file name (MAINDEC-11-GKAA.BIN, gkaa.ptap). A missing tape fails.
+//
+// Each runs from its start address with the console terminal attached. A pass prints END PASS on
+// the console, a HALT is an error halt and its address is the one to look up in the listing.
+// TODO: The switch register settings to loop on a test
+//
+// tests/tapes/synthetic.ptap is a small tape made for these tests, so the loader and the harness
+// are checked without DEC media: MOV #110,@#177566 and HALT at 1000, three bytes from 1011,
+// and an end block starting at 1000.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use pdp11_microcode_emulator::absolute_loader;
+use pdp11_microcode_emulator::unibus::device_by_name;
+use pdp11_microcode_emulator::{EmulatorError, Machine, MICROROM};
+
+const MEMORY_WORDS: usize = 28 * 1024;
+const BATCH_CYCLES: u64 = 65536;
+const PASS_TEXT: &str = "END PASS";
+
+struct Diagnostic {
+    name: &'static str,
+    title: &'static str,
+    start: u16,
+
+    // Machine cycles to give it for a pass before calling it hung
+    cycles: u64,
+}
+
+const GKAA: Diagnostic = Diagnostic { name: "GKAA", title: "CPU tests, part 1", start: 0o200, cycles: 200_000_000 };
+const GKAB: Diagnostic = Diagnostic { name: "GKAB", title: "CPU tests, part 2", start: 0o200, cycles: 200_000_000 };
+const GKBA: Diagnostic = Diagnostic { name: "GKBA", title: "KE11-E EIS instructions", start: 0o200, cycles: 200_000_000 };
+
+enum Outcome {
+    Passed { cycles: u64 },
+    Halted { PC: u16 },
+    Stopped(EmulatorError),
+    TimedOut,
+}
+
+struct Report {
+    outcome: Outcome,
+    output: String,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.outcome {
+            Outcome::Passed { cycles } => write!(f, "passed in {} machine cycles", cycles)?,
+            Outcome::Halted { PC } => write!(f, "error halt at {:06o}", PC)?,
+            Outcome::Stopped(error) => write!(f, "microcode stopped: {}", error)?,
+            Outcome::TimedOut => write!(f, "no pass or halt")?,
+        }
+        if !self.output.is_empty() {
+            write!(f, "\nconsole:\n{}", self.output)?;
+        }
+        Ok(())
+    }
+}
+
+fn tape_directory() -> PathBuf {
+    match env::var_os("MAINDEC_DIR") {
+        Some(directory) => PathBuf::from(directory),
+        None => panic!("MAINDEC_DIR isn't set, it names the directory with the MAINDEC tapes"),
+    }
+}
+
+fn synthetic_tape() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("tapes").join("synthetic.ptap")
+}
+
+// The first file with the name in it, ignoring case
+fn find_tape(directory: &Path, name: &str) -> Option<PathBuf> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(directory).ok()?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file())
+        .collect();
+    paths.sort();
+    paths.into_iter().find(|path| {
+        path.file_name().is_some_and(|file| file.to_string_lossy().to_uppercase().contains(name))
+    })
+}
+
+fn run(start: u16, cycles: u64, tape: &Path) -> Report {
+    let tape = absolute_loader::read(tape).unwrap();
+    let mut machine = Machine::new(&MICROROM, 0, MEMORY_WORDS).unwrap();
+    machine.attach(device_by_name("dl11").unwrap());
+    machine.attach(device_by_name("kw11l").unwrap());
+    machine.state.SINCLK = false;
+    machine.predecode();
+    absolute_loader::load(&tape, &mut machine.state.UNIBUS).unwrap();
+    machine.start(start).unwrap();
+
+    let mut output = String::new();
+    let first = machine.state.CYCLE;
+    let outcome = loop {
+        let result = machine.run_headless(BATCH_CYCLES);
+        // Parity off, the diagnostics send mark parity
+        output.extend(machine.take_output().iter().map(|byte| (byte & 0o177) as char));
+        let run = machine.state.CYCLE - first;
+        if let Err(error) = result {
+            break Outcome::Stopped(error);
+        }
+        if output.contains(PASS_TEXT) {
+            break Outcome::Passed { cycles: run };
+        }
+        // HALT ends in the console loop with the clock stopped
+        if machine.clock_stopped() {
+            break Outcome::Halted { PC: machine.state.IR_PC };
+        }
+        if run >= cycles {
+            break Outcome::TimedOut;
+        }
+    };
+    Report { outcome, output }
+}
+
+fn run_diagnostic(diagnostic: &Diagnostic) {
+    let directory = tape_directory();
+    let Some(tape) = find_tape(&directory, diagnostic.name) else {
+        panic!("no tape for {} in {}", diagnostic.name, directory.display());
+    };
+    let report = run(diagnostic.start, diagnostic.cycles, &tape);
+    eprintln!("{} {}: {}", diagnostic.name, diagnostic.title, report);
+    assert!(matches!(report.outcome, Outcome::Passed { .. }), "{} {}: {}", diagnostic.name, diagnostic.title, report);
+}
+
+#[test]
+#[ignore = "needs DEC's tapes, set MAINDEC_DIR"]
+fn gkaa() {
+    run_diagnostic(&GKAA);
+}
+
+#[test]
+#[ignore = "needs DEC's tapes, set MAINDEC_DIR"]
+fn gkab() {
+    run_diagnostic(&GKAB);
+}
+
+#[test]
+#[ignore = "needs DEC's tapes, set MAINDEC_DIR"]
+fn gkba() {
+    run_diagnostic(&GKBA);
+}
+
+#[test]
+fn synthetic_tape_loads() {
+    let tape = absolute_loader::read(&synthetic_tape()).unwrap();
+    let blocks: Vec<(u16, usize)> = tape.blocks.iter().map(|block| (block.address, block.data.len())).collect();
+    assert_eq!(blocks, [(0o1000, 8), (0o1011, 3)]);
+    assert_eq!(tape.start, Some(0o1000));
+
+    let mut machine = Machine::new(&MICROROM, 0, 1024).unwrap();
+    absolute_loader::load(&tape, &mut machine.state.UNIBUS).unwrap();
+    let words: Vec<Option<u16>> = (0o1000..0o1014).step_by(2).map(|address| machine.state.UNIBUS.peek(address)).collect();
+    assert_eq!(words, [Some(0o012737), Some(0o000110), Some(0o177566), Some(0), Some(0o040400), Some(0o041502)]);
+}
+
+#[test]
+fn damaged_synthetic_tape() {
+    let bytes = fs::read(synthetic_tape()).unwrap();
+
+    // A data byte of the first block
+    let mut damaged = bytes.clone();
+    damaged[0o30] ^= 0o40;
+    assert!(absolute_loader::from_bytes(&damaged).unwrap_err().contains("checksum"));
+
+    // Torn off before the end block
+    assert!(absolute_loader::from_bytes(&bytes[..0o55]).unwrap_err().contains("no end block"));
+}
+
+// The microcode doesn't fetch instructions yet, the harness reports where it stopped
+#[test]
+fn synthetic_tape_runs() {
+    let report = run(0o1000, 1_000_000, &synthetic_tape());
+    assert!(matches!(report.outcome, Outcome::Stopped(EmulatorError::UnimplementedMicroword { .. })), "{}", report);
+}
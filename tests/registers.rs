@@ -0,0 +1,75 @@
+#![allow(non_snake_case)]
+
+// The data path registers, clocked at the end of the machine cycle
+
+use pdp11_microcode_emulator::clock::CL3;
+use pdp11_microcode_emulator::microrom::{MicroRom, MicroWord};
+use pdp11_microcode_emulator::rom_image::bits_to_microword;
+use pdp11_microcode_emulator::Machine;
+
+// A microROM of unprogrammed words, with word 000 made by build, looping on itself in CL3
+fn rom(build: impl Fn(&mut MicroWord)) -> MicroRom {
+    let mut words: [MicroWord; 256] = std::array::from_fn(|_| bits_to_microword(0, false));
+    words[0].implemented = true;
+    words[0].CLK = 6;
+    build(&mut words[0]);
+    MicroRom::new(words)
+}
+
+// SDM 10 puts D on the DMUX
+const SDM_D: u8 = 0b10;
+
+#[test]
+fn scratchpad_written_on_the_last_pulse() {
+    let ROM = rom(|word| {
+        word.WR = 0b11;
+        word.RIF = 0o12;
+        word.SDM = SDM_D;
+    });
+    let mut machine = Machine::new(&ROM, 0, 0).unwrap();
+    machine.state.CLK_MODE = &CL3;
+    machine.state.D = 0o123456;
+
+    // CL3 has two pulses, P1 doesn't clock the registers
+    assert!(!machine.step_pulse().unwrap());
+    assert_eq!(machine.state.REGISTERS[0o12], 0);
+    assert_eq!(machine.state.REGISTER_WRITE, None);
+
+    assert!(machine.step_pulse().unwrap());
+    assert_eq!(machine.state.REGISTERS[0o12], 0o123456);
+    assert_eq!(machine.state.REGISTER_WRITE, Some((0o12, 0o123456)));
+}
+
+#[test]
+fn byte_writes() {
+    for (WR, expected) in [(0b01, 0o177456), (0b10, 0o123777)] {
+        let ROM = rom(|word| {
+            word.WR = WR;
+            word.RIF = 3;
+            word.SDM = SDM_D;
+        });
+        let mut machine = Machine::new(&ROM, 0, 0).unwrap();
+        machine.state.REGISTERS[3] = 0o177777;
+        machine.state.D = 0o123456;
+        machine.step_cycle().unwrap();
+        assert_eq!(machine.state.REGISTERS[3], expected, "WR {:02b}", WR);
+    }
+}
+
+// D and B are clocked from this cycle's ALU and DMUX, seen in the next cycle
+#[test]
+fn d_and_b_clocked_at_the_end_of_the_cycle() {
+    let ROM = rom(|word| {
+        word.CD = 1;
+        word.CB = 1;
+        word.SDM = SDM_D;
+        // ALU A, the register RIF selects
+        word.ALU = 0o00;
+        word.RIF = 1;
+    });
+    let mut machine = Machine::new(&ROM, 0, 0).unwrap();
+    machine.state.REGISTERS[1] = 0o7070;
+    machine.state.D = 0o1234;
+    machine.step_cycle().unwrap();
+    assert_eq!((machine.state.D, machine.state.B), (0o7070, 0o1234));
+}
@@ -0,0 +1,115 @@
+#![allow(non_snake_case)]
+
+// Recording the inputs to a run and replaying them gives the same run
+
+use pdp11_microcode_emulator::microrom::{MicroRom, MicroWord};
+use pdp11_microcode_emulator::replay::{Input, InputLog, Replay};
+use pdp11_microcode_emulator::rom_image::bits_to_microword;
+use pdp11_microcode_emulator::{Machine, MICROROM};
+
+// What has to match at the end
+fn final_state(machine: &Machine) -> (u8, u8, u64, u64, u16, bool) {
+    let state = machine.state();
+    (state.UPP, state.PUPP, state.CYCLE, state.TIME_NS, state.D, state.SWITCH)
+}
+
+// Run pulses on the machine, with each input applied before the pulse numbered with it
+fn record(machine: &mut Machine, pulses: usize, inputs: &[(usize, Input)]) {
+    machine.session.recording = Some(InputLog::new());
+    for pulse in 0..pulses {
+        for (_, input) in inputs.iter().filter(|(at, _)| *at == pulse) {
+            machine.input(*input).unwrap();
+        }
+        machine.step_pulse().unwrap();
+    }
+}
+
+// Save and load the log, then run as many pulses again from a new machine
+fn replay<'a>(name: &str, recorded: &mut Machine, mut machine: Machine<'a>, pulses: usize) -> Machine<'a> {
+    let path = std::env::temp_dir().join(format!("replay_{}_{}.log", name, std::process::id()));
+    recorded.session.recording.take().unwrap().save(&path).unwrap();
+    let log = InputLog::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    machine.session.replay = Some(Replay::new(log));
+    for _ in 0..pulses {
+        machine.step_pulse().unwrap();
+    }
+    assert!(machine.session.replay.as_ref().unwrap().finished());
+    machine
+}
+
+// The full debounce count, so the run stays in the console loop
+fn console_machine() -> Machine<'static> {
+    let mut machine = Machine::new(&MICROROM, 0, 0).unwrap();
+    machine.state.SINCLK = false;
+    machine
+}
+
+#[test]
+fn console_loop_replays() {
+    let inputs = [(7, Input::Switch(true)), (40, Input::Switch(false)), (41, Input::Switch(true))];
+    let mut recorded = console_machine();
+    record(&mut recorded, 500, &inputs);
+    let replayed = replay("console", &mut recorded, console_machine(), 500);
+    assert_eq!(final_state(&replayed), final_state(&recorded));
+}
+
+// 000 -> 001, which stops the clock -> 002, looping
+fn clkoff_rom() -> MicroRom {
+    let mut words: [MicroWord; 256] = std::array::from_fn(|_| bits_to_microword(0, false));
+    for (UPP, CLK, UPF) in [(0, 2, 1), (1, 0, 2), (2, 2, 2)] {
+        words[UPP].implemented = true;
+        words[UPP].CLK = CLK;
+        words[UPP].UPF = UPF;
+    }
+    MicroRom::new(words)
+}
+
+// Inputs while the clock is stopped come at the same machine cycle, only the time tells them apart
+#[test]
+fn inputs_while_the_clock_is_stopped() {
+    let ROM = clkoff_rom();
+    let inputs = [(2, Input::Switch(false)), (9, Input::Switch(true))];
+    let mut recorded = Machine::new(&ROM, 0, 0).unwrap();
+    record(&mut recorded, 30, &inputs);
+    let replayed = replay("clkoff", &mut recorded, Machine::new(&ROM, 0, 0).unwrap(), 30);
+    assert_eq!(final_state(&replayed), final_state(&recorded));
+    // 000, then eight idle pulses before SWITCH, then 001 in two pulses and 002 in one
+    assert_eq!(recorded.state().CYCLE, 2 + 19);
+}
+
+// Recorded and replayed from the same snapshot, which has SWITCH clear until the log sets it
+#[test]
+fn replays_from_a_snapshot() {
+    let path = std::env::temp_dir().join(format!("replay_snapshot_{}.snap", std::process::id()));
+    let mut saved = console_machine();
+    saved.run_until(30, |_| false).unwrap();
+    assert!(!saved.state().SWITCH);
+    saved.save_snapshot(&path).unwrap();
+
+    let restored = || {
+        let mut machine = Machine::new(&MICROROM, 0, 0).unwrap();
+        machine.restore_snapshot(&path).unwrap();
+        machine
+    };
+    let mut recorded = restored();
+    record(&mut recorded, 200, &[(20, Input::Switch(true))]);
+    let replayed = replay("snapshot", &mut recorded, restored(), 200);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(final_state(&replayed), final_state(&recorded));
+    assert!(replayed.state().SWITCH && replayed.state().CYCLE > saved.state().CYCLE);
+}
+
+#[test]
+fn bad_logs() {
+    let path = std::env::temp_dir().join(format!("replay_bad_{}.log", std::process::id()));
+    for line in ["140 switch 2", "140 switch yes", "140 switch", "140 console 400", "x switch 1", "140 panel 1"] {
+        std::fs::write(&path, format!("# time_ns input\n0 switch 1\n{}\n", line)).unwrap();
+        let error = InputLog::load(&path).err().unwrap();
+        assert!(error.to_string().contains(":3: invalid input"), "{}", error);
+    }
+    std::fs::write(&path, "0 switch 1\n140 switch 0\n300 console 101\n").unwrap();
+    assert_eq!(InputLog::load(&path).unwrap().events.len(), 3);
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,94 @@
+#![allow(non_snake_case)]
+
+// Bus cycles from the microcode to memory and the devices in the I/O page
+
+use pdp11_microcode_emulator::microrom::{MicroRom, MicroWord};
+use pdp11_microcode_emulator::rom_image::bits_to_microword;
+use pdp11_microcode_emulator::unibus::{self, device_by_name};
+use pdp11_microcode_emulator::Machine;
+
+// A microROM of unprogrammed words, with word 000 made by build
+fn rom(build: impl Fn(&mut MicroWord)) -> MicroRom {
+    let mut words: [MicroWord; 256] = std::array::from_fn(|_| bits_to_microword(0, false));
+    words[0].implemented = true;
+    build(&mut words[0]);
+    MicroRom::new(words)
+}
+
+// BGBUS with C1BUS set and C0BUS clear, D to BA
+const DATO: u8 = 0b101;
+
+#[test]
+fn io_page_addresses() {
+    assert_eq!(unibus::bus_address(0o157776), 0o157776);
+    assert_eq!(unibus::bus_address(0o160000), 0o760000);
+    assert_eq!(unibus::bus_address(0o177566), 0o777566);
+}
+
+#[test]
+fn dato_to_xbuf_prints() {
+    // 000 loops on itself, writing D to BA every cycle
+    let ROM = rom(|word| {
+        word.BUS = DATO;
+        word.CLK = 2;
+    });
+    let mut machine = Machine::new(&ROM, 0, 1024).unwrap();
+    machine.attach(device_by_name("dl11").unwrap());
+    machine.state.BA = 0o177566;
+    machine.state.D = b'K' as u16;
+    machine.step_cycle().unwrap();
+    assert_eq!(machine.take_output(), b"K");
+    assert_eq!(machine.state.BUS_ACCESS.map(|(_, address)| address), Some(0o777566));
+}
+
+// BGBUS with C1BUS and C0BUS clear, BA to BUS D
+const DATI: u8 = 0b001;
+
+const LKS: u32 = 0o777546;
+const MONITOR: u16 = 0o200;
+
+#[test]
+fn kw11l_ticks_on_the_line_period() {
+    let mut bus = unibus::Unibus::new(0);
+    bus.attach(device_by_name("kw11l").unwrap());
+    bus.write(LKS, 0, false).unwrap();
+    for tick in 1..=3 {
+        bus.run_events(tick * unibus::LINE_PERIOD_NS - 1);
+        assert_eq!(bus.read(LKS).unwrap(), 0, "before tick {}", tick);
+        bus.run_events(tick * unibus::LINE_PERIOD_NS);
+        assert_eq!(bus.read(LKS).unwrap(), MONITOR, "tick {}", tick);
+        bus.write(LKS, 0, false).unwrap();
+    }
+
+    // Missed ticks set MONITOR once, the next is still on the boundary
+    bus.run_events(10 * unibus::LINE_PERIOD_NS + 5);
+    assert_eq!(bus.read(LKS).unwrap(), MONITOR);
+    bus.write(LKS, 0, false).unwrap();
+    bus.run_events(11 * unibus::LINE_PERIOD_NS - 1);
+    assert_eq!(bus.read(LKS).unwrap(), 0);
+}
+
+#[test]
+fn dati_from_lks_sees_the_tick() {
+    // 000 loops on itself, reading BA every cycle
+    let ROM = rom(|word| {
+        word.BUS = DATI;
+        word.CLK = 2;
+    });
+    let mut machine = Machine::new(&ROM, 0, 1024).unwrap();
+    machine.attach(device_by_name("kw11l").unwrap());
+    machine.write_memory(LKS, 0).unwrap();
+    machine.state.BA = 0o177546;
+
+    // Every cycle starting before the boundary reads MONITOR clear, the first after it set
+    let start_ns = loop {
+        let start_ns = machine.state.TIME_NS;
+        machine.step_cycle().unwrap();
+        if machine.state.BUS_D & MONITOR != 0 {
+            break start_ns;
+        }
+        assert!(start_ns < unibus::LINE_PERIOD_NS, "no tick in the cycle from {} ns", start_ns);
+    };
+    assert!(start_ns >= unibus::LINE_PERIOD_NS, "MONITOR set in the cycle from {} ns", start_ns);
+    assert!(start_ns < unibus::LINE_PERIOD_NS + 1000, "MONITOR late, in the cycle from {} ns", start_ns);
+}